@@ -0,0 +1,325 @@
+//! Song playback that can be paused, seeked and sped up.
+//!
+//! amethyst's `Output::play_once` can't be controlled once started, so the song is
+//! decoded once up front and played through its own rodio sink.
+//!
+//! Changing the rate resamples the song, which also changes its pitch. When the pitch
+//! is preserved, the song is time stretched instead, as it plays.
+
+use rodio;
+use rodio::buffer::SamplesBuffer;
+use rodio::{Decoder, Device, Sink, Source};
+
+use std::f32::consts::PI;
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Length of a time stretching window, in audio frames.
+const STRETCH_WINDOW: usize = 2048;
+/// How far a window can be moved to line up with the previous one, in audio frames.
+const STRETCH_TOLERANCE: usize = 256;
+/// Frames compared when lining windows up.
+const STRETCH_MATCH_LENGTH: usize = 256;
+/// Step between the positions tried when lining windows up.
+const STRETCH_SEARCH_STEP: usize = 8;
+
+pub struct SongPlayer {
+    device: Option<Device>,
+    sink: Option<Sink>,
+    samples: Arc<Vec<i16>>,
+    channels: u16,
+    sample_rate: u32,
+    volume: f32,
+    preserve_pitch: bool,
+}
+
+impl SongPlayer {
+    /// Decodes the song from the bytes of an audio file.
+    pub fn new(bytes: Vec<u8>, volume: f32, preserve_pitch: bool) -> SongPlayer {
+        let (samples, channels, sample_rate) = match Decoder::new(Cursor::new(bytes)) {
+            Ok(decoder) => {
+                let channels = decoder.channels();
+                let sample_rate = decoder.sample_rate();
+                (decoder.collect::<Vec<i16>>(), channels, sample_rate)
+            }
+            Err(e) => {
+                error!("Failed to decode song: {:?}", e);
+                (vec![], 2, 44100)
+            }
+        };
+        SongPlayer::from_samples(samples, channels, sample_rate, volume, preserve_pitch)
+    }
+
+    /// Plays already decoded samples, interleaved with `channels` per frame.
+    pub fn from_samples(
+        samples: Vec<i16>,
+        channels: u16,
+        sample_rate: u32,
+        volume: f32,
+        preserve_pitch: bool,
+    ) -> SongPlayer {
+        let device = rodio::default_output_device();
+        if device.is_none() {
+            error!("Failed to find an audio output device for the song.");
+        }
+        SongPlayer {
+            device,
+            sink: None,
+            samples: Arc::new(samples),
+            channels,
+            sample_rate,
+            volume,
+            preserve_pitch,
+        }
+    }
+
+    /// Length of the song, in seconds.
+    pub fn duration(&self) -> f64 {
+        self.samples.len() as f64 / (self.channels as f64 * self.sample_rate as f64)
+    }
+
+    /// Plays the song from `time` seconds at the given speed. A negative time plays
+    /// silence until the song starts.
+    pub fn play_from(&mut self, time: f64, rate: f64) {
+        self.stop();
+        let device = match self.device {
+            Some(ref device) => device,
+            None => return,
+        };
+
+        let frame = (time.abs() * self.sample_rate as f64) as usize;
+        let start = frame * self.channels as usize;
+        if time >= 0.0 && start >= self.samples.len() {
+            return;
+        }
+
+        let sink = Sink::new(device);
+        sink.set_volume(self.volume);
+        if self.preserve_pitch && rate != 1.0 {
+            let frame = if time < 0.0 { -(frame as isize) } else { frame as isize };
+            sink.append(TimeStretch::new(
+                self.samples.clone(),
+                self.channels,
+                self.sample_rate,
+                frame,
+                rate,
+            ));
+        } else {
+            let samples = if time < 0.0 {
+                let mut samples = vec![0; start];
+                samples.extend_from_slice(&self.samples);
+                samples
+            } else {
+                self.samples[start..].to_vec()
+            };
+            sink.append(
+                SamplesBuffer::new(self.channels, self.sample_rate, samples).speed(rate as f32),
+            );
+        }
+        self.sink = Some(sink);
+    }
+
+    pub fn pause(&self) {
+        if let Some(ref sink) = self.sink {
+            sink.pause();
+        }
+    }
+
+    pub fn resume(&self) {
+        if let Some(ref sink) = self.sink {
+            sink.play();
+        }
+    }
+
+    pub fn stop(&mut self) {
+        if let Some(sink) = self.sink.take() {
+            sink.stop();
+        }
+    }
+}
+
+/// Plays a song `rate` times faster without changing its pitch, stretching it as it plays.
+///
+/// Windows of the input are overlapped at a different spacing than they are read at. Each
+/// window is moved by up to `STRETCH_TOLERANCE` so it lines up with the end of the previous
+/// one, which avoids most of the phasing of a plain overlap-add.
+struct TimeStretch {
+    samples: Arc<Vec<i16>>,
+    channels: usize,
+    sample_rate: u32,
+    rate: f64,
+    window: Vec<f32>,
+    /// Input frame the stretch starts at. Frames outside of the song are silent.
+    start: isize,
+    /// Where the previous window was read from.
+    previous: isize,
+    /// Index of the next window.
+    next_window: usize,
+    /// Output frames not mixed yet.
+    remaining: usize,
+    /// The windows mixed so far, from the next sample to play.
+    mixed: Vec<f32>,
+    /// Samples of `mixed` that no window will be added to anymore.
+    ready: usize,
+    played: usize,
+}
+
+impl TimeStretch {
+    /// Stretches `samples` (interleaved, `channels` per frame) from frame `start`, which
+    /// is negative to play silence before the song.
+    fn new(
+        samples: Arc<Vec<i16>>,
+        channels: u16,
+        sample_rate: u32,
+        start: isize,
+        rate: f64,
+    ) -> TimeStretch {
+        let channels = channels as usize;
+        let frames = (samples.len() / channels) as isize;
+        let window = (0..STRETCH_WINDOW)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / STRETCH_WINDOW as f32).cos())
+            .collect();
+        TimeStretch {
+            samples,
+            channels,
+            sample_rate,
+            rate,
+            window,
+            start,
+            previous: start,
+            next_window: 0,
+            remaining: ((frames - start).max(0) as f64 / rate) as usize,
+            mixed: vec![0.0; STRETCH_WINDOW * channels],
+            ready: 0,
+            played: 0,
+        }
+    }
+
+    fn sample(&self, frame: isize, channel: usize) -> f32 {
+        if frame < 0 || frame as usize >= self.samples.len() / self.channels {
+            0.0
+        } else {
+            self.samples[frame as usize * self.channels + channel] as f32
+        }
+    }
+
+    /// Where to read the next window from, lined up with the previous one.
+    fn next_start(&self) -> isize {
+        let hop_in = (STRETCH_WINDOW / 2) as f64 * self.rate;
+        let nominal = self.start + (self.next_window as f64 * hop_in) as isize;
+        if self.next_window == 0 {
+            return nominal;
+        }
+        // The previous window naturally continues at `previous + hop_out`.
+        let target = self.previous + (STRETCH_WINDOW / 2) as isize;
+        let tolerance = STRETCH_TOLERANCE as isize;
+        (nominal - tolerance..nominal + tolerance + 1)
+            .step_by(STRETCH_SEARCH_STEP)
+            .map(|candidate| {
+                let score = (0..STRETCH_MATCH_LENGTH as isize)
+                    .map(|i| self.sample(candidate + i, 0) * self.sample(target + i, 0))
+                    .sum::<f32>();
+                (candidate, score)
+            })
+            .fold((nominal, ::std::f32::MIN), |best, candidate| {
+                if candidate.1 > best.1 {
+                    candidate
+                } else {
+                    best
+                }
+            })
+            .0
+    }
+
+    /// Mixes in the next window, which completes the frames before the one after it.
+    fn mix_window(&mut self) {
+        self.mixed.drain(..self.played);
+        self.mixed.resize(STRETCH_WINDOW * self.channels, 0.0);
+        self.played = 0;
+
+        let start = self.next_start();
+        for i in 0..STRETCH_WINDOW {
+            for c in 0..self.channels {
+                let sample = self.sample(start + i as isize, c) * self.window[i];
+                self.mixed[i * self.channels + c] += sample;
+            }
+        }
+        self.previous = start;
+        self.next_window += 1;
+
+        let frames = (STRETCH_WINDOW / 2).min(self.remaining);
+        self.remaining -= frames;
+        self.ready = frames * self.channels;
+    }
+}
+
+impl Iterator for TimeStretch {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if self.played == self.ready {
+            if self.remaining == 0 {
+                return None;
+            }
+            self.mix_window();
+        }
+        let sample = self.mixed[self.played];
+        self.played += 1;
+        Some(sample.max(::std::i16::MIN as f32).min(::std::i16::MAX as f32) as i16)
+    }
+}
+
+impl Source for TimeStretch {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_stretch_keeps_the_song_in_time() {
+        let sample_rate = 44100;
+        // One second of stereo A4.
+        let samples = (0..sample_rate)
+            .flat_map(|i| {
+                let sample = (2.0 * PI * 440.0 * i as f32 / sample_rate as f32).sin() * 10000.0;
+                vec![sample as i16; 2]
+            })
+            .collect::<Vec<i16>>();
+        let samples = Arc::new(samples);
+
+        let out = TimeStretch::new(samples.clone(), 2, sample_rate, 0, 2.0).collect::<Vec<i16>>();
+        assert_eq!(out.len(), samples.len() / 2);
+        // Still 440 Hz, where resampling would have doubled it.
+        let crossings = out
+            .chunks(2)
+            .collect::<Vec<_>>()
+            .windows(2)
+            .filter(|w| (w[0][0] < 0) != (w[1][0] < 0))
+            .count();
+        assert!((crossings as i32 - 440).abs() < 10, "{} zero crossings", crossings);
+
+        // Half a second before the song plays as a quarter second of silence.
+        let start = -(sample_rate as isize / 2);
+        let out = TimeStretch::new(samples, 2, sample_rate, start, 2.0).collect::<Vec<i16>>();
+        let silence = (sample_rate as usize / 4 - STRETCH_WINDOW) * 2;
+        assert!(out[..silence].iter().all(|&sample| sample == 0));
+        assert_eq!(out.len(), sample_rate as usize * 3 / 4 * 2);
+    }
+}
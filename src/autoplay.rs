@@ -0,0 +1,143 @@
+//! Autoplay: a perfect play generated from the hit objects of a beatmap.
+//!
+//! The generated inputs are wrapped in a `Replay`, so watching autoplay goes through
+//! the exact same judgement as watching a recorded play.
+
+use components::*;
+use replay::*;
+use resources::*;
+use utils::*;
+
+/// Time between two hits on a drumroll, in seconds.
+const DRUMROLL_INTERVAL: f64 = 1.0 / 20.0;
+
+/// Inputs hitting every note on time with the right colour, both sides on big notes,
+/// and spamming drumrolls and dendens.
+pub fn autoplay_inputs(objects: &[HitObject]) -> Vec<DrumInput> {
+    let mut inputs = vec![];
+    // Hands alternate on every hit, like a player would.
+    let mut left = true;
+    {
+        let mut hit = |time: f64, red: bool, both: bool| {
+            let (left_key, right_key) = if red {
+                (DrumKey::LeftDon, DrumKey::RightDon)
+            } else {
+                (DrumKey::LeftKat, DrumKey::RightKat)
+            };
+            if both {
+                inputs.push(DrumInput { time, key: left_key });
+                inputs.push(DrumInput { time, key: right_key });
+            } else {
+                let key = if left { left_key } else { right_key };
+                inputs.push(DrumInput { time, key });
+                left = !left;
+            }
+        };
+
+        for obj in objects {
+            match obj.kind {
+                HitObjectKind::Note => hit(obj.time, obj.red, obj.big),
+                HitObjectKind::Drumroll { end } => {
+                    let mut time = obj.time;
+                    while time < end {
+                        hit(time, true, false);
+                        time += DRUMROLL_INTERVAL;
+                    }
+                }
+                HitObjectKind::Denden { end, required, .. } => {
+                    // Alternate don and kat, spread over the whole denden.
+                    let interval = (end - obj.time) / required as f64;
+                    for i in 0..required {
+                        hit(obj.time + interval * i as f64, i % 2 == 0, false);
+                    }
+                }
+            }
+        }
+    }
+    inputs
+}
+
+/// Replay of a perfect play on `beatmap`. Its score is the one the inputs should get.
+pub fn autoplay_replay(beatmap: &BeatMap, mods: Mods) -> Replay {
+    let mut replay = Replay {
+        beatmap_hash: beatmap.hash.clone(),
+        player: String::from("Autoplay"),
+        mods,
+        offset: 0.0,
+        inputs: autoplay_inputs(&beatmap.objects),
+        ..Replay::default()
+    };
+    replay.score = simulate_replay(beatmap, replay.clone());
+    replay
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn autoplay_gets_all_perfects() {
+        let beatmap = BeatMap {
+            od: 5.0,
+            objects: vec![
+                HitObject::test_note(1.0, true),
+                HitObject::test_note(1.25, false),
+                HitObject {
+                    big: true,
+                    ..HitObject::test_note(1.5, true)
+                },
+                HitObject {
+                    big: true,
+                    ..HitObject::test_note(1.75, false)
+                },
+                HitObject {
+                    kind: HitObjectKind::Drumroll { end: 3.0 },
+                    ..HitObject::test_note(2.0, true)
+                },
+                HitObject::test_note(3.25, false),
+                HitObject {
+                    kind: HitObjectKind::Denden {
+                        end: 5.0,
+                        required: 10,
+                        hits: 0,
+                    },
+                    ..HitObject::test_note(3.5, true)
+                },
+                HitObject {
+                    big: true,
+                    ..HitObject::test_note(5.25, true)
+                },
+                HitObject::test_note(5.5, false),
+                HitObject::test_note(5.625, true),
+            ],
+            ..BeatMap::default()
+        };
+        let fast = Mods {
+            rate: DOUBLE_TIME_RATE,
+            hard_rock: true,
+            ..Mods::default()
+        };
+        for mods in &[Mods::default(), fast] {
+            let score = autoplay_replay(&beatmap, *mods).score;
+            assert_eq!(score.perfect as usize, beatmap.note_count());
+            assert_eq!(score.good, 0);
+            assert_eq!(score.miss, 0);
+            assert_eq!(score.max_combo as usize, beatmap.note_count());
+        }
+    }
+
+    #[test]
+    fn autoplay_gets_all_perfects_on_bundled_maps() {
+        let maps = format!("{}/assets/base/maps", env!("CARGO_MANIFEST_DIR"));
+        // The maps the song select lists, read by the same parser.
+        let beatmaps = beatmap_list(&maps);
+        assert!(!beatmaps.is_empty(), "No beatmaps in {}", maps);
+        for beatmap in &beatmaps {
+            let score = autoplay_replay(beatmap, Mods::default()).score;
+            assert_eq!(score.perfect as usize, beatmap.note_count(), "{}", beatmap.path);
+            assert_eq!(score.good, 0, "{}", beatmap.path);
+            assert_eq!(score.miss, 0, "{}", beatmap.path);
+            assert_eq!(score.max_combo as usize, beatmap.note_count(), "{}", beatmap.path);
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Offset calibration.
+//!
+//! The wizard plays a metronome and records when the drum is hit compared to the clicks.
+//! Hitting late on average means inputs reach the game late, so the suggested offset is
+//! the opposite of the mean tap offset.
+//!
+//! After a play, the mean of the `HitOffsets` gives the same kind of suggestion for a
+//! local offset, added on top of the global one for that beatmap only.
+
+use resources::*;
+
+/// Tempo of the metronome.
+const METRONOME_BPM: f64 = 120.0;
+/// Clicks in the metronome track. The first bar only counts in and isn't judged.
+pub const METRONOME_BEATS: usize = 36;
+const COUNT_IN_BEATS: usize = 4;
+/// Silence before the first click, in seconds.
+const METRONOME_LEAD_IN: f64 = 1.0;
+const METRONOME_SAMPLE_RATE: u32 = 44100;
+/// Length of a click, in seconds.
+const CLICK_LENGTH: f64 = 0.03;
+/// Taps or hits needed before an offset is suggested.
+pub const MIN_CALIBRATION_HITS: usize = 16;
+
+/// Time of the `beat`th click of the metronome track, in seconds.
+pub fn beat_time(beat: usize) -> f64 {
+    METRONOME_LEAD_IN + beat as f64 * 60.0 / METRONOME_BPM
+}
+
+/// Length of the metronome track, in seconds.
+pub fn metronome_duration() -> f64 {
+    beat_time(METRONOME_BEATS)
+}
+
+/// Mono samples of the metronome track, with a higher click on the first beat of each bar.
+/// Returns the samples and their sample rate.
+pub fn metronome_track() -> (Vec<i16>, u32) {
+    let rate = METRONOME_SAMPLE_RATE as f64;
+    let mut samples = vec![0i16; (metronome_duration() * rate) as usize];
+    let click_samples = (CLICK_LENGTH * rate) as usize;
+    for beat in 0..METRONOME_BEATS {
+        let frequency = if beat % 4 == 0 { 1760.0 } else { 880.0 };
+        let start = (beat_time(beat) * rate) as usize;
+        for i in 0..click_samples {
+            let t = i as f64 / rate;
+            let envelope = 1.0 - i as f64 / click_samples as f64;
+            let value = (t * frequency * 2.0 * ::std::f64::consts::PI).sin() * envelope;
+            if let Some(sample) = samples.get_mut(start + i) {
+                *sample = (value * 0.6 * i16::max_value() as f64) as i16;
+            }
+        }
+    }
+    (samples, METRONOME_SAMPLE_RATE)
+}
+
+/// Tap offsets collected by the wizard.
+#[derive(Default)]
+pub struct OffsetCalibration {
+    /// Song time of each tap minus the time of the closest click, in seconds.
+    pub taps: Vec<f64>,
+}
+
+impl OffsetCalibration {
+    /// Records a tap at song time `time`. Taps during the count in, or too far from any
+    /// click to tell which one they were meant for, are ignored.
+    pub fn tap(&mut self, time: f64) {
+        let beat_length = 60.0 / METRONOME_BPM;
+        let beat = ((time - METRONOME_LEAD_IN) / beat_length).round();
+        if beat < COUNT_IN_BEATS as f64 || beat >= METRONOME_BEATS as f64 {
+            return;
+        }
+        let offset = time - beat_time(beat as usize);
+        if offset.abs() < beat_length / 3.0 {
+            self.taps.push(offset);
+        }
+    }
+
+    /// Global offset making the average tap land on the clicks, once there are enough
+    /// taps.
+    pub fn suggested_offset(&self) -> Option<f64> {
+        if self.taps.len() < MIN_CALIBRATION_HITS {
+            return None;
+        }
+        Some(-self.taps.iter().sum::<f64>() / self.taps.len() as f64)
+    }
+}
+
+/// Local offset for the beatmap `hash` that would have centred the hits of the last play.
+/// Offsets are in song time, and converted back to real time with `rate`.
+pub fn suggested_local_offset(
+    settings: &UserSettings,
+    hash: &str,
+    offsets: &HitOffsets,
+    rate: f64,
+) -> Option<f64> {
+    let hits = offsets.hits();
+    if hits.len() < MIN_CALIBRATION_HITS {
+        return None;
+    }
+    let mean = hits.iter().sum::<f64>() / hits.len() as f64 / rate;
+    Some(settings.local_offset(hash) - mean)
+}
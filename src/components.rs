@@ -2,13 +2,181 @@ extern crate amethyst;
 
 use amethyst::ecs::prelude::*;
 
+use resources::DrumKey;
+
+/// What has to be done to clear a hit object.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HitObjectKind {
+    /// A single don or kat.
+    Note,
+    /// Hit as many times as possible until `end`.
+    Drumroll { end: f64 },
+    /// Hit `required` times before `end`. `hits` counts the hits so far.
+    Denden { end: f64, required: u32, hits: u32 },
+}
+
+/// Bank of hit sound samples.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SampleSet {
+    Normal,
+    Soft,
+    Drum,
+}
+
+impl SampleSet {
+    /// Reads a sample set from its number in timing points and hit objects, where 0
+    /// means the set is inherited.
+    pub fn from_index(index: u8) -> Option<SampleSet> {
+        match index {
+            1 => Some(SampleSet::Normal),
+            2 => Some(SampleSet::Soft),
+            3 => Some(SampleSet::Drum),
+            _ => None,
+        }
+    }
+
+    /// Reads the `SampleSet:` of the [General] section.
+    pub fn from_name(name: &str) -> Option<SampleSet> {
+        match name {
+            "Normal" => Some(SampleSet::Normal),
+            "Soft" => Some(SampleSet::Soft),
+            "Drum" => Some(SampleSet::Drum),
+            _ => None,
+        }
+    }
+
+    /// Prefix of the sample files of the set.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SampleSet::Normal => "normal",
+            SampleSet::Soft => "soft",
+            SampleSet::Drum => "drum",
+        }
+    }
+}
+
+/// Samples played when a hit object is hit, with the inherited values already resolved
+/// from the timing points.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HitSamples {
+    /// Set of the normal sound.
+    pub normal_set: SampleSet,
+    /// Set of the whistle, finish and clap.
+    pub addition_set: SampleSet,
+    /// Custom sample index. 0 only uses the skin samples, 1 and up look in the beatmap
+    /// folder first.
+    pub index: u32,
+    /// From 0 to 1.
+    pub volume: f32,
+    /// Sample played instead of every other one, in the beatmap folder.
+    pub filename: Option<String>,
+    /// The object was mapped with a whistle.
+    pub whistle: bool,
+}
+
+impl Default for HitSamples {
+    fn default() -> Self {
+        HitSamples {
+            normal_set: SampleSet::Normal,
+            addition_set: SampleSet::Normal,
+            index: 0,
+            volume: 1.0,
+            filename: None,
+            whistle: false,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct HitObject {
     pub red: bool,
     pub time: f64,
     pub big: bool,
+    pub kind: HitObjectKind,
+    pub samples: HitSamples,
+    /// Whether the object is in kiai time, where it's worth more.
+    pub kiai: bool,
+}
+
+impl HitObject {
+    /// Whether the object is a don or kat that gets a judgement.
+    pub fn is_note(&self) -> bool {
+        self.kind == HitObjectKind::Note
+    }
+
+    /// Time after which the object can't be hit anymore, without the hit window.
+    pub fn end_time(&self) -> f64 {
+        match self.kind {
+            HitObjectKind::Note => self.time,
+            HitObjectKind::Drumroll { end } | HitObjectKind::Denden { end, .. } => end,
+        }
+    }
+}
+
+#[cfg(test)]
+impl HitObject {
+    /// A small note for tests, outside kiai time and with the default samples. Other
+    /// objects are made from it with the struct update syntax.
+    pub fn test_note(time: f64, red: bool) -> HitObject {
+        HitObject {
+            red,
+            time,
+            big: false,
+            kind: HitObjectKind::Note,
+            samples: HitSamples::default(),
+            kiai: false,
+        }
+    }
 }
 
 impl Component for HitObject {
     type Storage = VecStorage<HitObject>;
 }
+
+/// Body and end cap of a drumroll, moved along with its head.
+pub struct DrumrollSprites {
+    pub body: Entity,
+    pub end: Entity,
+}
+
+impl Component for DrumrollSprites {
+    type Storage = HashMapStorage<DrumrollSprites>;
+}
+
+/// A barline, scrolling like a note reaching the judgement line at `time`.
+pub struct Barline {
+    pub time: f64,
+}
+
+impl Component for Barline {
+    type Storage = VecStorage<Barline>;
+}
+
+/// Part of the drum lit for a moment when `key` is hit.
+pub struct DrumPart {
+    pub key: DrumKey,
+}
+
+impl Component for DrumPart {
+    type Storage = HashMapStorage<DrumPart>;
+}
+
+/// Playfield sprite sized in screen heights, whose width is scaled by the inverse of the
+/// aspect ratio so it isn't stretched. `mirrored` sprites are flipped horizontally.
+pub struct KeepAspect {
+    pub mirrored: bool,
+}
+
+impl Component for KeepAspect {
+    type Storage = HashMapStorage<KeepAspect>;
+}
+
+/// Playfield sprite shown during kiai time. `pulse` sprites are sized in screen heights, and
+/// beat with the song.
+pub struct KiaiEffect {
+    pub pulse: bool,
+}
+
+impl Component for KiaiEffect {
+    type Storage = HashMapStorage<KiaiEffect>;
+}
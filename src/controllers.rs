@@ -0,0 +1,388 @@
+//! Drum controllers other than the keyboard.
+//!
+//! Gamepads, MIDI drum kits and serial taiko drums are read on their own threads, and
+//! their hits are sent to the game with the `Instant` they were received at, which the
+//! `GameClock` turns into absolute time. Each device of `config/controllers.ron` has its
+//! own mapping to the four drum keys, and a latency that is taken off the time of its hits.
+//!
+//! Other HID devices aren't read: a drum has to show up as a gamepad, a MIDI port or a
+//! serial port.
+//!
+//! A `Virtual` device reads the same bytes as a serial drum from any file, like a FIFO,
+//! and a MIDI device named `virtual` opens a MIDI port other programs can send to. Both
+//! make it possible to try a mapping without the hardware.
+
+use gilrs::{EventType, Gilrs};
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use serialport::{self, SerialPortSettings};
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ron;
+
+use resources::*;
+
+/// Milliseconds to wait between two polls of the gamepads.
+const GAMEPAD_POLL_INTERVAL: u64 = 1;
+/// Name of the MIDI port that makes the game open its own virtual port.
+const VIRTUAL_MIDI_PORT: &str = "virtual";
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct ControllerConfig {
+    pub devices: Vec<DeviceConfig>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DeviceConfig {
+    /// Part of the gamepad or MIDI port name to use, or path of the serial port or
+    /// virtual device. An empty name matches the first gamepad or MIDI port.
+    pub name: String,
+    /// Seconds between hitting the drum and the device reporting it.
+    #[serde(default)]
+    pub latency: f64,
+    pub kind: DeviceKind,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum DeviceKind {
+    /// Gamepad buttons, by their gilrs name (`South`, `DPadLeft`, `LeftTrigger`...).
+    Gamepad { buttons: Vec<(String, DrumKey)> },
+    /// MIDI note-on messages, by note number. Softer notes than `velocity_threshold` are
+    /// ignored, so that pads ringing or being brushed don't count as hits.
+    Midi {
+        notes: Vec<(u8, DrumKey)>,
+        #[serde(default)]
+        velocity_threshold: u8,
+    },
+    /// Bytes sent by a serial drum.
+    Serial {
+        baud_rate: u32,
+        bytes: Vec<(u8, DrumKey)>,
+    },
+    /// Bytes read from a file, like a serial drum.
+    Virtual { bytes: Vec<(u8, DrumKey)> },
+}
+
+impl ControllerConfig {
+    /// Loads the devices from `path`. No device is used if the file can't be read.
+    pub fn load(path: &str) -> ControllerConfig {
+        let mut content = String::new();
+        match File::open(path).and_then(|mut f| f.read_to_string(&mut content)) {
+            Ok(_) => ron::de::from_str(&content).unwrap_or_else(|e| {
+                error!("Failed to parse controller config {}: {}", path, e);
+                ControllerConfig::default()
+            }),
+            Err(e) => {
+                error!("Failed to read controller config {}: {}", path, e);
+                ControllerConfig::default()
+            }
+        }
+    }
+}
+
+/// Key mapped to `value`, if any.
+fn mapped_key<T: PartialEq>(mapping: &[(T, DrumKey)], value: &T) -> Option<DrumKey> {
+    mapping.iter().find(|m| m.0 == *value).map(|m| m.1)
+}
+
+/// Key of a MIDI note-on `message` on any channel, if it is mapped and loud enough.
+/// A note-on with a velocity of 0 is a note-off.
+pub fn midi_key(
+    message: &[u8],
+    notes: &[(u8, DrumKey)],
+    velocity_threshold: u8,
+) -> Option<DrumKey> {
+    if message.len() < 3 || message[0] & 0xF0 != 0x90 {
+        return None;
+    }
+    if message[2] == 0 || message[2] < velocity_threshold {
+        return None;
+    }
+    mapped_key(notes, &message[1])
+}
+
+/// Wraps the error of a controller library.
+fn other_error<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+/// A hit on a controller.
+struct DrumHit {
+    key: DrumKey,
+    time: Instant,
+    /// Latency of the device, in seconds.
+    latency: f64,
+}
+
+/// Receives the hits of every configured controller.
+pub struct DrumControllers {
+    hits: Mutex<Receiver<DrumHit>>,
+}
+
+impl Default for DrumControllers {
+    fn default() -> Self {
+        DrumControllers {
+            hits: Mutex::new(channel().1),
+        }
+    }
+}
+
+impl DrumControllers {
+    /// Starts reading every device of `config`. Devices that can't be opened are logged
+    /// and skipped.
+    pub fn start(config: &ControllerConfig) -> DrumControllers {
+        let (sender, receiver) = channel();
+        for device in config.devices.iter().cloned() {
+            let sender = sender.clone();
+            let name = device.name.clone();
+            let spawned = thread::Builder::new()
+                .name(format!("drum controller {}", name))
+                .spawn(move || run_device(device, sender));
+            if let Err(e) = spawned {
+                error!("Failed to start drum controller {}: {}", name, e);
+            }
+        }
+        DrumControllers {
+            hits: Mutex::new(receiver),
+        }
+    }
+
+    /// Takes the hits received since the last call, with the absolute time of `clock`
+    /// they happened at, once the latency of their device is taken off.
+    pub fn poll(&self, clock: &GameClock) -> Vec<(f64, DrumKey)> {
+        let receiver = match self.hits.lock() {
+            Ok(receiver) => receiver,
+            Err(_) => return vec![],
+        };
+        receiver
+            .try_iter()
+            .map(|hit| (clock.time_of(hit.time) - hit.latency, hit.key))
+            .collect()
+    }
+}
+
+/// Reads `device` until it is disconnected, sending its hits to `sender`.
+fn run_device(device: DeviceConfig, sender: Sender<DrumHit>) {
+    let latency = device.latency;
+    let send = move |key, time| sender.send(DrumHit { key, time, latency }).is_ok();
+    let result = match device.kind {
+        DeviceKind::Gamepad { ref buttons } => run_gamepad(&device.name, buttons, send),
+        DeviceKind::Midi {
+            ref notes,
+            velocity_threshold,
+        } => run_midi(&device.name, notes.clone(), velocity_threshold, send),
+        DeviceKind::Serial {
+            baud_rate,
+            ref bytes,
+        } => {
+            let settings = SerialPortSettings {
+                baud_rate,
+                timeout: Duration::from_secs(3600),
+                ..Default::default()
+            };
+            serialport::open_with_settings(&device.name, &settings)
+                .map_err(other_error)
+                .and_then(|port| run_byte_stream(port, bytes, send))
+        }
+        DeviceKind::Virtual { ref bytes } => {
+            File::open(&device.name).and_then(|file| run_byte_stream(file, bytes, send))
+        }
+    };
+    match result {
+        Ok(()) => info!("Drum controller {} disconnected", device.name),
+        Err(e) => error!("Drum controller {} failed: {}", device.name, e),
+    }
+}
+
+/// Polls the gamepads whose name contains `name`. Presses are stamped when polled, which
+/// is at most `GAMEPAD_POLL_INTERVAL` late.
+fn run_gamepad<F>(name: &str, buttons: &[(String, DrumKey)], mut send: F) -> io::Result<()>
+where
+    F: FnMut(DrumKey, Instant) -> bool,
+{
+    let mut gilrs = Gilrs::new().map_err(other_error)?;
+    loop {
+        while let Some(event) = gilrs.next_event() {
+            let button = match event.event {
+                EventType::ButtonPressed(button, _) => button,
+                _ => continue,
+            };
+            if !gilrs[event.id].name().contains(name) {
+                continue;
+            }
+            if let Some(key) = mapped_key(buttons, &format!("{:?}", button)) {
+                if !send(key, Instant::now()) {
+                    return Ok(());
+                }
+            }
+        }
+        thread::sleep(Duration::from_millis(GAMEPAD_POLL_INTERVAL));
+    }
+}
+
+/// Listens to the first MIDI port whose name contains `name`, or to a new virtual port
+/// if `name` is `virtual`.
+fn run_midi<F>(
+    name: &str,
+    notes: Vec<(u8, DrumKey)>,
+    velocity_threshold: u8,
+    mut send: F,
+) -> io::Result<()>
+where
+    F: FnMut(DrumKey, Instant) -> bool + Send + 'static,
+{
+    let mut input = MidiInput::new("taiko-copy").map_err(other_error)?;
+    input.ignore(Ignore::All);
+    let callback = move |_stamp: u64, message: &[u8], _: &mut ()| {
+        if let Some(key) = midi_key(message, &notes, velocity_threshold) {
+            send(key, Instant::now());
+        }
+    };
+    // The connection stops listening once dropped, so this thread keeps it.
+    let _connection = if name == VIRTUAL_MIDI_PORT {
+        virtual_midi(input, callback)?
+    } else {
+        let port = (0..input.port_count())
+            .find(|&i| input.port_name(i).map(|n| n.contains(name)).unwrap_or(false))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no matching MIDI port"))?;
+        input
+            .connect(port, "taiko-copy drum", callback, ())
+            .map_err(other_error)?
+    };
+    loop {
+        thread::park();
+    }
+}
+
+#[cfg(unix)]
+fn virtual_midi<F>(input: MidiInput, callback: F) -> io::Result<MidiInputConnection<()>>
+where
+    F: FnMut(u64, &[u8], &mut ()) + Send + 'static,
+{
+    use midir::os::unix::VirtualInput;
+    input
+        .create_virtual("taiko-copy drum", callback, ())
+        .map_err(other_error)
+}
+
+#[cfg(not(unix))]
+fn virtual_midi<F>(_input: MidiInput, _callback: F) -> io::Result<MidiInputConnection<()>>
+where
+    F: FnMut(u64, &[u8], &mut ()) + Send + 'static,
+{
+    Err(other_error("virtual MIDI ports are not supported on this platform"))
+}
+
+/// Reads single byte hits from `stream` until it ends.
+fn run_byte_stream<R, F>(mut stream: R, bytes: &[(u8, DrumKey)], mut send: F) -> io::Result<()>
+where
+    R: Read,
+    F: FnMut(DrumKey, Instant) -> bool,
+{
+    let mut buffer = [0u8; 64];
+    loop {
+        let read = match stream.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let time = Instant::now();
+        for byte in &buffer[..read] {
+            if let Some(key) = mapped_key(bytes, byte) {
+                if !send(key, time) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTES: &[(u8, DrumKey)] = &[(38, DrumKey::LeftDon), (40, DrumKey::LeftKat)];
+
+    #[test]
+    fn midi_note_on() {
+        assert_eq!(midi_key(&[0x90, 38, 100], NOTES, 20), Some(DrumKey::LeftDon));
+        // Any channel.
+        assert_eq!(midi_key(&[0x99, 40, 100], NOTES, 20), Some(DrumKey::LeftKat));
+        // Unmapped note.
+        assert_eq!(midi_key(&[0x90, 39, 100], NOTES, 20), None);
+    }
+
+    #[test]
+    fn midi_ignored_messages() {
+        // Note-off, and note-on with a velocity of 0.
+        assert_eq!(midi_key(&[0x80, 38, 100], NOTES, 0), None);
+        assert_eq!(midi_key(&[0x90, 38, 0], NOTES, 0), None);
+        // Softer than the threshold.
+        assert_eq!(midi_key(&[0x90, 38, 19], NOTES, 20), None);
+        assert_eq!(midi_key(&[0x90, 38, 20], NOTES, 20), Some(DrumKey::LeftDon));
+        // Control change, and messages too short to be a note-on.
+        assert_eq!(midi_key(&[0xB0, 38, 100], NOTES, 0), None);
+        assert_eq!(midi_key(&[0x90, 38], NOTES, 0), None);
+        assert_eq!(midi_key(&[], NOTES, 0), None);
+    }
+
+    #[test]
+    fn byte_stream() {
+        let bytes = [(b'd', DrumKey::LeftDon), (b'K', DrumKey::RightKat)];
+        let mut keys = vec![];
+        run_byte_stream(&b"dxK\nd"[..], &bytes, |key, _| {
+            keys.push(key);
+            true
+        }).unwrap();
+        assert_eq!(keys, vec![DrumKey::LeftDon, DrumKey::RightKat, DrumKey::LeftDon]);
+    }
+
+    #[test]
+    fn byte_stream_stops_once_disconnected() {
+        let bytes = [(b'd', DrumKey::LeftDon)];
+        let mut count = 0;
+        run_byte_stream(&b"ddd"[..], &bytes, |_, _| {
+            count += 1;
+            false
+        }).unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn config_file() {
+        let content = include_str!("../assets/base/config/controllers.ron");
+        let config: ControllerConfig = ron::de::from_str(content).unwrap();
+        assert!(config.devices.is_empty());
+
+        // The commented out examples of the file are valid devices too.
+        let examples = content
+            .lines()
+            .skip_while(|line| !line.starts_with("// Examples"))
+            .skip(2)
+            .take_while(|line| *line != "//")
+            .map(|line| line.trim_left_matches("//"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let config: ControllerConfig =
+            ron::de::from_str(&format!("(devices: [{}])", examples)).unwrap();
+        assert_eq!(config.devices.len(), 4);
+        match config.devices[1].kind {
+            DeviceKind::Midi {
+                ref notes,
+                velocity_threshold,
+            } => {
+                assert_eq!(notes[0], (38, DrumKey::LeftDon));
+                assert_eq!(velocity_threshold, 20);
+            }
+            _ => panic!("The second example should be a MIDI device"),
+        }
+        assert_eq!(config.devices[3].latency, 0.0);
+    }
+}
@@ -0,0 +1,388 @@
+//! Star rating calculation for taiko beatmaps.
+//!
+//! Every hit object is rated by three skills: colour (don/kat switches), rhythm
+//! (changes in the spacing between notes) and stamina (how fast each hand has to hit).
+//! A skill keeps a strain value that decays over time and grows with every object.
+//! The highest strain of each 400 ms section is kept, and the sorted peaks are summed
+//! with a decaying weight to give the skill's difficulty. The three difficulties are
+//! then combined into a single star rating.
+
+use std::cmp::Ordering;
+
+use components::*;
+use resources::*;
+
+/// Length of a strain section, in milliseconds.
+const SECTION_LENGTH: f64 = 400.0;
+/// Weight multiplier applied to each successive (sorted) strain peak.
+const DECAY_WEIGHT: f64 = 0.9;
+
+const COLOUR_SKILL_MULTIPLIER: f64 = 0.0165;
+const RHYTHM_SKILL_MULTIPLIER: f64 = 0.019;
+const STAMINA_SKILL_MULTIPLIER: f64 = 0.0185;
+
+/// Common note spacing ratios. A rhythm change close to one of these is easy to read.
+const COMMON_RATIOS: [f64; 9] = [
+    1.0 / 4.0,
+    1.0 / 2.0,
+    2.0 / 3.0,
+    3.0 / 4.0,
+    1.0,
+    4.0 / 3.0,
+    3.0 / 2.0,
+    2.0,
+    4.0,
+];
+
+/// A hit object with the timing information the skills need.
+struct DifficultyHitObject {
+    /// Time of the object, in milliseconds.
+    time: f64,
+    /// Time since the previous object, in milliseconds.
+    delta: f64,
+    red: bool,
+}
+
+/// Decaying strain and the per-section peaks it reached.
+struct Strain {
+    skill_multiplier: f64,
+    strain_decay_base: f64,
+    current_strain: f64,
+    current_section_peak: f64,
+    current_section_end: Option<f64>,
+    previous_time: f64,
+    peaks: Vec<f64>,
+}
+
+impl Strain {
+    fn new(skill_multiplier: f64, strain_decay_base: f64) -> Strain {
+        Strain {
+            skill_multiplier,
+            strain_decay_base,
+            current_strain: 0.0,
+            current_section_peak: 0.0,
+            current_section_end: None,
+            previous_time: 0.0,
+            peaks: vec![],
+        }
+    }
+
+    fn strain_decay(&self, ms: f64) -> f64 {
+        self.strain_decay_base.powf(ms / 1000.0)
+    }
+
+    fn process(&mut self, obj: &DifficultyHitObject, value: f64) {
+        let mut section_end = self
+            .current_section_end
+            .unwrap_or((obj.time / SECTION_LENGTH).ceil() * SECTION_LENGTH);
+        while obj.time > section_end {
+            self.peaks.push(self.current_section_peak);
+            self.current_section_peak =
+                self.current_strain * self.strain_decay(section_end - self.previous_time);
+            section_end += SECTION_LENGTH;
+        }
+        self.current_section_end = Some(section_end);
+
+        self.current_strain *= self.strain_decay(obj.delta);
+        self.current_strain += value * self.skill_multiplier;
+        self.current_section_peak = self.current_section_peak.max(self.current_strain);
+        self.previous_time = obj.time;
+    }
+
+    /// All section peaks, including the one still in progress.
+    fn all_peaks(&self) -> Vec<f64> {
+        let mut peaks = self.peaks.clone();
+        peaks.push(self.current_section_peak);
+        peaks
+    }
+
+    fn difficulty_value(&self) -> f64 {
+        weighted_sum(self.all_peaks())
+    }
+}
+
+/// Rates how hard it is to read switches between don and kat.
+struct Colour {
+    strain: Strain,
+    mono_length: usize,
+    mono_history: Vec<usize>,
+}
+
+impl Colour {
+    fn new() -> Colour {
+        Colour {
+            strain: Strain::new(COLOUR_SKILL_MULTIPLIER, 0.4),
+            mono_length: 0,
+            mono_history: vec![],
+        }
+    }
+
+    fn process(&mut self, objects: &[DifficultyHitObject], index: usize) {
+        let value = if index == 0 {
+            self.mono_length = 1;
+            0.0
+        } else if objects[index].red == objects[index - 1].red {
+            self.mono_length += 1;
+            0.0
+        } else {
+            self.mono_history.push(self.mono_length);
+            self.mono_length = 1;
+            self.repetition_penalty()
+        };
+        self.strain.process(&objects[index], value);
+    }
+
+    /// Repeating the same pattern of mono-colour run lengths (ddkk ddkk) is easier than
+    /// an irregular one.
+    fn repetition_penalty(&self) -> f64 {
+        let history = &self.mono_history;
+        let mut penalty: f64 = 1.0;
+        for l in 1..5 {
+            if history.len() < l * 2 {
+                break;
+            }
+            let end = history.len();
+            if history[end - l..end] == history[end - 2 * l..end - l] {
+                penalty = penalty.min(0.25 + 0.15 * l as f64);
+            }
+        }
+        penalty
+    }
+}
+
+/// Rates how hard the changes in spacing between consecutive notes are.
+struct Rhythm {
+    strain: Strain,
+}
+
+impl Rhythm {
+    fn new() -> Rhythm {
+        Rhythm {
+            strain: Strain::new(RHYTHM_SKILL_MULTIPLIER, 0.0),
+        }
+    }
+
+    fn process(&mut self, objects: &[DifficultyHitObject], index: usize) {
+        let value = if index < 2 || objects[index - 1].delta <= 0.0 {
+            0.0
+        } else {
+            let obj = &objects[index];
+            let ratio = obj.delta / objects[index - 1].delta;
+            if (ratio.ln()).abs() < 0.05 {
+                0.0
+            } else {
+                // Distance (in log space) to the nearest common ratio.
+                let irregularity = COMMON_RATIOS
+                    .iter()
+                    .map(|r| (ratio / r).ln().abs())
+                    .fold(std::f64::MAX, f64::min);
+                // Rhythm changes matter less the slower the notes are.
+                let speed = (200.0 / obj.delta.max(50.0)).min(1.0);
+                (0.6 + irregularity.min(0.4) * 2.0) * speed
+            }
+        };
+        self.strain.process(&objects[index], value);
+    }
+}
+
+/// Rates how fast one hand has to hit. Hands alternate on every note.
+struct Stamina {
+    strain: Strain,
+    hand: usize,
+    previous_hit: Option<f64>,
+}
+
+impl Stamina {
+    fn new(hand: usize) -> Stamina {
+        Stamina {
+            strain: Strain::new(STAMINA_SKILL_MULTIPLIER, 0.4),
+            hand,
+            previous_hit: None,
+        }
+    }
+
+    fn process(&mut self, objects: &[DifficultyHitObject], index: usize) {
+        let obj = &objects[index];
+        let value = if index % 2 != self.hand {
+            0.0
+        } else {
+            let value = match self.previous_hit {
+                Some(previous) => 0.5 + 175.0 / (obj.time - previous + 100.0),
+                None => 0.5,
+            };
+            self.previous_hit = Some(obj.time);
+            value
+        };
+        self.strain.process(obj, value);
+    }
+}
+
+/// Computes the star rating of a beatmap from its hit objects.
+pub fn calculate_star_rating(beatmap: &BeatMap) -> f64 {
+    calculate_star_rating_of(&beatmap.objects)
+}
+
+/// Computes the star rating of a list of hit objects played `rate` times faster.
+pub fn calculate_star_rating_at_rate(objects: &[HitObject], rate: f64) -> f64 {
+    let objects = objects
+        .iter()
+        .map(|o| HitObject {
+            time: o.time / rate,
+            ..o.clone()
+        })
+        .collect::<Vec<_>>();
+    calculate_star_rating_of(&objects)
+}
+
+/// Computes the star rating of a list of hit objects, sorted by time.
+pub fn calculate_star_rating_of(objects: &[HitObject]) -> f64 {
+    // Drumrolls and dendens don't take any reading, only notes are rated.
+    let notes = objects.iter().filter(|o| o.is_note()).collect::<Vec<_>>();
+    if notes.len() < 2 {
+        return 0.0;
+    }
+
+    let objects = notes
+        .iter()
+        .enumerate()
+        .map(|(i, hit)| DifficultyHitObject {
+            time: hit.time * 1000.0,
+            delta: if i == 0 {
+                0.0
+            } else {
+                (hit.time - notes[i - 1].time) * 1000.0
+            },
+            red: hit.red,
+        })
+        .collect::<Vec<_>>();
+
+    let mut colour = Colour::new();
+    let mut rhythm = Rhythm::new();
+    let mut stamina_left = Stamina::new(0);
+    let mut stamina_right = Stamina::new(1);
+    for i in 0..objects.len() {
+        colour.process(&objects, i);
+        rhythm.process(&objects, i);
+        stamina_left.process(&objects, i);
+        stamina_right.process(&objects, i);
+    }
+
+    let colour_rating = colour.strain.difficulty_value();
+    let rhythm_rating = rhythm.strain.difficulty_value();
+    let stamina_rating =
+        stamina_left.strain.difficulty_value() + stamina_right.strain.difficulty_value();
+
+    // Combine the skills section by section, so maps that are hard in every skill at
+    // once rate higher than maps whose hard parts are spread out.
+    let colour_peaks = colour.strain.all_peaks();
+    let rhythm_peaks = rhythm.strain.all_peaks();
+    let stamina_left_peaks = stamina_left.strain.all_peaks();
+    let stamina_right_peaks = stamina_right.strain.all_peaks();
+    let combined_peaks = (0..colour_peaks.len())
+        .map(|i| {
+            norm(
+                2.0,
+                &[
+                    colour_peaks[i],
+                    rhythm_peaks[i],
+                    stamina_left_peaks[i] + stamina_right_peaks[i],
+                ],
+            )
+        })
+        .collect::<Vec<_>>();
+    let combined_rating = weighted_sum(combined_peaks);
+
+    let separated_rating = norm(1.5, &[colour_rating, rhythm_rating, stamina_rating]);
+    rescale(1.4 * separated_rating + 0.5 * combined_rating)
+}
+
+fn weighted_sum(mut peaks: Vec<f64>) -> f64 {
+    peaks.sort_by(|a, b| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+    let mut weight = 1.0;
+    let mut difficulty = 0.0;
+    for peak in peaks {
+        difficulty += peak * weight;
+        weight *= DECAY_WEIGHT;
+    }
+    difficulty
+}
+
+fn norm(p: f64, values: &[f64]) -> f64 {
+    values.iter().map(|v| v.powf(p)).sum::<f64>().powf(1.0 / p)
+}
+
+/// Compresses high ratings so the scale stays readable.
+fn rescale(sr: f64) -> f64 {
+    if sr < 0.0 {
+        sr
+    } else {
+        10.43 * (sr / 8.0 + 1.0).ln()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `count` notes `interval` seconds apart, with colours repeating `pattern`.
+    fn stream(count: usize, interval: f64, pattern: &[bool]) -> Vec<HitObject> {
+        (0..count)
+            .map(|i| HitObject::test_note(1.0 + i as f64 * interval, pattern[i % pattern.len()]))
+            .collect()
+    }
+
+    #[test]
+    fn too_few_notes_rate_zero() {
+        assert_eq!(calculate_star_rating_of(&[]), 0.0);
+        assert_eq!(calculate_star_rating_of(&[HitObject::test_note(1.0, true)]), 0.0);
+    }
+
+    #[test]
+    fn denser_maps_rate_higher() {
+        let slow = calculate_star_rating_of(&stream(200, 0.3, &[true, false]));
+        let fast = calculate_star_rating_of(&stream(400, 0.15, &[true, false]));
+        assert!(slow > 0.0);
+        assert!(fast > slow, "{} should be over {}", fast, slow);
+    }
+
+    #[test]
+    fn double_time_rates_higher() {
+        let objects = stream(200, 0.2, &[true, true, false]);
+        let normal = calculate_star_rating_at_rate(&objects, 1.0);
+        let double_time = calculate_star_rating_at_rate(&objects, DOUBLE_TIME_RATE);
+        let half_time = calculate_star_rating_at_rate(&objects, HALF_TIME_RATE);
+        assert!((normal - calculate_star_rating_of(&objects)).abs() < 1e-9);
+        assert!(double_time > normal);
+        assert!(half_time < normal);
+    }
+
+    #[test]
+    fn colour_changes_rate_higher_than_mono() {
+        let mono = calculate_star_rating_of(&stream(200, 0.2, &[true]));
+        let pattern = [true, false, false, true, false];
+        let mixed = calculate_star_rating_of(&stream(200, 0.2, &pattern));
+        assert!(mixed > mono, "{} should be over {}", mixed, mono);
+    }
+
+    #[test]
+    fn drumrolls_and_dendens_are_not_rated() {
+        let notes = stream(100, 0.2, &[true, false]);
+        let mut objects = notes.clone();
+        objects.push(HitObject {
+            kind: HitObjectKind::Drumroll { end: 40.0 },
+            ..HitObject::test_note(30.0, true)
+        });
+        objects.push(HitObject {
+            kind: HitObjectKind::Denden {
+                end: 50.0,
+                required: 20,
+                hits: 0,
+            },
+            ..HitObject::test_note(45.0, true)
+        });
+        assert_eq!(
+            calculate_star_rating_of(&objects),
+            calculate_star_rating_of(&notes)
+        );
+    }
+}
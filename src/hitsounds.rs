@@ -0,0 +1,195 @@
+//! Hit sounds, resolved like osu! does.
+//!
+//! A sample such as the clap of the soft set is looked up in the beatmap folder first
+//! (`soft-hitclap.wav` for sample index 1, `soft-hitclap2.wav` for index 2, nothing for
+//! index 0), then in the skin (`taiko-soft-hitclap.wav`, then `soft-hitclap.wav`), and
+//! falls back to the default samples of `Sounds`.
+
+use amethyst::assets::{AssetStorage, Loader};
+use amethyst::audio::{OggFormat, Source, SourceHandle, WavFormat};
+use amethyst::ecs::prelude::*;
+use amethyst_extra::*;
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use components::*;
+use resources::*;
+use skin::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HitSound {
+    Normal,
+    Whistle,
+    Finish,
+    Clap,
+}
+
+impl HitSound {
+    fn name(&self) -> &'static str {
+        match *self {
+            HitSound::Normal => "hitnormal",
+            HitSound::Whistle => "hitwhistle",
+            HitSound::Finish => "hitfinish",
+            HitSound::Clap => "hitclap",
+        }
+    }
+}
+
+impl Sounds {
+    /// Default sample of `sound`.
+    pub fn get(&self, sound: HitSound) -> &SourceHandle {
+        match sound {
+            HitSound::Normal => &self.normal,
+            HitSound::Whistle => &self.whistle,
+            HitSound::Finish => &self.finish,
+            HitSound::Clap => &self.clap,
+        }
+    }
+}
+
+/// Sounds of a hit on an object with `samples`, with their sample set: the normal sound
+/// for dons, the clap for kats (or the whistle if the object was mapped with one), and the
+/// finish on top for big hits.
+pub fn hit_sounds(samples: &HitSamples, red: bool, big: bool) -> Vec<(HitSound, SampleSet)> {
+    let mut sounds = vec![if red {
+        (HitSound::Normal, samples.normal_set)
+    } else if samples.whistle {
+        (HitSound::Whistle, samples.addition_set)
+    } else {
+        (HitSound::Clap, samples.addition_set)
+    }];
+    if big {
+        sounds.push((HitSound::Finish, samples.addition_set));
+    }
+    sounds
+}
+
+/// Samples the hit objects of the current beatmap can play, loaded ahead of time.
+#[derive(Default)]
+pub struct HitSoundBank {
+    /// By set, sound and index, or by file name for custom samples.
+    samples: HashMap<String, SourceHandle>,
+}
+
+impl HitSoundBank {
+    /// Resolves and loads every sample the objects of `beatmap` can play.
+    pub fn load(world: &World, beatmap: &BeatMap) -> HitSoundBank {
+        let mut bank = HitSoundBank::default();
+        let folder = Path::new(&beatmap.path)
+            .parent()
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let asset_loader = world.read_resource::<AssetLoader>();
+        let skin = world.read_resource::<Skin>();
+        let loader = world.read_resource::<Loader>();
+        let storage = world.read_resource::<AssetStorage<Source>>();
+
+        for object in &beatmap.objects {
+            let samples = &object.samples;
+            if let Some(ref filename) = samples.filename {
+                if !bank.samples.contains_key(filename) {
+                    let path = format!("{}/{}", folder, filename);
+                    if Path::new(&path).exists() {
+                        let handle = load_sample(&path, &loader, &storage);
+                        bank.samples.insert(filename.clone(), handle);
+                    } else {
+                        warn!("Missing custom hit sound {}", path);
+                    }
+                }
+            }
+            let possible = hit_sounds(samples, true, true)
+                .into_iter()
+                .chain(hit_sounds(samples, false, true));
+            for (sound, set) in possible {
+                let key = sample_key(set, sound, samples.index);
+                if bank.samples.contains_key(&key) {
+                    continue;
+                }
+                let path =
+                    resolve_sample(&folder, &skin, &asset_loader, set, sound, samples.index);
+                if let Some(path) = path {
+                    let handle = load_sample(&path, &loader, &storage);
+                    bank.samples.insert(key, handle);
+                }
+            }
+        }
+        bank
+    }
+
+    /// Custom sample replacing every sound of an object, if it has one.
+    pub fn custom(&self, samples: &HitSamples) -> Option<&SourceHandle> {
+        samples
+            .filename
+            .as_ref()
+            .and_then(|filename| self.samples.get(filename))
+    }
+
+    /// Sample to play for `sound` of `set` on an object with `samples`, or `None` to use
+    /// the default one.
+    pub fn get(
+        &self,
+        samples: &HitSamples,
+        sound: HitSound,
+        set: SampleSet,
+    ) -> Option<&SourceHandle> {
+        self.samples.get(&sample_key(set, sound, samples.index))
+    }
+}
+
+fn sample_key(set: SampleSet, sound: HitSound, index: u32) -> String {
+    format!("{}-{}{}", set.name(), sound.name(), index)
+}
+
+/// Path of the first sample found for `sound` of `set`, in the beatmap folder, then in the
+/// skin, then in the default assets.
+fn resolve_sample(
+    folder: &str,
+    skin: &Skin,
+    asset_loader: &AssetLoader,
+    set: SampleSet,
+    sound: HitSound,
+    index: u32,
+) -> Option<String> {
+    let name = format!("{}-{}", set.name(), sound.name());
+    if index > 0 {
+        let suffix = if index == 1 {
+            String::new()
+        } else {
+            index.to_string()
+        };
+        for extension in &["wav", "ogg"] {
+            let path = format!("{}/{}{}.{}", folder, name, suffix, extension);
+            if Path::new(&path).exists() {
+                return Some(path);
+            }
+        }
+    }
+    let files = [format!("taiko-{}", name), name.clone()];
+    let extensions = ["wav", "ogg"];
+    for file in &files {
+        for extension in &extensions {
+            let path = skin.element(&format!("{}.{}", file, extension));
+            if path.is_some() {
+                return path;
+            }
+        }
+    }
+    for file in &files {
+        for extension in &extensions {
+            let path = asset_loader.resolve_path(&format!("audio/{}.{}", file, extension));
+            if path.is_some() {
+                return path;
+            }
+        }
+    }
+    None
+}
+
+pub fn load_sample(path: &str, loader: &Loader, storage: &AssetStorage<Source>) -> SourceHandle {
+    if path.to_lowercase().ends_with(".ogg") {
+        loader.load(path, OggFormat, (), (), storage)
+    } else {
+        loader.load(path, WavFormat, (), (), storage)
+    }
+}
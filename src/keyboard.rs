@@ -0,0 +1,128 @@
+//! Times of the key presses, taken when the window receives them.
+//!
+//! amethyst turns the window events into input events without a timestamp, a frame after
+//! the window received them. `KeyStampSystem` runs right after the renderer polls the
+//! window, and stamps each key press with the time it came in. The drum presses of the
+//! next frame are matched to these stamps by scancode, and keep that time. Presses
+//! without a stamp get the time of the frame.
+
+/// Seconds a stamp waits for its press to reach the game before being dropped.
+const STAMP_LIFETIME: f64 = 0.25;
+
+/// Key presses received by the window, waiting for their input event.
+#[derive(Default)]
+pub struct KeyStamps {
+    /// Stamps not matched to a press yet, with their absolute time, oldest first.
+    pending: Vec<(u32, f64)>,
+    /// Keys held down, whose repeats aren't new presses.
+    held: Vec<u32>,
+}
+
+impl KeyStamps {
+    /// Stamps a key event the window received at absolute time `time`.
+    pub fn key_event(&mut self, scancode: u32, pressed: bool, time: f64) {
+        if !pressed {
+            self.held.retain(|&key| key != scancode);
+        } else if !self.held.contains(&scancode) {
+            self.held.push(scancode);
+            self.pending.push((scancode, time));
+        }
+    }
+
+    /// Forgets the held keys, as the window doesn't receive their release once it lost
+    /// the focus.
+    pub fn release_all(&mut self) {
+        self.held.clear();
+    }
+
+    /// Drops the stamps too old to still have their press reach the game.
+    pub fn drop_old(&mut self, now: f64) {
+        self.pending.retain(|&(_, time)| now - time <= STAMP_LIFETIME);
+    }
+
+    /// Absolute time of a press of `scancode` received this frame: the time of its oldest
+    /// stamp, or `now` if it has none.
+    pub fn take(&mut self, scancode: u32, now: f64) -> f64 {
+        match self.pending.iter().position(|stamp| stamp.0 == scancode) {
+            Some(i) => self.pending.remove(i).1,
+            None => now,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use components::*;
+    use replay::*;
+    use resources::*;
+    use utils::*;
+
+    /// Scancodes of Z, X and M.
+    const Z: u32 = 44;
+    const X: u32 = 45;
+    const M: u32 = 50;
+
+    /// Score of a replay of `presses` on two dons, at 1 and 1.1 seconds.
+    fn judge(presses: &[(f64, DrumKey)]) -> Score {
+        let beatmap = BeatMap {
+            od: 5.0,
+            objects: vec![HitObject::test_note(1.0, true), HitObject::test_note(1.1, true)],
+            ..BeatMap::default()
+        };
+        let replay = Replay {
+            inputs: presses
+                .iter()
+                .map(|&(time, key)| DrumInput { time, key })
+                .collect(),
+            ..Replay::default()
+        };
+        simulate_replay(&beatmap, replay)
+    }
+
+    #[test]
+    fn presses_in_one_frame_keep_their_times() {
+        let frame = 1.05;
+        let mut stamps = KeyStamps::default();
+        stamps.key_event(Z, true, 1.0);
+        stamps.key_event(X, true, 1.04);
+        let presses = vec![
+            (stamps.take(Z, frame), DrumKey::LeftDon),
+            (stamps.take(X, frame), DrumKey::RightDon),
+        ];
+        assert_eq!(presses[0].0, 1.0);
+        assert_eq!(presses[1].0, 1.04);
+        assert!(stamps.pending.is_empty());
+
+        // On time, then early.
+        let score = judge(&presses);
+        assert_eq!((score.perfect, score.good, score.miss), (1, 1, 0));
+        // Both late and early by the same time when judged at the frame.
+        let score = judge(&[(frame, DrumKey::LeftDon), (frame, DrumKey::RightDon)]);
+        assert_eq!((score.perfect, score.good, score.miss), (0, 2, 0));
+    }
+
+    #[test]
+    fn presses_without_stamp_get_the_frame_time() {
+        let mut stamps = KeyStamps::default();
+        stamps.key_event(Z, true, 1.0);
+        // A repeat of the held key.
+        stamps.key_event(Z, true, 1.01);
+        stamps.key_event(Z, false, 1.02);
+        stamps.key_event(Z, true, 1.03);
+        assert_eq!(stamps.take(M, 1.05), 1.05);
+        // Successive presses of a key take its stamps in order.
+        assert_eq!(stamps.take(Z, 1.05), 1.0);
+        assert_eq!(stamps.take(Z, 1.05), 1.03);
+        assert_eq!(stamps.take(Z, 1.05), 1.05);
+    }
+
+    #[test]
+    fn old_stamps_are_dropped() {
+        let mut stamps = KeyStamps::default();
+        stamps.key_event(Z, true, 1.0);
+        stamps.key_event(X, true, 1.0 + STAMP_LIFETIME * 2.0);
+        stamps.drop_old(1.0 + STAMP_LIFETIME * 2.0);
+        assert_eq!(stamps.pending, vec![(X, 1.0 + STAMP_LIFETIME * 2.0)]);
+    }
+}
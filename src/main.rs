@@ -3,15 +3,26 @@
 //#![deny(missing_docs,dead_code)]
 extern crate amethyst;
 extern crate futures;
+extern crate gilrs;
 extern crate imagefmt;
 extern crate rayon;
+extern crate rodio;
 extern crate rusttype;
 extern crate time;
 extern crate winit;
 #[macro_use]
 extern crate log;
 extern crate amethyst_extra;
+extern crate byteorder;
 extern crate core;
+extern crate md5;
+extern crate midir;
+extern crate ron;
+extern crate serde;
+extern crate serialport;
+#[macro_use]
+extern crate serde_derive;
+extern crate xz2;
 
 use amethyst::audio::AudioBundle;
 use amethyst::audio::Source;
@@ -22,31 +33,102 @@ use amethyst::renderer::*;
 use amethyst::ui::{FontAsset, UiBundle};
 use amethyst_extra::*;
 
+mod audio;
+mod autoplay;
+mod calibration;
 mod components;
+mod controllers;
+mod difficulty;
+mod hitsounds;
+mod keyboard;
+mod osr;
+mod performance;
+mod replay;
 mod resources;
+mod scores;
+mod skin;
 mod states;
+mod storyboard;
 mod systems;
 mod utils;
 
+use controllers::*;
+use keyboard::*;
+use replay::*;
+use resources::*;
+use scores::*;
+use skin::*;
 use states::*;
+use systems::*;
+use utils::*;
 
 fn main() -> amethyst::Result<()> {
     amethyst::start_logger(Default::default());
-    
+
+    // `taiko-copy --stars <folder>` prints the star ratings of the maps in <folder>.
+    let args = std::env::args().collect::<Vec<String>>();
+    if args.len() == 3 && args[1] == "--stars" {
+        print_star_ratings(&args[2]);
+        return Ok(());
+    }
+    // `taiko-copy --export-osr <replay.tkr> <out.osr>` converts one of our replays.
+    if args.len() == 4 && args[1] == "--export-osr" {
+        match Replay::load(&args[2]).and_then(|r| r.export_osr(&args[3])) {
+            Ok(()) => info!("Exported {} to {}", args[2], args[3]),
+            Err(e) => error!("Failed to export {}: {}", args[2], e),
+        }
+        return Ok(());
+    }
+    // `taiko-copy --replay <file>` watches a .tkr or osu! .osr replay.
+    let replay = if args.len() == 3 && args[1] == "--replay" {
+        let replay = if args[2].ends_with(".osr") {
+            Replay::import_osr(&args[2])
+        } else {
+            Replay::load(&args[2])
+        };
+        match replay {
+            Ok(replay) => Some(replay),
+            Err(e) => {
+                error!("Failed to load replay {}: {}", args[2], e);
+                return Ok(());
+            }
+        }
+    } else {
+        None
+    };
+    let initial_state = match replay {
+        Some(replay) => MenuState::with_replay(replay),
+        None => MenuState::new(),
+    };
+
     let base_path = get_working_dir();
     let asset_loader = AssetLoader::new(&format!("{}/assets", base_path).to_string(), "base");
     let display_config_path = asset_loader.resolve_path("config/display.ron").unwrap();
     let key_bindings_path = asset_loader.resolve_path("config/input.ron").unwrap();
+    let user_settings_path = asset_loader
+        .resolve_path("config/player_settings.ron")
+        .unwrap();
+    let score_store = ScoreStore::load(format!("{}/userdata/scores.ron", base_path));
+    let user_settings = UserSettings::load(&user_settings_path);
+    let controllers = asset_loader
+        .resolve_path("config/controllers.ron")
+        .map(|path| DrumControllers::start(&ControllerConfig::load(&path)))
+        .unwrap_or_default();
+    let skin = Skin::load(&asset_loader, &user_settings.skin);
 
     let game_data_builder = GameDataBuilder::default()
         .with_bundle(InputBundle::<String, String>::new().with_bindings_from_file(&key_bindings_path)?)?
         .with_bundle(TransformBundle::new())?
         .with_bundle(AudioBundle::new(|music: &mut Music| music.music.next()))?
         .with_bundle(UiBundle::<String, String>::new())?
+        .with(GameClockSystem, "game_clock", &[])
         .with(NormalOrthoCameraSystem::default(), "normal_cam", &[])
-        .with_basic_renderer(display_config_path, DrawFlat::<PosTex>::new().with_transparency(ColorMask::all(), ALPHA, None), true)?;
+        .with(ToastSystem, "toasts", &["game_clock"])
+        .with(DrumInputSystem::default(), "drum_input", &["game_clock"])
+        .with_basic_renderer(display_config_path, DrawFlat::<PosTex>::new().with_transparency(ColorMask::all(), ALPHA, None), true)?
+        .with_thread_local(KeyStampSystem::default());
     let resources_directory = format!("");
-    Application::build(resources_directory, MenuState::new())?
+    Application::build(resources_directory, initial_state)?
         .with_resource(asset_loader)
         .with_resource(AssetLoaderInternal::<Mesh>::new())
         .with_resource(AssetLoaderInternal::<Texture>::new())
@@ -55,6 +137,15 @@ fn main() -> amethyst::Result<()> {
         .with_resource(Music {
             music: vec![].into_iter().cycle(),
         })
+        .with_resource(GameClock::default())
+        .with_resource(Mods::default())
+        .with_resource(Toasts::default())
+        .with_resource(DrumInputs::default())
+        .with_resource(controllers)
+        .with_resource(KeyStamps::default())
+        .with_resource(user_settings)
+        .with_resource(skin)
+        .with_resource(score_store)
         .build(game_data_builder)?
         .run();
     Ok(())
@@ -0,0 +1,456 @@
+//! osu! .osr replays in taiko mode.
+//!
+//! An .osr file is a header (beatmap MD5, player, judgement counts, mods) followed by
+//! LZMA-compressed frame data. Each frame is `w|x|y|z,` where `w` is the time since the
+//! previous frame in milliseconds and `z` is the key state. In taiko, the key bits are
+//! left centre (1), left rim (2), right centre (4) and right rim (8).
+//!
+//! osu! frame times already include the player's offset, so imported replays have a zero
+//! offset, and exported ones have ours baked into the frame times.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use md5;
+use time;
+use xz2::read::XzDecoder;
+use xz2::stream::{LzmaOptions, Stream};
+use xz2::write::XzEncoder;
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use replay::*;
+use resources::*;
+
+const TAIKO_MODE: u8 = 1;
+/// Game version written in exported replays.
+const OSR_VERSION: u32 = 20180722;
+/// Replays from this version on end with an online score id.
+const SCORE_ID_VERSION: u32 = 20140721;
+/// Frame holding the RNG seed instead of an input.
+const SEED_FRAME: i64 = -12345;
+/// Windows ticks (100 ns) between 0001-01-01 and the unix epoch.
+const EPOCH_TICKS: i64 = 621_355_968_000_000_000;
+
+const MOD_EASY: u32 = 1 << 1;
+const MOD_HIDDEN: u32 = 1 << 3;
+const MOD_HARD_ROCK: u32 = 1 << 4;
+const MOD_DOUBLE_TIME: u32 = 1 << 6;
+const MOD_HALF_TIME: u32 = 1 << 8;
+const MOD_NIGHTCORE: u32 = 1 << 9;
+const MOD_FLASHLIGHT: u32 = 1 << 10;
+
+/// How long an exported key stays down, in milliseconds.
+const KEY_HOLD: i64 = 20;
+
+impl Replay {
+    pub fn import_osr(path: &str) -> io::Result<Replay> {
+        read_osr(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn export_osr(&self, path: &str) -> io::Result<()> {
+        if let Some(parent) = Path::new(path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_osr(self, &mut BufWriter::new(File::create(path)?))
+    }
+}
+
+/// Bit of a drum key in an osu! taiko key state.
+fn key_bit(key: DrumKey) -> u32 {
+    match key {
+        DrumKey::LeftDon => 1,
+        DrumKey::LeftKat => 2,
+        DrumKey::RightDon => 4,
+        DrumKey::RightKat => 8,
+    }
+}
+
+const ALL_KEYS: [DrumKey; 4] = [
+    DrumKey::LeftDon,
+    DrumKey::LeftKat,
+    DrumKey::RightDon,
+    DrumKey::RightKat,
+];
+
+pub fn read_osr<R: Read>(r: &mut R) -> io::Result<Replay> {
+    let mode = r.read_u8()?;
+    if mode != TAIKO_MODE {
+        return Err(invalid_data(&format!("Not a taiko replay (mode {})", mode)));
+    }
+    let version = r.read_u32::<LittleEndian>()?;
+    let beatmap_hash = read_string(r)?;
+    let player = read_string(r)?;
+    let _replay_hash = read_string(r)?;
+
+    let count_300 = r.read_u16::<LittleEndian>()?;
+    let count_100 = r.read_u16::<LittleEndian>()?;
+    let _count_50 = r.read_u16::<LittleEndian>()?;
+    let _count_geki = r.read_u16::<LittleEndian>()?;
+    let _count_katu = r.read_u16::<LittleEndian>()?;
+    let count_miss = r.read_u16::<LittleEndian>()?;
+    let total_score = r.read_u32::<LittleEndian>()?;
+    let max_combo = r.read_u16::<LittleEndian>()?;
+    let _perfect = r.read_u8()?;
+    let mods = read_mods(r.read_u32::<LittleEndian>()?);
+    let _life_bar = read_string(r)?;
+    let _timestamp = r.read_i64::<LittleEndian>()?;
+
+    let length = r.read_u32::<LittleEndian>()?;
+    let mut compressed = vec![0u8; length as usize];
+    r.read_exact(&mut compressed)?;
+    if version >= SCORE_ID_VERSION {
+        let _score_id = r.read_i64::<LittleEndian>()?;
+    }
+
+    let mut frames = String::new();
+    XzDecoder::new_stream(&compressed[..], Stream::new_lzma_decoder(u64::max_value())?)
+        .read_to_string(&mut frames)?;
+
+    Ok(Replay {
+        beatmap_hash,
+        player,
+        mods,
+        offset: 0.0,
+        score: Score {
+            score: total_score as u64,
+            max_combo: max_combo as u32,
+            perfect: count_300 as u32,
+            good: count_100 as u32,
+            miss: count_miss as u32,
+            ..Score::default()
+        },
+        inputs: parse_frames(&frames)?,
+        offset_changes: vec![],
+        imported: true,
+    })
+}
+
+/// Turns key states into presses: a key is pressed on the frame it goes down.
+fn parse_frames(frames: &str) -> io::Result<Vec<DrumInput>> {
+    let mut inputs = vec![];
+    let mut time = 0i64;
+    let mut previous_keys = 0u32;
+    for frame in frames.split(',').filter(|f| !f.is_empty()) {
+        let fields = frame.split('|').collect::<Vec<_>>();
+        if fields.len() != 4 {
+            return Err(invalid_data(&format!("Invalid replay frame {:?}", frame)));
+        }
+        let delta = fields[0]
+            .parse::<i64>()
+            .map_err(|_| invalid_data(&format!("Invalid frame time {:?}", fields[0])))?;
+        if delta == SEED_FRAME {
+            continue;
+        }
+        // Key states are written as floats by some versions.
+        let keys = fields[3]
+            .parse::<f64>()
+            .map_err(|_| invalid_data(&format!("Invalid key state {:?}", fields[3])))?
+            as u32;
+        time += delta;
+
+        let pressed = keys & !previous_keys;
+        for key in ALL_KEYS.iter() {
+            if pressed & key_bit(*key) != 0 {
+                inputs.push(DrumInput {
+                    time: time as f64 / 1000.0,
+                    key: *key,
+                });
+            }
+        }
+        previous_keys = keys;
+    }
+    Ok(inputs)
+}
+
+pub fn write_osr<W: Write>(replay: &Replay, w: &mut W) -> io::Result<()> {
+    let frames = write_frames(replay);
+    let mut encoder = XzEncoder::new_stream(
+        vec![],
+        Stream::new_lzma_encoder(&LzmaOptions::new_preset(6)?)?,
+    );
+    encoder.write_all(frames.as_bytes())?;
+    let compressed = encoder.finish()?;
+
+    let score = &replay.score;
+    w.write_u8(TAIKO_MODE)?;
+    w.write_u32::<LittleEndian>(OSR_VERSION)?;
+    write_string(w, &replay.beatmap_hash)?;
+    write_string(w, &replay.player)?;
+    write_string(w, &format!("{:x}", md5::compute(frames.as_bytes())))?;
+    w.write_u16::<LittleEndian>(score.perfect as u16)?;
+    w.write_u16::<LittleEndian>(score.good as u16)?;
+    w.write_u16::<LittleEndian>(0)?;
+    w.write_u16::<LittleEndian>(0)?;
+    w.write_u16::<LittleEndian>(0)?;
+    w.write_u16::<LittleEndian>(score.miss as u16)?;
+    w.write_u32::<LittleEndian>(score.score as u32)?;
+    w.write_u16::<LittleEndian>(score.max_combo as u16)?;
+    w.write_u8((score.miss == 0) as u8)?;
+    w.write_u32::<LittleEndian>(write_mods(&replay.mods))?;
+    write_string(w, "")?;
+    let now = time::get_time();
+    w.write_i64::<LittleEndian>(EPOCH_TICKS + now.sec * 10_000_000)?;
+    w.write_u32::<LittleEndian>(compressed.len() as u32)?;
+    w.write_all(&compressed)?;
+    w.write_i64::<LittleEndian>(0)?;
+    w.flush()
+}
+
+/// Writes every group of simultaneous presses as a key-down frame, followed by a release
+/// before the next group.
+fn write_frames(replay: &Replay) -> String {
+    // Press times in milliseconds, with the offset baked in.
+    let mut presses: Vec<(i64, u32)> = vec![];
+    for input in &replay.inputs {
+        let time = (replay.judge_time(input.time) * 1000.0).round() as i64;
+        if let Some(last) = presses.last_mut() {
+            if last.0 == time {
+                last.1 |= key_bit(input.key);
+                continue;
+            }
+        }
+        presses.push((time, key_bit(input.key)));
+    }
+
+    let mut frames = String::new();
+    let mut previous = 0;
+    let mut push_frame = |frames: &mut String, time: i64, keys: u32| {
+        frames.push_str(&format!("{}|256|-500|{},", time - previous, keys));
+        previous = time;
+    };
+    for (i, &(time, keys)) in presses.iter().enumerate() {
+        push_frame(&mut frames, time, keys);
+        let release = match presses.get(i + 1) {
+            Some(&(next, _)) => (time + KEY_HOLD).min(time + (next - time) / 2),
+            None => time + KEY_HOLD,
+        };
+        push_frame(&mut frames, release.max(time), 0);
+    }
+    frames.push_str(&format!("{}|0|0|0,", SEED_FRAME));
+    frames
+}
+
+fn read_mods(bits: u32) -> Mods {
+    Mods {
+        hidden: bits & MOD_HIDDEN != 0,
+        flashlight: bits & MOD_FLASHLIGHT != 0,
+        hard_rock: bits & MOD_HARD_ROCK != 0,
+        easy: bits & MOD_EASY != 0,
+        rate: if bits & (MOD_DOUBLE_TIME | MOD_NIGHTCORE) != 0 {
+            DOUBLE_TIME_RATE
+        } else if bits & MOD_HALF_TIME != 0 {
+            HALF_TIME_RATE
+        } else {
+            1.0
+        },
+    }
+}
+
+fn write_mods(mods: &Mods) -> u32 {
+    let mut bits = 0;
+    if mods.hidden {
+        bits |= MOD_HIDDEN;
+    }
+    if mods.flashlight {
+        bits |= MOD_FLASHLIGHT;
+    }
+    if mods.hard_rock {
+        bits |= MOD_HARD_ROCK;
+    }
+    if mods.easy {
+        bits |= MOD_EASY;
+    }
+    // osu! only has fixed rates: faster ones export as Double Time, slower ones as Half Time.
+    if mods.rate > 1.0 {
+        bits |= MOD_DOUBLE_TIME;
+    } else if mods.rate < 1.0 {
+        bits |= MOD_HALF_TIME;
+    }
+    bits
+}
+
+fn read_uleb128<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = r.read_u8()?;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+        if shift >= 64 {
+            return Err(invalid_data("Invalid string length in replay"));
+        }
+    }
+}
+
+fn write_uleb128<W: Write>(w: &mut W, mut value: u64) -> io::Result<()> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            return w.write_u8(byte);
+        }
+        w.write_u8(byte | 0x80)?;
+    }
+}
+
+/// Strings are 0x00 when absent, or 0x0b followed by a ULEB128 length and UTF-8 bytes.
+fn read_string<R: Read>(r: &mut R) -> io::Result<String> {
+    match r.read_u8()? {
+        0x00 => Ok(String::new()),
+        0x0b => {
+            let len = read_uleb128(r)?;
+            let mut bytes = vec![0u8; len as usize];
+            r.read_exact(&mut bytes)?;
+            String::from_utf8(bytes).map_err(|_| invalid_data("Invalid string in replay"))
+        }
+        b => Err(invalid_data(&format!("Invalid string marker {:#x}", b))),
+    }
+}
+
+fn write_string<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    if s.is_empty() {
+        return w.write_u8(0x00);
+    }
+    w.write_u8(0x0b)?;
+    write_uleb128(w, s.len() as u64)?;
+    w.write_all(s.as_bytes())
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(time: f64, key: DrumKey) -> DrumInput {
+        DrumInput { time, key }
+    }
+
+    fn sample_replay() -> Replay {
+        Replay {
+            // Lengths on both sides of the one byte ULEB128 limit.
+            beatmap_hash: "a".repeat(127),
+            player: "b".repeat(128),
+            mods: Mods {
+                hidden: true,
+                hard_rock: true,
+                rate: DOUBLE_TIME_RATE,
+                ..Mods::default()
+            },
+            offset: 0.01,
+            score: Score {
+                score: 123_456,
+                max_combo: 4,
+                perfect: 3,
+                good: 1,
+                miss: 1,
+                ..Score::default()
+            },
+            inputs: vec![
+                input(1.0, DrumKey::LeftDon),
+                input(1.0, DrumKey::RightDon),
+                input(1.25, DrumKey::LeftKat),
+                input(1.5, DrumKey::RightKat),
+                input(1.501, DrumKey::LeftDon),
+            ],
+            ..Replay::default()
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let replay = sample_replay();
+        let mut bytes = vec![];
+        write_osr(&replay, &mut bytes).unwrap();
+        let read = read_osr(&mut &bytes[..]).unwrap();
+
+        assert!(read.imported);
+        assert_eq!(read.beatmap_hash, replay.beatmap_hash);
+        assert_eq!(read.player, replay.player);
+        assert_eq!(read.mods, replay.mods);
+        assert_eq!(read.offset, 0.0);
+        assert!(read.score.matches(&replay.score));
+
+        // The offset is baked into the frame times, which are whole milliseconds.
+        assert_eq!(read.inputs.len(), replay.inputs.len());
+        for (read, input) in read.inputs.iter().zip(&replay.inputs) {
+            assert_eq!(read.key, input.key);
+            assert!((read.time - replay.judge_time(input.time)).abs() < 0.0005);
+        }
+    }
+
+    #[test]
+    fn mod_bits() {
+        let hidden = Mods {
+            hidden: true,
+            ..Mods::default()
+        };
+        let hard_rock = Mods {
+            hard_rock: true,
+            ..Mods::default()
+        };
+        let double_time = Mods {
+            rate: DOUBLE_TIME_RATE,
+            ..Mods::default()
+        };
+        let half_time = Mods {
+            rate: HALF_TIME_RATE,
+            ..Mods::default()
+        };
+        assert_eq!(write_mods(&hidden), 8);
+        assert_eq!(write_mods(&hard_rock), 16);
+        assert_eq!(write_mods(&double_time), 64);
+        assert_eq!(write_mods(&half_time), 256);
+        for mods in &[hidden, hard_rock, double_time, half_time] {
+            assert_eq!(read_mods(write_mods(mods)), *mods);
+        }
+        // Nightcore is Double Time with a pitch change.
+        assert_eq!(read_mods(64 | 512), double_time);
+    }
+
+    #[test]
+    fn key_bits_and_frame_deltas() {
+        let frames = "10|0|0|1,-12345|0|0|0,5|0|0|1,5|0|0|0,5|0|0|14,20|0|0|6.0,";
+        let inputs = parse_frames(frames).unwrap();
+        let pressed = inputs.iter().map(|i| (i.time, i.key)).collect::<Vec<_>>();
+        // The seed frame is skipped without moving the time, and held keys aren't pressed
+        // again.
+        assert_eq!(
+            pressed,
+            vec![
+                (0.010, DrumKey::LeftDon),
+                (0.025, DrumKey::LeftKat),
+                (0.025, DrumKey::RightDon),
+                (0.025, DrumKey::RightKat),
+            ]
+        );
+    }
+
+    #[test]
+    fn uleb128_lengths() {
+        for &(value, ref encoded) in &[(127, vec![0x7f]), (128, vec![0x80, 0x01])] {
+            let mut bytes = vec![];
+            write_uleb128(&mut bytes, value).unwrap();
+            assert_eq!(bytes, *encoded);
+            assert_eq!(read_uleb128(&mut &bytes[..]).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn rejects_other_modes() {
+        let mut bytes = vec![];
+        write_osr(&sample_replay(), &mut bytes).unwrap();
+        // osu!standard
+        bytes[0] = 0;
+        let error = read_osr(&mut &bytes[..]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
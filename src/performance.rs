@@ -0,0 +1,72 @@
+//! Performance rating of a finished play.
+//!
+//! Combines the map's star rating with how well it was played into a single number
+//! that can be compared across maps. Strain (reading and hitting the notes) and
+//! accuracy (hitting them on time) are rated separately, then added together. Judgements
+//! in kiai time weigh more, like they do in the score.
+
+use resources::*;
+
+/// Computes the performance value of a play.
+/// `windows` must be the judgement windows the play was judged with.
+pub fn calculate_performance(
+    star_rating: f64,
+    windows: &HitWindows,
+    score: &Score,
+    mods: &Mods,
+) -> f64 {
+    let total_hits = score.judged();
+    if total_hits == 0 {
+        return 0.0;
+    }
+
+    let mut multiplier = 1.1;
+    if mods.hidden {
+        multiplier *= 1.1;
+    }
+
+    let strain = strain_value(star_rating, score, mods);
+    let accuracy = accuracy_value(windows, score, mods);
+
+    (strain.powf(1.1) + accuracy.powf(1.1)).powf(1.0 / 1.1) * multiplier
+}
+
+fn strain_value(star_rating: f64, score: &Score, mods: &Mods) -> f64 {
+    let total_hits = score.judged() as f64;
+
+    let mut value = (5.0 * (star_rating / 0.0075).max(1.0) - 4.0).powi(2) / 100000.0;
+
+    // Longer maps are worth more.
+    let length_bonus = 1.0 + 0.1 * (total_hits / 1500.0).min(1.0);
+    value *= length_bonus;
+
+    // Every miss is penalised, and so is losing a large part of the combo.
+    value *= 0.985f64.powi(score.miss as i32);
+    value *= (score.max_combo as f64 / total_hits).powf(0.3).min(1.0);
+
+    if mods.hidden {
+        value *= 1.025;
+    }
+    if mods.flashlight {
+        value *= 1.05 * length_bonus;
+    }
+
+    value * score.weighted_accuracy()
+}
+
+fn accuracy_value(windows: &HitWindows, score: &Score, mods: &Mods) -> f64 {
+    let great_window = windows.great * 1000.0;
+    if great_window <= 0.0 {
+        return 0.0;
+    }
+
+    let mut value = (150.0 / great_window).powf(1.1) * score.weighted_accuracy().powi(15) * 22.0;
+
+    // Accuracy over more notes is harder to keep up.
+    value *= (score.judged() as f64 / 1500.0).powf(0.3).min(1.15);
+
+    if mods.hidden && mods.flashlight {
+        value *= 1.1;
+    }
+    value
+}
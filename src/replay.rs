@@ -0,0 +1,273 @@
+//! Recorded plays.
+//!
+//! A replay holds every drum input and offset change of a play, stamped with the song
+//! time they happened at, along with what's needed to play it back: the beatmap hash,
+//! the mods and the offset the play started with.
+//!
+//! Binary layout (little endian):
+//! magic "TKRP", version u16, beatmap hash, player, mods u8, rate f64, offset f64,
+//! score u64, max combo u32, perfect u32, good u32, miss u32,
+//! input count u32 then per input: time delta i32 (microseconds) and key u8,
+//! offset change count u32 then per change: time f64 and offset f64.
+//! Strings are a u16 length followed by UTF-8 bytes.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use resources::*;
+
+const REPLAY_MAGIC: &[u8; 4] = b"TKRP";
+pub const REPLAY_VERSION: u16 = 3;
+
+/// A single drum hit.
+#[derive(Clone, Copy, Debug)]
+pub struct DrumInput {
+    /// Song time of the hit, in seconds, without the offset.
+    pub time: f64,
+    pub key: DrumKey,
+}
+
+/// The user offset was changed during the play.
+#[derive(Clone, Copy, Debug)]
+pub struct OffsetChange {
+    /// Song time of the change, in seconds, without the offset.
+    pub time: f64,
+    pub offset: f64,
+}
+
+#[derive(Clone, Default)]
+pub struct Replay {
+    pub beatmap_hash: String,
+    pub player: String,
+    pub mods: Mods,
+    /// User offset at the start of the play.
+    pub offset: f64,
+    /// Final score of the play.
+    pub score: Score,
+    pub inputs: Vec<DrumInput>,
+    pub offset_changes: Vec<OffsetChange>,
+    /// Imported from an osu! replay. Its score comes from osu!'s own formula, so only
+    /// its judgement counts can be checked.
+    pub imported: bool,
+}
+
+impl Replay {
+    /// User offset in effect at the given song time.
+    pub fn offset_at(&self, time: f64) -> f64 {
+        self.offset_changes
+            .iter()
+            .take_while(|c| c.time <= time)
+            .last()
+            .map(|c| c.offset)
+            .unwrap_or(self.offset)
+    }
+
+    /// Time an input pressed at song time `time` is judged at. The offset is in real
+    /// time, so it is scaled by the rate.
+    pub fn judge_time(&self, time: f64) -> f64 {
+        time + self.offset_at(time) * self.mods.rate
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        if let Some(parent) = Path::new(path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)
+    }
+
+    pub fn load(path: &str) -> io::Result<Replay> {
+        Replay::read(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(REPLAY_MAGIC)?;
+        w.write_u16::<LittleEndian>(REPLAY_VERSION)?;
+        write_string(w, &self.beatmap_hash)?;
+        write_string(w, &self.player)?;
+        w.write_u8(self.mods.bits())?;
+        w.write_f64::<LittleEndian>(self.mods.rate)?;
+        w.write_f64::<LittleEndian>(self.offset)?;
+
+        w.write_u64::<LittleEndian>(self.score.score)?;
+        w.write_u32::<LittleEndian>(self.score.max_combo)?;
+        w.write_u32::<LittleEndian>(self.score.perfect)?;
+        w.write_u32::<LittleEndian>(self.score.good)?;
+        w.write_u32::<LittleEndian>(self.score.miss)?;
+
+        w.write_u32::<LittleEndian>(self.inputs.len() as u32)?;
+        let mut previous = 0;
+        for input in &self.inputs {
+            let time = (input.time * 1_000_000.0).round() as i64;
+            w.write_i32::<LittleEndian>((time - previous) as i32)?;
+            w.write_u8(input.key.to_u8())?;
+            previous = time;
+        }
+
+        w.write_u32::<LittleEndian>(self.offset_changes.len() as u32)?;
+        for change in &self.offset_changes {
+            w.write_f64::<LittleEndian>(change.time)?;
+            w.write_f64::<LittleEndian>(change.offset)?;
+        }
+        w.flush()
+    }
+
+    pub fn read<R: Read>(r: &mut R) -> io::Result<Replay> {
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != REPLAY_MAGIC {
+            return Err(invalid_data("Not a replay file"));
+        }
+        let version = r.read_u16::<LittleEndian>()?;
+        if version != REPLAY_VERSION {
+            return Err(invalid_data(&format!(
+                "Unsupported replay version {}",
+                version
+            )));
+        }
+
+        let beatmap_hash = read_string(r)?;
+        let player = read_string(r)?;
+        let mut mods = Mods::from_bits(r.read_u8()?);
+        mods.set_rate(r.read_f64::<LittleEndian>()?);
+        let offset = r.read_f64::<LittleEndian>()?;
+
+        let score = Score {
+            score: r.read_u64::<LittleEndian>()?,
+            max_combo: r.read_u32::<LittleEndian>()?,
+            perfect: r.read_u32::<LittleEndian>()?,
+            good: r.read_u32::<LittleEndian>()?,
+            miss: r.read_u32::<LittleEndian>()?,
+            ..Score::default()
+        };
+
+        let count = r.read_u32::<LittleEndian>()?;
+        let mut inputs = Vec::with_capacity(count as usize);
+        let mut time = 0;
+        for _ in 0..count {
+            time += r.read_i32::<LittleEndian>()? as i64;
+            let key = DrumKey::from_u8(r.read_u8()?)
+                .ok_or_else(|| invalid_data("Invalid drum key in replay"))?;
+            inputs.push(DrumInput {
+                time: time as f64 / 1_000_000.0,
+                key,
+            });
+        }
+
+        let count = r.read_u32::<LittleEndian>()?;
+        let mut offset_changes = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            offset_changes.push(OffsetChange {
+                time: r.read_f64::<LittleEndian>()?,
+                offset: r.read_f64::<LittleEndian>()?,
+            });
+        }
+
+        Ok(Replay {
+            beatmap_hash,
+            player,
+            mods,
+            offset,
+            score,
+            inputs,
+            offset_changes,
+            imported: false,
+        })
+    }
+}
+
+/// Replay being watched. While `active`, `GameSystem` feeds its inputs to the judgement
+/// instead of reading the keyboard.
+#[derive(Default)]
+pub struct ReplayPlayback {
+    pub active: bool,
+    pub replay: Replay,
+    /// Index of the next input to feed.
+    pub next_input: usize,
+}
+
+impl ReplayPlayback {
+    pub fn new(replay: Replay) -> ReplayPlayback {
+        ReplayPlayback {
+            active: true,
+            replay,
+            next_input: 0,
+        }
+    }
+
+    /// Takes the inputs pressed up to `song_time`, each with its judgement time, which
+    /// includes the offset in effect.
+    pub fn take_inputs(&mut self, song_time: f64) -> Vec<(f64, DrumKey)> {
+        let mut inputs = Vec::new();
+        while let Some(input) = self.replay.inputs.get(self.next_input) {
+            if input.time > song_time {
+                break;
+            }
+            inputs.push((self.replay.judge_time(input.time), input.key));
+            self.next_input += 1;
+        }
+        inputs
+    }
+
+    pub fn finished(&self) -> bool {
+        self.next_input >= self.replay.inputs.len()
+    }
+}
+
+fn write_string<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    w.write_u16::<LittleEndian>(s.len() as u16)?;
+    w.write_all(s.as_bytes())
+}
+
+fn read_string<R: Read>(r: &mut R) -> io::Result<String> {
+    let len = r.read_u16::<LittleEndian>()?;
+    let mut bytes = vec![0u8; len as usize];
+    r.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| invalid_data("Invalid string in replay"))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let replay = Replay {
+            beatmap_hash: String::from("hash"),
+            player: String::from("player"),
+            mods: Mods {
+                hidden: true,
+                rate: 1.25,
+                ..Mods::default()
+            },
+            offset: 0.01,
+            inputs: vec![
+                DrumInput {
+                    time: 1.0,
+                    key: DrumKey::LeftDon,
+                },
+                DrumInput {
+                    time: 1.000_25,
+                    key: DrumKey::RightKat,
+                },
+            ],
+            ..Replay::default()
+        };
+        let mut bytes = vec![];
+        replay.write(&mut bytes).unwrap();
+        let read = Replay::read(&mut &bytes[..]).unwrap();
+        assert_eq!(read.mods, replay.mods);
+        assert_eq!(read.offset, replay.offset);
+        let times = read.inputs.iter().map(|i| (i.time, i.key)).collect::<Vec<_>>();
+        assert_eq!(times, vec![(1.0, DrumKey::LeftDon), (1.000_25, DrumKey::RightKat)]);
+    }
+}
@@ -3,16 +3,125 @@ extern crate amethyst;
 use amethyst::audio::SourceHandle;
 use amethyst::core::timing::Stopwatch;
 use amethyst::ecs::prelude::*;
-use amethyst::renderer::Material;
+use amethyst::renderer::{Material, MeshHandle};
+use amethyst::winit::VirtualKeyCode;
 
-use std::collections::VecDeque;
+use ron;
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
 
 use components::*;
 
+/// Absolute time, in seconds since the game started. Every clock of the game runs on it,
+/// and drum hits stamped with an `Instant` when they are received are placed on it with
+/// `time_of`.
+pub struct GameClock {
+    epoch: Instant,
+    /// Absolute time at the start of the current frame.
+    now: f64,
+}
+
+impl Default for GameClock {
+    fn default() -> Self {
+        GameClock {
+            epoch: Instant::now(),
+            now: 0.0,
+        }
+    }
+}
+
+impl GameClock {
+    /// Absolute time at the start of the current frame.
+    pub fn now(&self) -> f64 {
+        self.now
+    }
+
+    /// Starts a new frame.
+    pub fn tick(&mut self) {
+        self.now = self.time_of(Instant::now());
+    }
+
+    /// Absolute time of `instant`.
+    pub fn time_of(&self, instant: Instant) -> f64 {
+        if instant >= self.epoch {
+            duration_seconds(instant - self.epoch)
+        } else {
+            -duration_seconds(self.epoch - instant)
+        }
+    }
+}
+
+pub fn duration_seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9
+}
+
 pub struct StopwatchWrapper {
     pub stopwatch: Stopwatch,
 }
 
+/// Song time, in seconds. Unlike absolute time, it can be paused, seeked and sped up.
+pub struct SongClock {
+    /// Absolute time at which the song was at `anchor_song_time`.
+    anchor_time: f64,
+    anchor_song_time: f64,
+    rate: f64,
+    paused: bool,
+}
+
+impl SongClock {
+    /// Starts the clock at song time `song_time`.
+    pub fn new(now: f64, song_time: f64) -> SongClock {
+        SongClock {
+            anchor_time: now,
+            anchor_song_time: song_time,
+            rate: 1.0,
+            paused: false,
+        }
+    }
+
+    pub fn time(&self, now: f64) -> f64 {
+        if self.paused {
+            self.anchor_song_time
+        } else {
+            self.anchor_song_time + (now - self.anchor_time) * self.rate
+        }
+    }
+
+    pub fn seek(&mut self, now: f64, song_time: f64) {
+        self.anchor_time = now;
+        self.anchor_song_time = song_time;
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    pub fn set_rate(&mut self, now: f64, rate: f64) {
+        let song_time = self.time(now);
+        self.seek(now, song_time);
+        self.rate = rate;
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, now: f64, paused: bool) {
+        let song_time = self.time(now);
+        self.seek(now, song_time);
+        self.paused = paused;
+    }
+}
+
+impl Default for SongClock {
+    fn default() -> Self {
+        SongClock::new(0.0, 0.0)
+    }
+}
+
 pub struct Sounds {
     pub normal: SourceHandle,
     pub clap: SourceHandle,
@@ -23,9 +132,157 @@ pub struct Sounds {
 #[derive(Clone, Default)]
 pub struct BeatMap {
     pub name: String,
+    /// Path of the .osu file.
+    pub path: String,
     pub songpath: String,
     pub objects: Vec<HitObject>,
-    pub maxhitoffset: f64,
+    pub star_rating: f64,
+    pub od: f64,
+    /// MD5 of the .osu file, used to identify the difficulty.
+    pub hash: String,
+    pub breaks: Vec<Break>,
+    /// Whether black bars cover the top and bottom of the screen during breaks.
+    pub letterbox_in_breaks: bool,
+    /// Path of the background image.
+    pub background: Option<String>,
+    /// Times of the barlines, in seconds.
+    pub barlines: Vec<f64>,
+    pub kiai: Vec<KiaiSection>,
+}
+
+/// A section without notes, from the [Events] of the beatmap. Times are in seconds.
+#[derive(Clone, Copy, Debug)]
+pub struct Break {
+    pub start: f64,
+    pub end: f64,
+}
+
+impl Break {
+    /// How much of the break has passed at `time`, between 0 and 1.
+    pub fn progress(&self, time: f64) -> f64 {
+        ((time - self.start) / (self.end - self.start)).max(0.0).min(1.0)
+    }
+}
+
+/// A section in kiai time, called go-go time in taiko, from the [TimingPoints] of the
+/// beatmap. Times are in seconds.
+#[derive(Clone, Copy, Debug)]
+pub struct KiaiSection {
+    pub start: f64,
+    pub end: f64,
+    /// Length of a beat at the start of the section.
+    pub beat_length: f64,
+}
+
+impl KiaiSection {
+    /// How far into the current beat `time` is, between 0 and 1.
+    pub fn beat_progress(&self, time: f64) -> f64 {
+        ((time - self.start) / self.beat_length).fract().max(0.0)
+    }
+}
+
+impl BeatMap {
+    /// The kiai section `time` is in, if any.
+    pub fn kiai_at(&self, time: f64) -> Option<&KiaiSection> {
+        self.kiai.iter().find(|k| k.start <= time && time < k.end)
+    }
+
+    /// Judgement windows of the map with `mods` applied, in song time. The windows stay
+    /// the same in real time, so they are scaled by the rate.
+    pub fn hit_windows(&self, mods: &Mods) -> HitWindows {
+        self.real_hit_windows(mods).scaled(mods.rate)
+    }
+
+    /// Judgement windows of the map with `mods` applied, in real time.
+    pub fn real_hit_windows(&self, mods: &Mods) -> HitWindows {
+        HitWindows::from_od(mods.overall_difficulty(self.od))
+    }
+
+    /// The break `time` is in, if any.
+    pub fn break_at(&self, time: f64) -> Option<&Break> {
+        self.breaks
+            .iter()
+            .find(|b| b.start <= time && time < b.end)
+    }
+
+    /// Number of objects that get a judgement, without drumrolls and dendens.
+    pub fn note_count(&self) -> usize {
+        self.objects.iter().filter(|o| o.is_note()).count()
+    }
+}
+
+/// Judgement windows, in seconds on either side of a note.
+#[derive(Clone, Copy, Debug)]
+pub struct HitWindows {
+    pub great: f64,
+    pub good: f64,
+    pub miss: f64,
+}
+
+impl HitWindows {
+    /// Windows used by osu!taiko for the given overall difficulty.
+    pub fn from_od(od: f64) -> HitWindows {
+        HitWindows {
+            great: difficulty_range(od, 0.050, 0.035, 0.020),
+            good: difficulty_range(od, 0.120, 0.080, 0.050),
+            miss: difficulty_range(od, 0.135, 0.095, 0.070),
+        }
+    }
+
+    pub fn scaled(&self, factor: f64) -> HitWindows {
+        HitWindows {
+            great: self.great * factor,
+            good: self.good * factor,
+            miss: self.miss * factor,
+        }
+    }
+
+    pub fn judge(&self, offset: f64) -> HitResult {
+        if offset.abs() <= self.great {
+            HitResult::Perfect
+        } else if offset.abs() <= self.good {
+            HitResult::Good
+        } else {
+            HitResult::Miss
+        }
+    }
+}
+
+/// Maps a difficulty setting (0-10) onto the value it has at 0, 5 and 10.
+pub fn difficulty_range(difficulty: f64, min: f64, mid: f64, max: f64) -> f64 {
+    if difficulty > 5.0 {
+        mid + (max - mid) * (difficulty - 5.0) / 5.0
+    } else if difficulty < 5.0 {
+        mid - (mid - min) * (5.0 - difficulty) / 5.0
+    } else {
+        mid
+    }
+}
+
+/// Diameter of a small note, in screen heights.
+pub const NOTE_SIZE: f32 = 0.15;
+/// Size of big notes relative to small ones.
+pub const BIG_NOTE_SCALE: f32 = 1.4;
+
+/// Meshes and materials the notes are drawn with. Circles are sized in screen heights, and
+/// drawn with their width scaled by the inverse of the aspect ratio so they stay round.
+pub struct NoteAssets {
+    pub small_mesh: MeshHandle,
+    pub big_mesh: MeshHandle,
+    /// Drumroll body, starting at its left edge and one screen width long.
+    pub roll_body_mesh: MeshHandle,
+    /// Drumroll end cap, starting at its left edge.
+    pub roll_end_mesh: MeshHandle,
+    pub red: Material,
+    pub blue: Material,
+    pub big_red: Material,
+    pub big_blue: Material,
+    /// Drumroll heads.
+    pub yellow: Material,
+    pub big_yellow: Material,
+    pub roll_body: Material,
+    pub roll_end: Material,
+    pub denden: Material,
 }
 
 pub struct HitResultTextures {
@@ -37,6 +294,7 @@ impl Component for HitResultTextures {
     type Storage = VecStorage<HitResultTextures>;
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum HitResult {
     Perfect,
     Good,
@@ -56,11 +314,14 @@ impl Component for BeatMap {
 #[derive(Default)]
 pub struct HitObjectQueue {
     pub queue: VecDeque<HitObject>,
+    /// First side of a big note hit waiting for the other one, with its judgement time.
+    pub half_big_hit: Option<(f64, DrumKey)>,
 }
 impl HitObjectQueue {
     pub fn new() -> HitObjectQueue {
         HitObjectQueue {
             queue: VecDeque::new(),
+            half_big_hit: None,
         }
     }
 }
@@ -73,11 +334,604 @@ pub struct HitOffsets {
     pub offsets: Vec<Option<f64>>,
 }
 
+impl HitOffsets {
+    /// Offsets of the notes that were hit, without the misses.
+    pub fn hits(&self) -> Vec<f64> {
+        self.offsets.iter().filter_map(|o| *o).collect()
+    }
+
+    /// Mean offset of the last `count` hits.
+    pub fn recent_mean(&self, count: usize) -> Option<f64> {
+        let hits = self.hits();
+        let recent = &hits[hits.len().saturating_sub(count)..];
+        if recent.is_empty() {
+            return None;
+        }
+        Some(recent.iter().sum::<f64>() / recent.len() as f64)
+    }
+
+    /// Unstable rate: the standard deviation of the hit offsets in milliseconds, times 10.
+    /// Offsets are in song time, so they are converted back to real time with `rate`.
+    pub fn unstable_rate(&self, rate: f64) -> Option<f64> {
+        let hits = self.hits();
+        if hits.len() < 2 {
+            return None;
+        }
+        let mean = hits.iter().sum::<f64>() / hits.len() as f64;
+        let variance =
+            hits.iter().map(|o| (o - mean).powi(2)).sum::<f64>() / (hits.len() - 1) as f64;
+        Some(variance.sqrt() / rate * 1000.0 * 10.0)
+    }
+}
+
 impl Component for HitOffsets {
     type Storage = VecStorage<HitOffsets>;
 }
 
+/// Score multiplier of kiai time, go-go time in taiko.
+pub const KIAI_MULTIPLIER: f64 = 1.2;
+
+/// Running score of the current play.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Score {
+    pub score: u64,
+    pub combo: u32,
+    pub max_combo: u32,
+    pub perfect: u32,
+    pub good: u32,
+    pub miss: u32,
+    /// Judgements made in kiai time, also counted above.
+    #[serde(default)]
+    pub kiai_perfect: u32,
+    #[serde(default)]
+    pub kiai_good: u32,
+    #[serde(default)]
+    pub kiai_miss: u32,
+}
+
+impl Score {
+    /// Adds a judgement. `multiplier` comes from the mods. Judgements in `kiai` time are
+    /// worth `KIAI_MULTIPLIER` times more.
+    pub fn add(&mut self, result: HitResult, big: bool, kiai: bool, multiplier: f64) {
+        if kiai {
+            match result {
+                HitResult::Perfect => self.kiai_perfect += 1,
+                HitResult::Good => self.kiai_good += 1,
+                HitResult::Miss => self.kiai_miss += 1,
+            }
+        }
+        let multiplier = if kiai {
+            multiplier * KIAI_MULTIPLIER
+        } else {
+            multiplier
+        };
+        let base = match result {
+            HitResult::Perfect => {
+                self.perfect += 1;
+                300
+            }
+            HitResult::Good => {
+                self.good += 1;
+                150
+            }
+            HitResult::Miss => {
+                self.miss += 1;
+                self.combo = 0;
+                return;
+            }
+        };
+        self.combo += 1;
+        self.max_combo = self.max_combo.max(self.combo);
+        let base = if big { base * 2 } else { base };
+        // Up to twice the base value, reached at 100 combo.
+        let points = base + base * self.combo.min(100) as u64 / 100;
+        self.score += (points as f64 * multiplier) as u64;
+    }
+
+    /// Points from drumroll and denden hits. They don't count as judgements.
+    pub fn add_bonus(&mut self, points: u64) {
+        self.score += points;
+    }
+
+    /// Whether both scores have the same final result, ignoring the current combo.
+    pub fn matches(&self, other: &Score) -> bool {
+        self.score == other.score
+            && self.max_combo == other.max_combo
+            && self.perfect == other.perfect
+            && self.good == other.good
+            && self.miss == other.miss
+    }
+
+    /// Whether both scores have the same judgement counts.
+    pub fn judgements_match(&self, other: &Score) -> bool {
+        self.perfect == other.perfect && self.good == other.good && self.miss == other.miss
+    }
+
+    pub fn judged(&self) -> u32 {
+        self.perfect + self.good + self.miss
+    }
+
+    /// Accuracy between 0 and 1. Goods count for half a perfect.
+    pub fn accuracy(&self) -> f64 {
+        if self.judged() == 0 {
+            return 1.0;
+        }
+        (self.perfect as f64 + self.good as f64 * 0.5) / self.judged() as f64
+    }
+
+    /// Accuracy where the judgements in kiai time weigh `KIAI_MULTIPLIER` times as much,
+    /// like their points do.
+    pub fn weighted_accuracy(&self) -> f64 {
+        let extra = KIAI_MULTIPLIER - 1.0;
+        let kiai_judged = self.kiai_perfect + self.kiai_good + self.kiai_miss;
+        let judged = self.judged() as f64 + kiai_judged as f64 * extra;
+        if judged == 0.0 {
+            return 1.0;
+        }
+        let hits = self.perfect as f64 + self.good as f64 * 0.5;
+        let kiai_hits = self.kiai_perfect as f64 + self.kiai_good as f64 * 0.5;
+        (hits + kiai_hits * extra) / judged
+    }
+}
+
+/// Life gauge of the current play, between 0 and 1.
+/// The play is cleared if the gauge ends above `GAUGE_CLEAR`.
+#[derive(Clone, Default)]
+pub struct Gauge {
+    pub value: f64,
+    /// Gauge gained by a perfect hit.
+    gain: f64,
+    /// Gauge lost by a miss.
+    drain: f64,
+}
+
+pub const GAUGE_CLEAR: f64 = 0.8;
+
+impl Gauge {
+    /// Creates an empty gauge that fills up after hitting most of `object_count` notes.
+    /// Easy makes it recover faster and drain slower.
+    pub fn new(object_count: usize, mods: &Mods) -> Gauge {
+        let gain = 1.0 / (object_count.max(1) as f64 * 0.6);
+        if mods.easy {
+            Gauge {
+                value: 0.0,
+                gain: gain * 1.5,
+                drain: gain,
+            }
+        } else {
+            Gauge {
+                value: 0.0,
+                gain,
+                drain: gain * 2.0,
+            }
+        }
+    }
+
+    pub fn add(&mut self, result: HitResult) {
+        let change = match result {
+            HitResult::Perfect => self.gain,
+            HitResult::Good => self.gain * 0.5,
+            HitResult::Miss => -self.drain,
+        };
+        self.value = (self.value + change).max(0.0).min(1.0);
+    }
+
+    pub fn cleared(&self) -> bool {
+        self.value >= GAUGE_CLEAR
+    }
+}
+
+/// Most recent judgement, shown on the HUD.
+#[derive(Default)]
+pub struct LastJudgement {
+    pub result: Option<HitResult>,
+    /// Song time of the judgement.
+    pub time: f64,
+}
+
+/// Entities making up the gameplay HUD.
+pub struct Hud {
+    pub score: Entity,
+    pub combo: Entity,
+    pub gauge: Entity,
+    pub judgement: Entity,
+    /// "Break" text and the bar showing the time left in the break.
+    pub break_text: Entity,
+    pub break_bar: Entity,
+    /// Black bars shown during breaks if the beatmap asks for them.
+    pub letterbox: Vec<Entity>,
+    /// Only there if enabled in the settings.
+    pub hit_error: Option<HitErrorMeter>,
+}
+
+/// Bar of the judgement windows, with a tick for every hit, a marker at the average of the
+/// recent hits, and the unstable rate.
+pub struct HitErrorMeter {
+    /// Screen width per second of hit error.
+    pub scale: f32,
+    pub ticks: Vec<Entity>,
+    /// Absolute time each tick was placed at.
+    pub tick_times: Vec<f64>,
+    pub next_tick: usize,
+    /// Number of `HitOffsets` already shown.
+    pub seen: usize,
+    pub average: Entity,
+    pub unstable_rate: Entity,
+}
+
+/// Gameplay modifiers selected for a play.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Mods {
+    pub hidden: bool,
+    pub flashlight: bool,
+    pub hard_rock: bool,
+    pub easy: bool,
+    /// Playback speed of the song, between `MIN_RATE` and `MAX_RATE`.
+    #[serde(default = "default_rate")]
+    pub rate: f64,
+}
+
+pub const MIN_RATE: f64 = 0.5;
+pub const MAX_RATE: f64 = 2.0;
+/// Rates of the Double Time and Half Time presets.
+pub const DOUBLE_TIME_RATE: f64 = 1.5;
+pub const HALF_TIME_RATE: f64 = 0.75;
+
+fn default_rate() -> f64 {
+    1.0
+}
+
+impl Default for Mods {
+    fn default() -> Self {
+        Mods {
+            hidden: false,
+            flashlight: false,
+            hard_rock: false,
+            easy: false,
+            rate: 1.0,
+        }
+    }
+}
+
+impl Mods {
+    pub fn bits(&self) -> u8 {
+        (self.hidden as u8)
+            | (self.flashlight as u8) << 1
+            | (self.hard_rock as u8) << 2
+            | (self.easy as u8) << 3
+    }
+
+    /// Mods from `bits()`. The rate isn't part of the bits and is left at 1.
+    pub fn from_bits(bits: u8) -> Mods {
+        Mods {
+            hidden: bits & 1 != 0,
+            flashlight: bits & (1 << 1) != 0,
+            hard_rock: bits & (1 << 2) != 0,
+            easy: bits & (1 << 3) != 0,
+            rate: 1.0,
+        }
+    }
+
+    pub fn set_rate(&mut self, rate: f64) {
+        self.rate = rate.max(MIN_RATE).min(MAX_RATE);
+    }
+
+    /// Overall difficulty the windows are computed from. Hard Rock tightens them, Easy
+    /// loosens them.
+    pub fn overall_difficulty(&self, od: f64) -> f64 {
+        if self.hard_rock {
+            (od * 1.4).min(10.0)
+        } else if self.easy {
+            od * 0.5
+        } else {
+            od
+        }
+    }
+
+    /// Multiplier of the scroll speed of the notes.
+    pub fn scroll_speed(&self) -> f64 {
+        if self.hard_rock {
+            1.4
+        } else if self.easy {
+            0.8
+        } else {
+            1.0
+        }
+    }
+
+    /// Multiplier applied to every point scored.
+    pub fn score_multiplier(&self) -> f64 {
+        let mut multiplier = 1.0;
+        if self.hidden {
+            multiplier *= 1.06;
+        }
+        if self.flashlight {
+            multiplier *= 1.12;
+        }
+        if self.hard_rock {
+            multiplier *= 1.06;
+        }
+        if self.easy {
+            multiplier *= 0.5;
+        }
+        if self.rate > 1.0 {
+            multiplier *= 1.0 + 0.24 * (self.rate - 1.0);
+        } else if self.rate < 1.0 {
+            multiplier *= 0.3;
+        }
+        multiplier
+    }
+
+    /// Whether a note `distance` away from the judgement line (in screen units) is drawn.
+    /// Hidden removes notes as they get close to the line, Flashlight only shows the ones
+    /// close to it.
+    pub fn note_visible(&self, distance: f32) -> bool {
+        if self.hidden && distance < HIDDEN_FADE_DISTANCE {
+            return false;
+        }
+        if self.flashlight && distance > FLASHLIGHT_DISTANCE {
+            return false;
+        }
+        true
+    }
+
+    /// Short names of the enabled mods, like "HD HR" or "DT 1.25x".
+    pub fn names(&self) -> String {
+        let mut names = vec![];
+        if self.hidden {
+            names.push(String::from("HD"));
+        }
+        if self.flashlight {
+            names.push(String::from("FL"));
+        }
+        if self.hard_rock {
+            names.push(String::from("HR"));
+        }
+        if self.easy {
+            names.push(String::from("EZ"));
+        }
+        if self.rate == DOUBLE_TIME_RATE {
+            names.push(String::from("DT"));
+        } else if self.rate == HALF_TIME_RATE {
+            names.push(String::from("HT"));
+        } else if self.rate != 1.0 {
+            names.push(format!("{:.2}x", self.rate));
+        }
+        names.join(" ")
+    }
+}
+
+/// Distance from the judgement line under which Hidden removes the notes.
+const HIDDEN_FADE_DISTANCE: f32 = 0.2;
+/// Distance from the judgement line over which Flashlight removes the notes.
+const FLASHLIGHT_DISTANCE: f32 = 0.25;
+
+/// One of the four drum inputs: don (red) or kat (blue), on the left or right side.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DrumKey {
+    LeftDon,
+    RightDon,
+    LeftKat,
+    RightKat,
+}
+
+impl DrumKey {
+    pub fn from_key_code(key_code: VirtualKeyCode) -> Option<DrumKey> {
+        match key_code {
+            VirtualKeyCode::Z => Some(DrumKey::LeftDon),
+            VirtualKeyCode::X => Some(DrumKey::RightDon),
+            VirtualKeyCode::N => Some(DrumKey::LeftKat),
+            VirtualKeyCode::M => Some(DrumKey::RightKat),
+            _ => None,
+        }
+    }
+
+    pub fn red(&self) -> bool {
+        *self == DrumKey::LeftDon || *self == DrumKey::RightDon
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match *self {
+            DrumKey::LeftDon => 0,
+            DrumKey::RightDon => 1,
+            DrumKey::LeftKat => 2,
+            DrumKey::RightKat => 3,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<DrumKey> {
+        match value {
+            0 => Some(DrumKey::LeftDon),
+            1 => Some(DrumKey::RightDon),
+            2 => Some(DrumKey::LeftKat),
+            3 => Some(DrumKey::RightKat),
+            _ => None,
+        }
+    }
+}
+
+/// Drum hits of the current frame, from the keyboard and every drum controller, with the
+/// absolute time they happened at, oldest first.
 #[derive(Default)]
+pub struct DrumInputs {
+    pub hits: Vec<(f64, DrumKey)>,
+}
+
+/// Seconds a drum part stays lit after a hit.
+const DRUM_FLASH_TIME: f64 = 0.1;
+
+/// When each drum key was last hit, in absolute time, to light the drum.
+pub struct DrumFlash {
+    last_hits: [f64; 4],
+}
+
+impl Default for DrumFlash {
+    fn default() -> Self {
+        DrumFlash {
+            last_hits: [::std::f64::NEG_INFINITY; 4],
+        }
+    }
+}
+
+impl DrumFlash {
+    pub fn hit(&mut self, key: DrumKey, time: f64) {
+        self.last_hits[key.to_u8() as usize] = time;
+    }
+
+    /// Whether the part of the drum for `key` is lit at `time`.
+    pub fn lit(&self, key: DrumKey, time: f64) -> bool {
+        time - self.last_hits[key.to_u8() as usize] < DRUM_FLASH_TIME
+    }
+}
+
+/// Outcome of a finished play.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct PlayResult {
+    pub beatmap_hash: String,
+    pub player: String,
+    pub score: Score,
+    pub mods: Mods,
+    pub performance: f64,
+    /// Unix timestamp of the end of the play, in seconds.
+    pub timestamp: i64,
+    /// Path of the replay file of this play, if one was saved.
+    pub replay: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct UserSettings {
+    #[serde(skip)]
+    path: String,
+    /// Global offset in seconds, added to the time of every input.
     pub offset: f64,
+    /// Offsets added on top of the global one for some beatmaps, by beatmap hash.
+    pub local_offsets: HashMap<String, f64>,
+    pub player_name: String,
+    /// Keep the pitch of the song when playing at a different rate.
+    pub preserve_pitch: bool,
+    /// How much the background is darkened during gameplay, from 0 (not at all) to 1
+    /// (black).
+    pub background_dim: f32,
+    /// Folder of the skin in `skins/`. Empty for the default skin.
+    pub skin: String,
+    /// Show the hit error meter and unstable rate during play.
+    pub hit_error_meter: bool,
+}
+
+impl Default for UserSettings {
+    fn default() -> Self {
+        UserSettings {
+            path: String::new(),
+            offset: 0.0,
+            local_offsets: HashMap::new(),
+            player_name: String::from("Player"),
+            preserve_pitch: true,
+            background_dim: 0.7,
+            skin: String::new(),
+            hit_error_meter: true,
+        }
+    }
+}
+
+impl UserSettings {
+    /// Loads the settings from `path`, using the defaults for anything missing.
+    pub fn load(path: &str) -> UserSettings {
+        let mut content = String::new();
+        let mut settings = match File::open(path).and_then(|mut f| f.read_to_string(&mut content))
+        {
+            Ok(_) => ron::de::from_str(&content).unwrap_or_else(|e| {
+                error!("Failed to parse user settings {}: {}", path, e);
+                UserSettings::default()
+            }),
+            Err(e) => {
+                error!("Failed to read user settings {}: {}", path, e);
+                UserSettings::default()
+            }
+        };
+        settings.path = path.to_string();
+        settings
+    }
+
+    /// Writes the settings back to the file they were loaded from.
+    pub fn save(&self) {
+        let content = match ron::ser::to_string_pretty(self, Default::default()) {
+            Ok(content) => content,
+            Err(e) => {
+                error!("Failed to serialize user settings: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = File::create(&self.path).and_then(|mut f| f.write_all(content.as_bytes()))
+        {
+            error!("Failed to save user settings to {}: {}", self.path, e);
+        }
+    }
+
+    /// Local offset of the beatmap `hash`, 0 if it has none.
+    pub fn local_offset(&self, hash: &str) -> f64 {
+        self.local_offsets.get(hash).cloned().unwrap_or(0.0)
+    }
+
+    /// Offset inputs get on the beatmap `hash`: the global one plus the local one.
+    pub fn offset_for(&self, hash: &str) -> f64 {
+        self.offset + self.local_offset(hash)
+    }
+
+    /// Sets the local offset of the beatmap `hash`. A zero offset removes it.
+    pub fn set_local_offset(&mut self, hash: &str, offset: f64) {
+        if offset == 0.0 {
+            self.local_offsets.remove(hash);
+        } else {
+            self.local_offsets.insert(hash.to_string(), offset);
+        }
+    }
+}
+
+/// Seconds a toast stays on screen, including its fade out.
+pub const TOAST_TIME: f64 = 3.0;
+/// Seconds a toast takes to fade out.
+pub const TOAST_FADE_TIME: f64 = 0.5;
+/// Toasts shown at once. Older ones are dropped early.
+pub const TOAST_LINES: usize = 3;
+
+/// Short messages shown on top of every state, like offset changes or saved settings.
+#[derive(Default)]
+pub struct Toasts {
+    /// Messages on screen, oldest first, with the time they were first shown. The time is
+    /// `None` until the `ToastSystem` picks them up.
+    messages: VecDeque<(String, Option<f64>)>,
+    /// Text entities the toasts are shown in, from the bottom up.
+    pub entities: Vec<Entity>,
+}
+
+impl Toasts {
+    /// Shows `message` for a few seconds. It is logged too.
+    pub fn push(&mut self, message: String) {
+        info!("{}", message);
+        self.messages.push_back((message, None));
+        while self.messages.len() > TOAST_LINES {
+            self.messages.pop_front();
+        }
+    }
+
+    /// Drops the expired toasts and returns the others, newest first, with how visible
+    /// they are between 0 and 1.
+    pub fn update(&mut self, now: f64) -> Vec<(&str, f32)> {
+        for message in self.messages.iter_mut() {
+            if message.1.is_none() {
+                message.1 = Some(now);
+            }
+        }
+        self.messages
+            .retain(|&(_, shown)| shown.map_or(true, |shown| now - shown < TOAST_TIME));
+        self.messages
+            .iter()
+            .rev()
+            .map(|&(ref message, shown)| {
+                let left = TOAST_TIME - (now - shown.unwrap_or(now));
+                (message.as_str(), (left / TOAST_FADE_TIME).min(1.0) as f32)
+            })
+            .collect()
+    }
 }
@@ -0,0 +1,85 @@
+//! Local score database.
+//!
+//! Every finished play is kept in a single ron file, and looked up by beatmap hash and
+//! player name to build the per-map leaderboards.
+
+use ron;
+
+use std::cmp::Ordering;
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use resources::*;
+
+/// Number of scores shown in the song select leaderboard.
+pub const LEADERBOARD_SIZE: usize = 5;
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct ScoreStore {
+    #[serde(skip)]
+    path: String,
+    scores: Vec<PlayResult>,
+}
+
+impl ScoreStore {
+    /// Loads the store from `path`. Starts an empty store if the file doesn't exist yet.
+    pub fn load(path: String) -> ScoreStore {
+        let mut content = String::new();
+        let mut store = match File::open(&path).and_then(|mut f| f.read_to_string(&mut content)) {
+            Ok(_) => ron::de::from_str::<ScoreStore>(&content).unwrap_or_else(|e| {
+                error!("Failed to parse score store {}: {}", path, e);
+                ScoreStore::default()
+            }),
+            Err(_) => ScoreStore::default(),
+        };
+        store.path = path;
+        store
+    }
+
+    pub fn save(&self) {
+        if let Some(parent) = Path::new(&self.path).parent() {
+            if let Err(e) = fs::create_dir_all(parent) {
+                error!("Failed to create score folder {:?}: {}", parent, e);
+                return;
+            }
+        }
+        let content = match ron::ser::to_string_pretty(self, Default::default()) {
+            Ok(content) => content,
+            Err(e) => {
+                error!("Failed to serialize scores: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = File::create(&self.path).and_then(|mut f| f.write_all(content.as_bytes()))
+        {
+            error!("Failed to save scores to {}: {}", self.path, e);
+        }
+    }
+
+    pub fn add(&mut self, result: PlayResult) {
+        self.scores.push(result);
+    }
+
+    /// Best scores of `player` on the given beatmap, highest first.
+    pub fn top_scores(&self, beatmap_hash: &str, player: &str, count: usize) -> Vec<&PlayResult> {
+        let mut scores = self
+            .scores
+            .iter()
+            .filter(|s| s.beatmap_hash == beatmap_hash && s.player == player)
+            .collect::<Vec<_>>();
+        scores.sort_by(|a, b| {
+            b.score
+                .score
+                .cmp(&a.score.score)
+                .then(b.performance.partial_cmp(&a.performance).unwrap_or(Ordering::Equal))
+        });
+        scores.truncate(count);
+        scores
+    }
+
+    pub fn personal_best(&self, beatmap_hash: &str, player: &str) -> Option<&PlayResult> {
+        self.top_scores(beatmap_hash, player, 1).into_iter().next()
+    }
+}
@@ -0,0 +1,223 @@
+//! Skins, in the osu!taiko format.
+//!
+//! A skin is a folder of `skins/` holding elements with their osu! names
+//! (`taikohitcircle.png`, `taiko-hit300.png`, `taiko-normal-hitclap.wav`...) and a
+//! skin.ini. Every element the skin doesn't have falls back to the default assets, so a
+//! skin can override as few files as it wants.
+//!
+//! Besides the [General] name and author, skin.ini can set:
+//!
+//! ```ini
+//! [Colours]
+//! TaikoDon: 235,69,44
+//! TaikoKat: 67,142,172
+//! TaikoDrumroll: 252,184,6
+//!
+//! [Layout]
+//! // Screen positions, from 0 to 1.
+//! HitPosition: 0.3,0.5
+//! JudgementPosition: 0.3,0.65
+//! // Height of the lane in screen heights, and where it starts. The drum fills the left.
+//! LaneHeight: 0.22
+//! LaneLeft: 0.18
+//! ```
+
+use amethyst_extra::*;
+
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+
+/// Where the playfield elements go on screen, from 0 to 1.
+#[derive(Clone, Copy, Debug)]
+pub struct SkinLayout {
+    /// Position of the judgement line. Notes scroll along its height.
+    pub hit_position: [f32; 2],
+    /// Position of the judgement popups.
+    pub judgement_position: [f32; 2],
+    /// Height of the lane the notes scroll on, in screen heights. The lane is centred on
+    /// the hit position.
+    pub lane_height: f32,
+    /// Left edge of the lane. The drum sits left of it.
+    pub lane_left: f32,
+}
+
+impl Default for SkinLayout {
+    fn default() -> Self {
+        SkinLayout {
+            hit_position: [0.3, 0.5],
+            judgement_position: [0.3, 0.65],
+            lane_height: 0.22,
+            lane_left: 0.18,
+        }
+    }
+}
+
+/// Settings read from skin.ini.
+#[derive(Clone, Debug)]
+pub struct SkinIni {
+    pub name: String,
+    pub author: String,
+    /// RGBA colours of the notes.
+    pub don_colour: [f32; 4],
+    pub kat_colour: [f32; 4],
+    pub drumroll_colour: [f32; 4],
+    pub layout: SkinLayout,
+}
+
+impl Default for SkinIni {
+    fn default() -> Self {
+        SkinIni {
+            name: String::from("Default"),
+            author: String::new(),
+            don_colour: [1.0, 0.0, 0.0, 1.0],
+            kat_colour: [0.0, 0.0, 1.0, 1.0],
+            drumroll_colour: [1.0, 0.8, 0.0, 1.0],
+            layout: SkinLayout::default(),
+        }
+    }
+}
+
+impl SkinIni {
+    pub fn parse(content: &str) -> SkinIni {
+        let mut ini = SkinIni::default();
+        let mut section = "";
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+            if line.starts_with('[') {
+                section = line;
+                continue;
+            }
+            let mut split = line.splitn(2, ':');
+            let (key, value) = match (split.next(), split.next()) {
+                (Some(key), Some(value)) => (key.trim(), value.trim()),
+                _ => continue,
+            };
+            match (section, key) {
+                ("[General]", "Name") => ini.name = value.to_string(),
+                ("[General]", "Author") => ini.author = value.to_string(),
+                ("[Colours]", "TaikoDon") => parse_colour(value, &mut ini.don_colour),
+                ("[Colours]", "TaikoKat") => parse_colour(value, &mut ini.kat_colour),
+                ("[Colours]", "TaikoDrumroll") => {
+                    parse_colour(value, &mut ini.drumroll_colour)
+                }
+                ("[Layout]", "HitPosition") => {
+                    parse_position(value, &mut ini.layout.hit_position)
+                }
+                ("[Layout]", "JudgementPosition") => {
+                    parse_position(value, &mut ini.layout.judgement_position)
+                }
+                ("[Layout]", "LaneHeight") => parse_size(value, &mut ini.layout.lane_height),
+                ("[Layout]", "LaneLeft") => parse_size(value, &mut ini.layout.lane_left),
+                _ => {}
+            }
+        }
+        ini
+    }
+}
+
+/// Reads `r,g,b` or `r,g,b,a` from 0 to 255 into `colour`, if valid.
+fn parse_colour(value: &str, colour: &mut [f32; 4]) {
+    let values = value
+        .split(',')
+        .map(|v| v.trim().parse::<u8>())
+        .collect::<Result<Vec<_>, _>>();
+    match values {
+        Ok(ref values) if values.len() == 3 || values.len() == 4 => {
+            for (c, v) in colour.iter_mut().zip(values) {
+                *c = *v as f32 / 255.0;
+            }
+        }
+        _ => error!("Invalid skin colour {:?}", value),
+    }
+}
+
+/// Reads `x,y` into `position`, if valid.
+fn parse_position(value: &str, position: &mut [f32; 2]) {
+    let values = value
+        .split(',')
+        .map(|v| v.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>();
+    match values {
+        Ok(ref values) if values.len() == 2 => position.copy_from_slice(values),
+        _ => error!("Invalid skin position {:?}", value),
+    }
+}
+
+/// Reads a size into `size`, if valid.
+fn parse_size(value: &str, size: &mut f32) {
+    match value.parse::<f32>() {
+        Ok(value) if value >= 0.0 => *size = value,
+        _ => error!("Invalid skin size {:?}", value),
+    }
+}
+
+/// The skin in use.
+#[derive(Clone, Debug, Default)]
+pub struct Skin {
+    pub ini: SkinIni,
+    /// Files of the skin folder, by lowercase name since skins aren't consistent about
+    /// case.
+    files: HashMap<String, String>,
+}
+
+impl Skin {
+    /// Loads the skin `name` from `skins/`. An empty name, or a skin that can't be found,
+    /// gives the default skin.
+    pub fn load(asset_loader: &AssetLoader, name: &str) -> Skin {
+        if name.is_empty() {
+            return Skin::default();
+        }
+        let folder = match asset_loader.resolve_path(&format!("skins/{}", name)) {
+            Some(folder) => folder,
+            None => {
+                error!("Failed to find skin {}, using the default skin", name);
+                return Skin::default();
+            }
+        };
+        let files = match fs::read_dir(&folder) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok())
+                .map(|e| {
+                    (
+                        e.file_name().to_string_lossy().to_lowercase(),
+                        e.path().to_string_lossy().into_owned(),
+                    )
+                })
+                .collect::<HashMap<_, _>>(),
+            Err(e) => {
+                error!("Failed to read skin folder {}: {}", folder, e);
+                return Skin::default();
+            }
+        };
+
+        let mut content = String::new();
+        let ini = match files.get("skin.ini") {
+            Some(path) => match File::open(path).and_then(|mut f| f.read_to_string(&mut content)) {
+                Ok(_) => SkinIni::parse(&content),
+                Err(e) => {
+                    error!("Failed to read {}: {}", path, e);
+                    SkinIni::default()
+                }
+            },
+            None => SkinIni::default(),
+        };
+        info!("Using skin {} by {}", ini.name, ini.author);
+        Skin { ini, files }
+    }
+
+    /// Path of the skin element `file`, if the skin has it.
+    pub fn element(&self, file: &str) -> Option<String> {
+        self.files.get(&file.to_lowercase()).cloned()
+    }
+
+    /// Path of the skin element `file`, falling back to the `default` asset.
+    pub fn resolve(&self, asset_loader: &AssetLoader, file: &str, default: &str) -> Option<String> {
+        self.element(file)
+            .or_else(|| asset_loader.resolve_path(default))
+    }
+}
@@ -2,15 +2,15 @@ extern crate amethyst;
 extern crate futures;
 extern crate rayon;
 
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use amethyst::assets::{AssetStorage, Handle, Loader};
 use amethyst::audio::output::Output;
-use amethyst::audio::WavFormat;
 use amethyst::audio::{AudioSink, OggFormat, Source};
 use amethyst::core::cgmath::{Matrix4, Vector3};
 use amethyst::core::timing::Stopwatch;
-use amethyst::core::transform::{GlobalTransform, Transform};
+use amethyst::core::transform::{GlobalTransform, Parent, Transform};
 use amethyst::ecs::prelude::*;
 use amethyst::prelude::*;
 use amethyst::renderer::*;
@@ -19,256 +19,1220 @@ use amethyst::ui::*;
 use amethyst_extra::*;
 use amethyst::input::*;
 
+use imagefmt;
 use rayon::ThreadPool;
+use time;
 
 use amethyst::winit::VirtualKeyCode;
 
 use amethyst::core::shred::*;
 
+use audio::*;
+use autoplay::*;
+use calibration::*;
+use components::*;
+use difficulty::*;
+use hitsounds::*;
+use performance::*;
+use replay::*;
 use resources::*;
+use scores::*;
+use skin::*;
+use storyboard::*;
 use systems::*;
 use utils::*;
 
+/// Seconds to wait after the last note before showing the results.
+const RESULTS_DELAY: f64 = 2.0;
+/// Rate change of the [ and ] keys in song select.
+const RATE_STEP: f64 = 0.05;
+/// Seconds of song played before the notes a play restarts from.
+const LEAD_IN: f64 = 2.0;
+/// Key to hold to retry, and how long to hold it, in seconds.
+const RETRY_KEY: VirtualKeyCode = VirtualKeyCode::Grave;
+const RETRY_HOLD_TIME: f64 = 0.5;
+/// Shortest wait before the next note that can be skipped, in seconds.
+const SKIP_MIN_GAP: f64 = 5.0;
+/// Seconds the practice start moves by.
+const PRACTICE_SEEK_STEP: f64 = 5.0;
+
+/// Gauge bar placement, in playfield coordinates.
+const GAUGE_Y: f32 = 0.85;
+const GAUGE_HEIGHT: f32 = 0.03;
+const BREAK_BAR_Y: f32 = 0.35;
+const LETTERBOX_HEIGHT: f32 = 0.12;
+const HIT_ERROR_Y: f32 = 0.95;
+/// Ticks of the hit error meter, reused once they all have been shown.
+const HIT_ERROR_TICKS: usize = 30;
+/// Brightness of the blurred background in song select.
+const BACKDROP_BRIGHTNESS: f32 = 0.5;
+
 pub struct GameState {
     dispatch: ParSeq<Arc<rayon::ThreadPool>, GameSystem>,
+    hud_dispatch: ParSeq<Arc<rayon::ThreadPool>, HudSystem>,
+    storyboard_dispatch: ParSeq<Arc<rayon::ThreadPool>, StoryboardSystem>,
+    playfield_dispatch: ParSeq<Arc<rayon::ThreadPool>, PlayfieldSystem>,
     audio_handle: Handle<Source>,
+    finish_time: Option<f64>,
+    /// Set when playing in practice mode.
+    practice: Option<Practice>,
+    /// Prompt shown while the intro or a break can be skipped.
+    skip_text: Option<Entity>,
+    /// Absolute time the retry key started being held at.
+    retry_hold_start: Option<f64>,
+    /// Set once a retry happened, until the retry key is released.
+    retried: bool,
+}
+
+/// Practice mode: the play can start from any time and loop a section between two
+/// marks. Practice scores are never saved.
+#[derive(Default)]
+struct Practice {
+    /// Song time the play starts from. Earlier notes are skipped.
+    start: f64,
+    /// Section to loop, marked with A and B.
+    loop_start: Option<f64>,
+    loop_end: Option<f64>,
+}
+
+impl Practice {
+    fn looped_section(&self) -> Option<(f64, f64)> {
+        match (self.loop_start, self.loop_end) {
+            (Some(start), Some(end)) if end > start => Some((start, end)),
+            _ => None,
+        }
+    }
 }
 
 impl GameState {
+    /// A practice play of the current beatmap.
+    pub fn practice(world: &mut World, audio_handle: Handle<Source>) -> GameState {
+        GameState {
+            practice: Some(Practice::default()),
+            ..GameState::new(world, audio_handle)
+        }
+    }
+
     pub fn new(world: &mut World, audio_handle: Handle<Source>) -> GameState {
         GameState {
             dispatch: ParSeq::new(
-                GameSystem {
-                    reader_id: None,
-                    start_time: 0.0,
-                },
+                GameSystem { reader_id: None },
+                world.read_resource::<Arc<ThreadPool>>().clone(),
+            ),
+            hud_dispatch: ParSeq::new(
+                HudSystem,
+                world.read_resource::<Arc<ThreadPool>>().clone(),
+            ),
+            storyboard_dispatch: ParSeq::new(
+                StoryboardSystem,
+                world.read_resource::<Arc<ThreadPool>>().clone(),
+            ),
+            playfield_dispatch: ParSeq::new(
+                PlayfieldSystem,
                 world.read_resource::<Arc<ThreadPool>>().clone(),
             ),
             audio_handle,
+            finish_time: None,
+            practice: None,
+            skip_text: None,
+            retry_hold_start: None,
+            retried: false,
+        }
+    }
+
+    /// Empty replay recording a play of the current beatmap.
+    fn new_recording(world: &World) -> Replay {
+        let beatmap = world.read_resource::<BeatMap>();
+        let user_settings = world.read_resource::<UserSettings>();
+        Replay {
+            beatmap_hash: beatmap.hash.clone(),
+            player: user_settings.player_name.clone(),
+            mods: *world.read_resource::<Mods>(),
+            offset: user_settings.offset_for(&beatmap.hash),
+            ..Replay::default()
+        }
+    }
+
+    /// Starts the play over without reloading anything. Practice plays go back to their
+    /// start.
+    fn retry(&mut self, world: &mut World) {
+        let start = self.practice.as_ref().map(|p| p.start).unwrap_or(0.0);
+        self.restart_from(world, start);
+        let replay = GameState::new_recording(world);
+        world.add_resource(replay);
+    }
+
+    /// Retries once the retry key has been held long enough.
+    fn update_retry(&mut self, world: &mut World) {
+        let held = world
+            .read_resource::<InputHandler<String, String>>()
+            .key_is_down(RETRY_KEY);
+        if !held {
+            self.retry_hold_start = None;
+            self.retried = false;
+            return;
+        }
+        if self.retried {
+            return;
+        }
+        let now = world.read_resource::<GameClock>().now();
+        let start = *self.retry_hold_start.get_or_insert(now);
+        if now - start > RETRY_HOLD_TIME {
+            self.retried = true;
+            self.retry(world);
+        }
+    }
+
+    /// Song time to skip to, if the next note is far enough away.
+    pub fn skip_target(world: &World) -> Option<f64> {
+        let song_time = GameState::song_time(world);
+        let next = world.read_resource::<HitObjectQueue>().queue.front()?.time;
+        if next - song_time > SKIP_MIN_GAP {
+            Some(next - LEAD_IN)
+        } else {
+            None
+        }
+    }
+
+    /// Current song time, without the offset.
+    pub fn song_time(world: &World) -> f64 {
+        let now = world.read_resource::<GameClock>().now();
+        world.read_resource::<SongClock>().time(now)
+    }
+
+    /// Moves the song and its clock to `song_time`, keeping them paused if they were.
+    pub fn seek_song(world: &mut World, song_time: f64) {
+        let now = world.read_resource::<GameClock>().now();
+        let mut clock = world.write_resource::<SongClock>();
+        clock.seek(now, song_time);
+        let mut song_player = world.write_resource::<SongPlayer>();
+        song_player.play_from(song_time, clock.rate());
+        if clock.paused() {
+            song_player.pause();
+        }
+    }
+
+    /// Removes the notes before `time` from the queue without judging them.
+    pub fn skip_notes_before(world: &mut World, time: f64) {
+        let mut hitqueue = world.write_resource::<HitObjectQueue>();
+        hitqueue.half_big_hit = None;
+        while hitqueue
+            .queue
+            .front()
+            .map(|hit| hit.time < time)
+            .unwrap_or(false)
+        {
+            hitqueue.queue.pop_front();
+        }
+    }
+
+    /// Restarts the play from the notes at `time`, after a short lead-in.
+    fn restart_from(&mut self, world: &mut World, time: f64) {
+        GameState::reset_play(world);
+        GameState::skip_notes_before(world, time);
+        GameState::spawn_notes(world);
+        GameState::seek_song(world, time - LEAD_IN);
+        self.finish_time = None;
+    }
+
+    /// Handles the practice mode keys: Left/Right move the start, A and B mark the
+    /// section to loop and C clears it.
+    fn handle_practice_key(&mut self, world: &mut World, event: &Event) {
+        let song_time = GameState::song_time(world);
+        // Starting after the last object would leave nothing to practice.
+        let last_object = world
+            .read_resource::<BeatMap>()
+            .objects
+            .last()
+            .map_or(0.0, |o| o.time);
+        let restart = {
+            let mut toasts = world.write_resource::<Toasts>();
+            let practice = match self.practice {
+                Some(ref mut practice) => practice,
+                None => return,
+            };
+            if is_key_down(event, VirtualKeyCode::Left) {
+                practice.start = (practice.start - PRACTICE_SEEK_STEP).max(0.0);
+                Some(practice.start)
+            } else if is_key_down(event, VirtualKeyCode::Right) {
+                practice.start = (practice.start + PRACTICE_SEEK_STEP).min(last_object);
+                Some(practice.start)
+            } else if is_key_down(event, VirtualKeyCode::A) {
+                practice.loop_start = Some(song_time);
+                toasts.push(format!("Loop start: {:.2}s", song_time));
+                None
+            } else if is_key_down(event, VirtualKeyCode::B) {
+                practice.loop_end = Some(song_time);
+                toasts.push(format!("Loop end: {:.2}s", song_time));
+                practice.looped_section().map(|(start, _)| start)
+            } else if is_key_down(event, VirtualKeyCode::C) {
+                practice.loop_start = None;
+                practice.loop_end = None;
+                toasts.push(String::from("Loop cleared"));
+                None
+            } else {
+                None
+            }
+        };
+        if let Some(time) = restart {
+            self.restart_from(world, time);
+        }
+    }
+
+    /// Saves the replay of the play next to the scores. Returns its path.
+    pub fn save_replay(world: &World, result: &PlayResult) -> Option<String> {
+        let mut replay = world.write_resource::<Replay>();
+        replay.score = result.score.clone();
+        let path = format!(
+            "{}/userdata/replays/{}-{}.tkr",
+            get_working_dir(),
+            result.beatmap_hash,
+            result.timestamp
+        );
+        match replay.save(&path) {
+            Ok(_) => Some(path),
+            Err(e) => {
+                error!("Failed to save replay to {}: {}", path, e);
+                None
+            }
+        }
+    }
+    pub fn play_result(world: &World) -> PlayResult {
+        let beatmap = world.read_resource::<BeatMap>();
+        let score = world.read_resource::<Score>().clone();
+        let mods = *world.read_resource::<Mods>();
+        let star_rating = if mods.rate == 1.0 {
+            beatmap.star_rating
+        } else {
+            calculate_star_rating_at_rate(&beatmap.objects, mods.rate)
+        };
+        let performance =
+            calculate_performance(star_rating, &beatmap.real_hit_windows(&mods), &score, &mods);
+        PlayResult {
+            beatmap_hash: beatmap.hash.clone(),
+            player: world.read_resource::<UserSettings>().player_name.clone(),
+            score,
+            mods,
+            performance,
+            timestamp: time::get_time().sec,
+            replay: None,
         }
     }
+    /// Loads the default hit sounds, from the skin if it has them.
     pub fn load_sounds(world: &World) -> Sounds {
-        //let loader = world.read_resource::<Loader>();
-        let asset_loader = world.read_resource::<AssetLoader>();
+        let load = |name: &str| {
+            let path = world
+                .read_resource::<Skin>()
+                .resolve(
+                    &world.read_resource(),
+                    name,
+                    &format!("audio/{}", name),
+                )
+                .expect("Failed to find the default hit sounds");
+            load_sample(&path, &world.read_resource(), &world.read_resource())
+        };
+        Sounds {
+            normal: load("taiko-normal-hitnormal.wav"),
+            clap: load("taiko-normal-hitclap.wav"),
+            finish: load("taiko-normal-hitfinish.wav"),
+            whistle: load("taiko-normal-hitwhistle.wav"),
+        }
+    }
 
-        // Unwrap because we know the path is right.
-        let hitsound_normal = asset_loader
-            .load(
-                "audio/taiko-normal-hitnormal.wav",
-                WavFormat,
-                (),
-                &mut world.write_resource(),
-                &mut world.write_resource(),
+    /// Loads the miss, good and perfect judgement textures, from the skin if it has them.
+    pub fn load_hit_results(world: &World) -> (Material, Material, Material) {
+        let load = |name: &str| {
+            let path = world
+                .read_resource::<Skin>()
+                .resolve(
+                    &world.read_resource(),
+                    name,
+                    &format!("textures/hitresult/{}", name),
+                )
+                .expect("Failed to find hit result textures");
+            material_from_image(
+                &path,
                 &world.read_resource(),
-            )
-            .unwrap();
-        let hitsound_clap = asset_loader
-            .load(
-                "audio/taiko-normal-hitclap.wav",
-                WavFormat,
-                (),
-                &mut world.write_resource(),
-                &mut world.write_resource(),
                 &world.read_resource(),
-            )
-            .unwrap();
-        let hitsound_finish = asset_loader
-            .load(
-                "audio/taiko-normal-hitfinish.wav",
-                WavFormat,
-                (),
-                &mut world.write_resource(),
-                &mut world.write_resource(),
-                &world.read_resource(),
-            )
-            .unwrap();
-        let hitsound_whistle = asset_loader
-            .load(
-                "audio/taiko-normal-hitwhistle.wav",
-                WavFormat,
-                (),
-                &mut world.write_resource(),
-                &mut world.write_resource(),
                 &world.read_resource(),
             )
-            .unwrap();
-        Sounds {
-            normal: hitsound_normal,
-            clap: hitsound_clap,
-            finish: hitsound_finish,
-            whistle: hitsound_whistle,
-        }
+        };
+        (
+            load("taiko-hit0.png"),
+            load("taiko-hit100.png"),
+            load("taiko-hit300.png"),
+        )
     }
-    pub fn load_hit_results(
-        hit_results_path: String,
-        world: &World,
-    ) -> (Material, Material, Material) {
+
+    /// Loads the note meshes and materials. The hit circle textures are tinted with the
+    /// skin colours. If they can't be found, notes are plain coloured squares.
+    pub fn load_note_assets(world: &World) -> NoteAssets {
+        let skin = world.read_resource::<Skin>();
+        let asset_loader = world.read_resource::<AssetLoader>();
         let loader = world.read_resource::<Loader>();
-        (
-            material_from_png(
-                &format!("{}/taiko-hit0.png", hit_results_path),
+        let element =
+            |name: &str| skin.resolve(&asset_loader, name, &format!("textures/taiko/{}", name));
+        let small_circle = element("taikohitcircle.png");
+        let small_overlay = element("taikohitcircleoverlay.png");
+        let big_circle = skin
+            .element("taikobigcircle.png")
+            .or_else(|| small_circle.clone());
+        let big_overlay = skin
+            .element("taikobigcircleoverlay.png")
+            .or_else(|| small_overlay.clone());
+        let material = |image: &Option<String>, overlay: &Option<String>, colour: [f32; 4]| {
+            image
+                .as_ref()
+                .and_then(|path| {
+                    tinted_material_from_image(
+                        path,
+                        overlay.as_ref().map(|o| o.as_str()),
+                        colour,
+                        &loader,
+                        &world.read_resource(),
+                        &world.read_resource(),
+                    )
+                })
+                .unwrap_or_else(|| {
+                    material_from_color(
+                        colour,
+                        &loader,
+                        &world.read_resource(),
+                        &world.read_resource(),
+                    )
+                })
+        };
+        let colours = &skin.ini;
+        let white = [1.0; 4];
+        let big_size = NOTE_SIZE * BIG_NOTE_SCALE;
+
+        NoteAssets {
+            small_mesh: gen_rectangle_mesh(
+                NOTE_SIZE,
+                NOTE_SIZE,
                 &loader,
                 &world.read_resource(),
-                &world.read_resource(),
             ),
-            material_from_png(
-                &format!("{}/taiko-hit100.png", hit_results_path),
+            big_mesh: gen_rectangle_mesh(big_size, big_size, &loader, &world.read_resource()),
+            roll_body_mesh: gen_sprite_mesh(
+                1.0,
+                NOTE_SIZE,
+                [0.0, 0.5],
                 &loader,
                 &world.read_resource(),
-                &world.read_resource(),
             ),
-            material_from_png(
-                &format!("{}/taiko-hit300.png", hit_results_path),
+            roll_end_mesh: gen_sprite_mesh(
+                NOTE_SIZE / 2.0,
+                NOTE_SIZE,
+                [0.0, 0.5],
                 &loader,
                 &world.read_resource(),
-                &world.read_resource(),
             ),
-        )
+            red: material(&small_circle, &small_overlay, colours.don_colour),
+            blue: material(&small_circle, &small_overlay, colours.kat_colour),
+            big_red: material(&big_circle, &big_overlay, colours.don_colour),
+            big_blue: material(&big_circle, &big_overlay, colours.kat_colour),
+            yellow: material(&small_circle, &small_overlay, colours.drumroll_colour),
+            big_yellow: material(&big_circle, &big_overlay, colours.drumroll_colour),
+            roll_body: material(&element("taiko-roll-middle.png"), &None, colours.drumroll_colour),
+            roll_end: material(&element("taiko-roll-end.png"), &None, colours.drumroll_colour),
+            denden: material(&element("spinner-warning.png"), &None, white),
+        }
     }
-}
 
-impl<'a, 'b> State<GameData<'a, 'b>> for GameState {
-    fn on_start(&mut self, data: StateData<GameData<'a, 'b>>) {
-        self.dispatch.setup(&mut data.world.res);
+    /// Sets up everything a play needs: playfield, notes, HUD, song and clock.
+    /// Shared by live play and replays.
+    pub fn start_play(world: &mut World, audio_handle: &Handle<Source>) {
+        let sounds = GameState::load_sounds(world);
+        world.add_resource(sounds);
+        let hitsound_bank = HitSoundBank::load(world, &world.read_resource::<BeatMap>());
+        world.add_resource(hitsound_bank);
+        let note_assets = GameState::load_note_assets(world);
+        world.add_resource(note_assets);
+
+        let (miss, good, perfect) = GameState::load_hit_results(world);
+        world.add_resource(HitResultTextures {
+            miss,
+            good,
+            perfect,
+        });
+
+        world
+            .create_entity()
+            .with(Camera::from(Projection::orthographic(0.0, 1.0, 1.0, 0.0)))
+            .with(GlobalTransform(
+                Matrix4::from_translation(Vector3::new(0.0, 0.0, 1.0)).into(),
+            ))
+            .with(Removal::new(RemovalLayer::Gameplay))
+            .build();
+
+        GameState::create_background(world);
 
-        let beatmap = data.world
-            .res
-            .try_fetch::<BeatMap>()
-            .expect("Can't fetch beatmap from resources.")
+        GameState::create_playfield(world);
+
+        let hud = GameState::create_hud(world);
+        world.add_resource(hud);
+
+        GameState::reset_play(world);
+        GameState::spawn_notes(world);
+
+        let song = world
+            .read_resource::<AssetStorage<Source>>()
+            .get(audio_handle)
+            .expect("Can't find music")
+            .bytes
             .clone();
+        let rate = world.read_resource::<Mods>().rate;
+        let preserve_pitch = world.read_resource::<UserSettings>().preserve_pitch;
+        let mut song_player = SongPlayer::new(song, 0.5, preserve_pitch);
+        song_player.play_from(0.0, rate);
+        world.add_resource(song_player);
+        let now = world.read_resource::<GameClock>().now();
+        let mut clock = SongClock::new(now, 0.0);
+        clock.set_rate(now, rate);
+        world.add_resource(clock);
+    }
 
-        let sounds = GameState::load_sounds(&data.world);
+    /// Creates the lane the notes scroll on, its barlines, the judgement circle, their
+    /// kiai glows, and the drum on the left.
+    fn create_playfield(world: &mut World) {
+        let layout = world.read_resource::<Skin>().ini.layout;
+        let (hit_x, hit_y) = (layout.hit_position[0], layout.hit_position[1]);
+        let lane_mtl =
+            GameState::skin_material(world, "taiko-bar-right.png", [0.15, 0.15, 0.15, 0.9]);
+        let panel_mtl = material_from_color(
+            [0.35, 0.12, 0.12, 1.0],
+            &world.read_resource::<Loader>(),
+            &world.read_resource(),
+            &world.read_resource(),
+        );
+        let barline_mtl = material_from_color(
+            [1.0, 1.0, 1.0, 0.6],
+            &world.read_resource::<Loader>(),
+            &world.read_resource(),
+            &world.read_resource(),
+        );
+        let target_mtl =
+            GameState::skin_material(world, "approachcircle.png", [0.0, 1.0, 0.0, 0.5]);
+        let lane_glow_mtl =
+            GameState::skin_material(world, "taiko-bar-right-glow.png", [1.0, 0.6, 0.1, 0.3]);
+        let target_glow_mtl =
+            GameState::skin_material(world, "taiko-glow.png", [1.0, 0.6, 0.1, 0.5]);
+        let drum_mtl =
+            GameState::skin_material(world, "taiko-bar-left.png", [0.6, 0.45, 0.45, 1.0]);
+        let inner_mtl =
+            GameState::skin_material(world, "taiko-drum-inner.png", [1.0, 0.4, 0.3, 0.9]);
+        let outer_mtl =
+            GameState::skin_material(world, "taiko-drum-outer.png", [0.3, 0.7, 1.0, 0.9]);
+        let (strip_mesh, barline_mesh, target_mesh, glow_mesh, drum_mesh, half_drum_mesh) = {
+            let loader = world.read_resource::<Loader>();
+            let storage = world.read_resource::<AssetStorage<Mesh>>();
+            (
+                gen_sprite_mesh(1.0, 1.0, [0.0, 0.5], &loader, &storage),
+                gen_rectangle_mesh(0.002, layout.lane_height, &loader, &storage),
+                gen_rectangle_mesh(NOTE_SIZE, NOTE_SIZE, &loader, &storage),
+                gen_rectangle_mesh(NOTE_SIZE * 1.6, NOTE_SIZE * 1.6, &loader, &storage),
+                gen_rectangle_mesh(layout.lane_height, layout.lane_height, &loader, &storage),
+                gen_sprite_mesh(
+                    layout.lane_height / 2.0,
+                    layout.lane_height,
+                    [1.0, 0.5],
+                    &loader,
+                    &storage,
+                ),
+            )
+        };
+        let transform = |x: f32, width: f32, height: f32| {
+            let mut tr = Transform::default();
+            tr.translation = [x, hit_y, 0.0].into();
+            tr.scale = [width, height, 1.0].into();
+            tr
+        };
 
-        //let (miss, good, perfect) = GameState::load_hit_results(hit_results_path, &data.world);
+        world
+            .create_entity()
+            .with(strip_mesh.clone())
+            .with(lane_mtl)
+            .with(transform(layout.lane_left, 1.0 - layout.lane_left, layout.lane_height))
+            .with(GlobalTransform::default())
+            .with(Removal::new(RemovalLayer::Gameplay))
+            .build();
+        world
+            .create_entity()
+            .with(strip_mesh.clone())
+            .with(lane_glow_mtl)
+            .with(KiaiEffect { pulse: false })
+            .with(transform(layout.lane_left, 1.0 - layout.lane_left, layout.lane_height))
+            .with(GlobalTransform::default())
+            .with(Hidden)
+            .with(Removal::new(RemovalLayer::Gameplay))
+            .build();
 
-        let big_hit_mesh = gen_rectangle_mesh(
-            0.01,
-            0.25,
-            &data.world.read_resource::<Loader>(),
-            &data.world.read_resource(),
+        let barlines = world.read_resource::<BeatMap>().barlines.clone();
+        for time in barlines {
+            world
+                .create_entity()
+                .with(barline_mesh.clone())
+                .with(barline_mtl.clone())
+                .with(Barline { time })
+                .with(transform(hit_x, 1.0, 1.0))
+                .with(GlobalTransform::default())
+                .with(Hidden)
+                .with(Removal::new(RemovalLayer::Gameplay))
+                .build();
+        }
+
+        world
+            .create_entity()
+            .with(glow_mesh)
+            .with(target_glow_mtl)
+            .with(KiaiEffect { pulse: true })
+            .with(transform(hit_x, 1.0, 1.0))
+            .with(GlobalTransform::default())
+            .with(Hidden)
+            .with(Removal::new(RemovalLayer::Gameplay))
+            .build();
+        world
+            .create_entity()
+            .with(target_mesh)
+            .with(target_mtl)
+            .with(KeepAspect { mirrored: false })
+            .with(transform(hit_x, 1.0, 1.0))
+            .with(GlobalTransform::default())
+            .with(Removal::new(RemovalLayer::Gameplay))
+            .build();
+
+        world
+            .create_entity()
+            .with(strip_mesh)
+            .with(panel_mtl)
+            .with(transform(0.0, layout.lane_left, layout.lane_height))
+            .with(GlobalTransform::default())
+            .with(Removal::new(RemovalLayer::Gameplay))
+            .build();
+        let drum_x = layout.lane_left / 2.0;
+        world
+            .create_entity()
+            .with(drum_mesh)
+            .with(drum_mtl)
+            .with(KeepAspect { mirrored: false })
+            .with(transform(drum_x, 1.0, 1.0))
+            .with(GlobalTransform::default())
+            .with(Removal::new(RemovalLayer::Gameplay))
+            .build();
+        // The halves are drawn for the left side, and mirrored for the right one.
+        let parts = [
+            (DrumKey::LeftDon, inner_mtl.clone(), false),
+            (DrumKey::RightDon, inner_mtl, true),
+            (DrumKey::LeftKat, outer_mtl.clone(), false),
+            (DrumKey::RightKat, outer_mtl, true),
+        ];
+        for &(key, ref mtl, mirrored) in parts.iter() {
+            world
+                .create_entity()
+                .with(half_drum_mesh.clone())
+                .with(mtl.clone())
+                .with(DrumPart { key })
+                .with(KeepAspect { mirrored })
+                .with(transform(drum_x, 1.0, 1.0))
+                .with(GlobalTransform::default())
+                .with(Hidden)
+                .with(Removal::new(RemovalLayer::Gameplay))
+                .build();
+        }
+    }
+
+    /// Material of the skin element `file`, or of the default one, or plain `fallback`
+    /// coloured if there is neither.
+    fn skin_material(world: &World, file: &str, fallback: [f32; 4]) -> Material {
+        let path = world.read_resource::<Skin>().resolve(
+            &world.read_resource::<AssetLoader>(),
+            file,
+            &format!("textures/taiko/{}", file),
         );
-        let small_hit_mesh = gen_rectangle_mesh(
-            0.005,
-            0.15,
-            &data.world.read_resource::<Loader>(),
-            &data.world.read_resource(),
+        path.and_then(|path| {
+            tinted_material_from_image(
+                &path,
+                None,
+                [1.0; 4],
+                &world.read_resource::<Loader>(),
+                &world.read_resource(),
+                &world.read_resource(),
+            )
+        }).unwrap_or_else(|| {
+            material_from_color(
+                fallback,
+                &world.read_resource::<Loader>(),
+                &world.read_resource(),
+                &world.read_resource(),
+            )
+        })
+    }
+
+    /// Shows the beatmap background and storyboard behind the playfield, darkened by the
+    /// dim setting.
+    fn create_background(world: &mut World) {
+        let background = world.read_resource::<BeatMap>().background.clone();
+        let dim = world.read_resource::<UserSettings>().background_dim;
+        let mesh = gen_rectangle_mesh(
+            1.0,
+            1.0,
+            &world.read_resource::<Loader>(),
+            &world.read_resource(),
         );
-        let hit_judgement_mesh = gen_rectangle_mesh(
-            0.001,
-            0.25,
-            &data.world.read_resource::<Loader>(),
-            &data.world.read_resource(),
+        let create = |world: &mut World, material: Material, z: f32| {
+            let mut tr = Transform::default();
+            tr.translation = [0.5, 0.5, z].into();
+            world
+                .create_entity()
+                .with(mesh.clone())
+                .with(material)
+                .with(tr)
+                .with(GlobalTransform::default())
+                .with(Removal::new(RemovalLayer::Gameplay))
+                .build();
+        };
+
+        if let Some(path) = background {
+            let material = material_from_image(
+                &path,
+                &world.read_resource::<Loader>(),
+                &world.read_resource(),
+                &world.read_resource(),
+            );
+            create(world, material, -0.5);
+        }
+        GameState::create_storyboard(world);
+        let dim_mtl = material_from_color(
+            [0.0, 0.0, 0.0, dim.max(0.0).min(1.0)],
+            &world.read_resource::<Loader>(),
+            &world.read_resource(),
+            &world.read_resource(),
         );
+        create(world, dim_mtl, -0.4);
+    }
 
-        let red_hit_mtl = material_from_color(
-            [1.0, 0.0, 0.0, 1.0],
-            &data.world.read_resource::<Loader>(),
-            &data.world.read_resource(),
-            &data.world.read_resource(),
+    /// Creates an entity for every storyboard sprite, under a root entity scaling
+    /// storyboard pixels to the screen. Sprites are hidden until the storyboard system
+    /// shows them.
+    fn create_storyboard(world: &mut World) {
+        let path = world.read_resource::<BeatMap>().path.clone();
+        let storyboard = Storyboard::load(&path);
+
+        // Images are shared by sprites, and meshes by sprites with the same image and
+        // origin.
+        let mut images: HashMap<String, Option<(Material, [usize; 2])>> = HashMap::new();
+        let mut meshes: HashMap<(String, Origin), MeshHandle> = HashMap::new();
+        let mut sprites = vec![];
+        {
+            let loader = world.read_resource::<Loader>();
+            for sprite in &storyboard.sprites {
+                let frames = sprite
+                    .frame_paths()
+                    .into_iter()
+                    .map(|path| {
+                        images
+                            .entry(path.clone())
+                            .or_insert_with(|| match imagefmt::read_info(&path) {
+                                Ok(info) => Some((
+                                    material_from_image(
+                                        &path,
+                                        &loader,
+                                        &world.read_resource(),
+                                        &world.read_resource(),
+                                    ),
+                                    [info.w, info.h],
+                                )),
+                                Err(e) => {
+                                    error!("Failed to load storyboard image {}: {:?}", path, e);
+                                    None
+                                }
+                            })
+                            .clone()
+                    })
+                    .collect::<Option<Vec<_>>>();
+                let frames = match frames {
+                    Some(ref frames) if !frames.is_empty() => frames.clone(),
+                    _ => {
+                        sprites.push(None);
+                        continue;
+                    }
+                };
+                let size = frames[0].1;
+                let origin = sprite.origin.offset();
+                let mesh = meshes
+                    .entry((sprite.path.clone(), sprite.origin))
+                    .or_insert_with(|| {
+                        gen_sprite_mesh(
+                            size[0] as f32,
+                            size[1] as f32,
+                            [origin[0] as f32, origin[1] as f32],
+                            &loader,
+                            &world.read_resource(),
+                        )
+                    })
+                    .clone();
+                sprites.push(Some((mesh, frames)));
+            }
+        }
+
+        let mut tr = Transform::default();
+        tr.translation = [
+            (STORYBOARD_WIDESCREEN_OFFSET / STORYBOARD_WIDTH) as f32,
+            0.0,
+            -0.45,
+        ].into();
+        tr.scale = [
+            (1.0 / STORYBOARD_WIDTH) as f32,
+            (1.0 / STORYBOARD_HEIGHT) as f32,
+            1.0,
+        ].into();
+        let root = world
+            .create_entity()
+            .with(tr)
+            .with(GlobalTransform::default())
+            .with(Removal::new(RemovalLayer::Gameplay))
+            .build();
+
+        let entities = sprites
+            .into_iter()
+            .map(|sprite| {
+                sprite.map(|(mesh, frames)| {
+                    let entity = world
+                        .create_entity()
+                        .with(mesh)
+                        .with(frames[0].0.clone())
+                        .with(Transform::default())
+                        .with(GlobalTransform::default())
+                        .with(Parent { entity: root })
+                        .with(Hidden)
+                        .with(Removal::new(RemovalLayer::Gameplay))
+                        .build();
+                    SpriteEntity {
+                        entity,
+                        frames: frames.into_iter().map(|f| f.0).collect(),
+                        frame: 0,
+                    }
+                })
+            })
+            .collect();
+        world.add_resource(StoryboardPlayback::new(storyboard, entities));
+    }
+
+    fn create_hud(world: &mut World) -> Hud {
+        let font = load_font(world).expect("Failed to load font for the HUD");
+        let score = create_text(
+            world,
+            &font,
+            "hud_score",
+            String::new(),
+            Anchor::TopRight,
+            (-150.0, -30.0),
+            300.0,
+            30.0,
+            RemovalLayer::Gameplay,
         );
-        let blue_hit_mtl = material_from_color(
-            [0.0, 0.0, 1.0, 1.0],
-            &data.world.read_resource::<Loader>(),
-            &data.world.read_resource(),
-            &data.world.read_resource(),
+        let combo = create_text(
+            world,
+            &font,
+            "hud_combo",
+            String::new(),
+            Anchor::TopLeft,
+            (150.0, -30.0),
+            300.0,
+            30.0,
+            RemovalLayer::Gameplay,
         );
-        let hit_judgement_mtl = material_from_color(
-            [0.0, 1.0, 0.0, 1.0],
-            &data.world.read_resource::<Loader>(),
-            &data.world.read_resource(),
-            &data.world.read_resource(),
+
+        let gauge_mesh = gen_rectangle_mesh(
+            GAUGE_WIDTH,
+            GAUGE_HEIGHT,
+            &world.read_resource::<Loader>(),
+            &world.read_resource(),
+        );
+        let gauge_background_mtl = material_from_color(
+            [0.2, 0.2, 0.2, 1.0],
+            &world.read_resource::<Loader>(),
+            &world.read_resource(),
+            &world.read_resource(),
+        );
+        let gauge_mtl = material_from_color(
+            [1.0, 0.6, 0.0, 1.0],
+            &world.read_resource::<Loader>(),
+            &world.read_resource(),
+            &world.read_resource(),
+        );
+        let mut tr = Transform::default();
+        tr.translation = [GAUGE_LEFT + GAUGE_WIDTH / 2.0, GAUGE_Y, -0.1].into();
+        world
+            .create_entity()
+            .with(gauge_mesh.clone())
+            .with(gauge_background_mtl)
+            .with(tr)
+            .with(GlobalTransform::default())
+            .with(Removal::new(RemovalLayer::Gameplay))
+            .build();
+        let mut tr = Transform::default();
+        tr.translation = [GAUGE_LEFT, GAUGE_Y, 0.0].into();
+        tr.scale[0] = 0.0;
+        let gauge = world
+            .create_entity()
+            .with(gauge_mesh)
+            .with(gauge_mtl)
+            .with(tr)
+            .with(GlobalTransform::default())
+            .with(Removal::new(RemovalLayer::Gameplay))
+            .build();
+
+        let judgement_mesh = gen_rectangle_mesh(
+            0.06,
+            0.1,
+            &world.read_resource::<Loader>(),
+            &world.read_resource(),
         );
+        let judgement_mtl = world.read_resource::<HitResultTextures>().perfect.clone();
+        let position = world.read_resource::<Skin>().ini.layout.judgement_position;
+        let mut tr = Transform::default();
+        tr.translation = [position[0], position[1], 0.0].into();
+        let judgement = world
+            .create_entity()
+            .with(judgement_mesh)
+            .with(judgement_mtl)
+            .with(tr)
+            .with(GlobalTransform::default())
+            .with(Hidden)
+            .with(Removal::new(RemovalLayer::Gameplay))
+            .build();
 
-        /*data.world.add_resource(HitResultTextures {
-            miss,
-            good,
-            perfect,
-        });*/
+        let break_text = create_text(
+            world,
+            &font,
+            "hud_break",
+            String::new(),
+            Anchor::Middle,
+            (0.0, 100.0),
+            300.0,
+            40.0,
+            RemovalLayer::Gameplay,
+        );
+        let break_bar_mesh = gen_rectangle_mesh(
+            BREAK_BAR_WIDTH,
+            0.01,
+            &world.read_resource::<Loader>(),
+            &world.read_resource(),
+        );
+        let white = material_from_color(
+            [1.0, 1.0, 1.0, 1.0],
+            &world.read_resource::<Loader>(),
+            &world.read_resource(),
+            &world.read_resource(),
+        );
+        let mut tr = Transform::default();
+        tr.translation = [0.5, BREAK_BAR_Y, 0.0].into();
+        let break_bar = world
+            .create_entity()
+            .with(break_bar_mesh)
+            .with(white)
+            .with(tr)
+            .with(GlobalTransform::default())
+            .with(Hidden)
+            .with(Removal::new(RemovalLayer::Gameplay))
+            .build();
 
-        data.world.add_resource(sounds);
+        let letterbox_mesh = gen_rectangle_mesh(
+            1.0,
+            LETTERBOX_HEIGHT,
+            &world.read_resource::<Loader>(),
+            &world.read_resource(),
+        );
+        let black = material_from_color(
+            [0.0, 0.0, 0.0, 1.0],
+            &world.read_resource::<Loader>(),
+            &world.read_resource(),
+            &world.read_resource(),
+        );
+        let letterbox = [LETTERBOX_HEIGHT / 2.0, 1.0 - LETTERBOX_HEIGHT / 2.0]
+            .iter()
+            .map(|y| {
+                let mut tr = Transform::default();
+                tr.translation = [0.5, *y, 0.5].into();
+                world
+                    .create_entity()
+                    .with(letterbox_mesh.clone())
+                    .with(black.clone())
+                    .with(tr)
+                    .with(GlobalTransform::default())
+                    .with(Hidden)
+                    .with(Removal::new(RemovalLayer::Gameplay))
+                    .build()
+            })
+            .collect();
 
-        if let Some(output) = data.world.res.try_fetch::<Output>() {
-            let mut sink = data.world.write_resource::<AudioSink>();
-            sink.set_volume(0.5);
-            let m = data.world.read_resource::<AssetStorage<Source>>();
-            output.play_once(m.get(&self.audio_handle).expect("Can't find music"), 1.0);
+        let hit_error = if world.read_resource::<UserSettings>().hit_error_meter {
+            Some(GameState::create_hit_error_meter(world, &font))
         } else {
-            error!("Failed to find audio `Output`.");
+            None
+        };
+
+        Hud {
+            score,
+            combo,
+            gauge,
+            judgement,
+            break_text,
+            break_bar,
+            letterbox,
+            hit_error,
         }
+    }
 
-        let mut stopwatch = StopwatchWrapper {
-            stopwatch: Stopwatch::new(),
+    /// Creates the hit error meter at the bottom of the screen, with the judgement windows
+    /// of the current beatmap and mods.
+    fn create_hit_error_meter(world: &mut World, font: &FontHandle) -> HitErrorMeter {
+        let windows = {
+            let mods = world.read_resource::<Mods>();
+            world.read_resource::<BeatMap>().hit_windows(&mods)
         };
-        stopwatch.stopwatch.start();
-        data.world.add_resource(stopwatch);
+        let scale = HIT_ERROR_WIDTH / (2.0 * windows.good as f32);
+        let great_width = 2.0 * windows.great as f32 * scale;
+        let y = HIT_ERROR_Y;
+        let rectangle = GameState::create_rectangle;
+        rectangle(world, [HIT_ERROR_WIDTH, 0.008], [0.35, 0.8, 0.3, 0.7], [0.5, y], false);
+        rectangle(world, [great_width, 0.008], [0.3, 0.6, 1.0, 0.8], [0.5, y], false);
+        rectangle(world, [0.002, 0.03], [1.0, 1.0, 1.0, 1.0], [0.5, y], false);
+        let ticks = (0..HIT_ERROR_TICKS)
+            .map(|_| rectangle(world, [0.002, 0.025], [1.0, 1.0, 1.0, 0.8], [0.5, y], true))
+            .collect::<Vec<_>>();
+        // The average marker sits under the bar.
+        let average_position = [0.5, y + 0.025];
+        let average_colour = [1.0, 0.8, 0.0, 1.0];
+        let average = rectangle(world, [0.006, 0.012], average_colour, average_position, true);
 
-        // TODO: Remove this. It needs to be created only once.
-        data.world
+        let unstable_rate = create_text(
+            world,
+            font,
+            "hud_unstable_rate",
+            String::new(),
+            Anchor::BottomMiddle,
+            (0.0, 70.0),
+            300.0,
+            25.0,
+            RemovalLayer::Gameplay,
+        );
+        HitErrorMeter {
+            scale,
+            tick_times: vec![::std::f64::NEG_INFINITY; ticks.len()],
+            ticks,
+            next_tick: 0,
+            seen: 0,
+            average,
+            unstable_rate,
+        }
+    }
+
+    /// Creates a plain coloured rectangle of `size` centred on `position`.
+    fn create_rectangle(
+        world: &mut World,
+        size: [f32; 2],
+        colour: [f32; 4],
+        position: [f32; 2],
+        hidden: bool,
+    ) -> Entity {
+        let mesh = gen_rectangle_mesh(
+            size[0],
+            size[1],
+            &world.read_resource::<Loader>(),
+            &world.read_resource(),
+        );
+        let mtl = material_from_color(
+            colour,
+            &world.read_resource::<Loader>(),
+            &world.read_resource(),
+            &world.read_resource(),
+        );
+        let mut tr = Transform::default();
+        tr.translation = [position[0], position[1], 0.0].into();
+        let mut builder = world
             .create_entity()
-            .with(Camera::from(Projection::orthographic(0.0, 1.0, 1.0, 0.0)))
-            .with(GlobalTransform(
-                Matrix4::from_translation(Vector3::new(0.0, 0.0, 1.0)).into(),
-            ))
-            .build();
+            .with(mesh)
+            .with(mtl)
+            .with(tr)
+            .with(GlobalTransform::default())
+            .with(Removal::new(RemovalLayer::Gameplay));
+        if hidden {
+            builder = builder.with(Hidden);
+        }
+        builder.build()
+    }
 
+    /// Clears the judgements, notes, drum flashes, storyboard triggers and kiai glows of
+    /// the current play, so it can start over.
+    /// Notes have to be spawned again with `spawn_notes`.
+    pub fn reset_play(world: &mut World) {
+        let mut notes = (&*world.entities(), &world.read_storage::<HitObject>())
+            .join()
+            .map(|(entity, _)| entity)
+            .collect::<Vec<_>>();
+        for roll in world.read_storage::<DrumrollSprites>().join() {
+            notes.push(roll.body);
+            notes.push(roll.end);
+        }
+        if let Err(e) = world.delete_entities(&notes) {
+            error!("Failed to delete notes: {:?}", e);
+        }
+
+        let beatmap = world.read_resource::<BeatMap>().clone();
         let mut hitqueue = HitObjectQueue::new();
-        for hit in &beatmap.objects {
-            hitqueue.queue.push_back(hit.clone());
+        hitqueue.queue.extend(beatmap.objects.iter().cloned());
+        world.add_resource(hitqueue);
+        world.add_resource(Score::default());
+        world.add_resource(HitOffsets::default());
+        let mods = *world.read_resource::<Mods>();
+        world.add_resource(Gauge::new(beatmap.note_count(), &mods));
+        world.add_resource(LastJudgement::default());
+        world.add_resource(DrumFlash::default());
+        if let Some(mut storyboard) = world.res.try_fetch_mut::<StoryboardPlayback>() {
+            storyboard.reset();
+        }
 
-            let mut tr = Transform::default();
-            tr.translation = [0.0, 0.5, 0.0].into();
-            let mtl = if hit.red {
-                red_hit_mtl.clone()
-            } else {
-                blue_hit_mtl.clone()
+        // The playfield system shows the kiai glows again if the new start is in kiai time.
+        let glows = (&*world.entities(), &world.read_storage::<KiaiEffect>())
+            .join()
+            .map(|(entity, _)| entity)
+            .collect::<Vec<_>>();
+        let mut hiddens = world.write_storage::<Hidden>();
+        for glow in glows {
+            if let Err(e) = hiddens.insert(glow, Hidden) {
+                error!("Failed to hide kiai glow: {:?}", e);
+            }
+        }
+    }
+
+    /// Creates the visual entities of the notes still waiting in the `HitObjectQueue`.
+    pub fn spawn_notes(world: &mut World) {
+        let hits = world
+            .read_resource::<HitObjectQueue>()
+            .queue
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        let hit_y = world.read_resource::<Skin>().ini.layout.hit_position[1];
+        for hit in hits {
+            let (mesh, mtl) = {
+                let assets = world.read_resource::<NoteAssets>();
+                let mtl = match (hit.kind, hit.red, hit.big) {
+                    (HitObjectKind::Denden { .. }, _, _) => assets.denden.clone(),
+                    (HitObjectKind::Drumroll { .. }, _, false) => assets.yellow.clone(),
+                    (HitObjectKind::Drumroll { .. }, _, true) => assets.big_yellow.clone(),
+                    (HitObjectKind::Note, true, false) => assets.red.clone(),
+                    (HitObjectKind::Note, true, true) => assets.big_red.clone(),
+                    (HitObjectKind::Note, false, false) => assets.blue.clone(),
+                    (HitObjectKind::Note, false, true) => assets.big_blue.clone(),
+                };
+                let mesh = if hit.big {
+                    assets.big_mesh.clone()
+                } else {
+                    assets.small_mesh.clone()
+                };
+                (mesh, mtl)
             };
-            let mesh = if hit.big {
-                big_hit_mesh.clone()
-            } else {
-                small_hit_mesh.clone()
+
+            // The body goes first so it's drawn under the head.
+            let roll = match hit.kind {
+                HitObjectKind::Drumroll { .. } => {
+                    Some(GameState::spawn_drumroll(world, &hit, hit_y))
+                }
+                _ => None,
             };
-            data.world
+
+            let mut tr = Transform::default();
+            tr.translation = [0.0, hit_y, 0.0].into();
+            let mut builder = world
                 .create_entity()
                 .with(mesh)
                 .with(mtl)
-                .with(hit.clone())
+                .with(hit)
                 .with(tr)
                 .with(GlobalTransform::default())
-                .with(Removal::new(RemovalLayer::Gameplay))
-                .build();
+                .with(Removal::new(RemovalLayer::Gameplay));
+            if let Some(roll) = roll {
+                builder = builder.with(roll);
+            }
+            builder.build();
         }
-        data.world.add_resource(hitqueue);
-
-        //add hit judgement On Time
-        // 0.5 screen/sec, 25 ms = 0.0125 screens
+    }
 
-        //Count bars from right to left. 1rst one is at the exact time the note hits,
-        //then each is -25 ms from the one at the right of it.
-        /*for i in 1..20 {
-            let mut tr = LocalTransform::default();
-            tr.translation = [0.3 - (0.0125 as f32 * i as f32), 0.5, 0.0];
+    /// Creates the body and end cap of a drumroll, hidden until it scrolls in.
+    fn spawn_drumroll(world: &mut World, hit: &HitObject, hit_y: f32) -> DrumrollSprites {
+        let (body_mesh, body_mtl, end_mesh, end_mtl) = {
+            let assets = world.read_resource::<NoteAssets>();
+            (
+                assets.roll_body_mesh.clone(),
+                assets.roll_body.clone(),
+                assets.roll_end_mesh.clone(),
+                assets.roll_end.clone(),
+            )
+        };
+        let scale = if hit.big { BIG_NOTE_SCALE } else { 1.0 };
+        let mut create = |mesh: MeshHandle, mtl: Material| {
+            let mut tr = Transform::default();
+            tr.translation = [0.0, hit_y, 0.0].into();
+            tr.scale = [1.0, scale, 1.0].into();
             world
                 .create_entity()
-                .with(hit_judgement_mesh.clone())
-                .with(hit_judgement_mtl.clone())
+                .with(mesh)
+                .with(mtl)
                 .with(tr)
-                .with(Transform::default())
-                .build();
-        }*/
+                .with(GlobalTransform::default())
+                .with(Hidden)
+                .with(Removal::new(RemovalLayer::Gameplay))
+                .build()
+        };
+        DrumrollSprites {
+            body: create(body_mesh, body_mtl),
+            end: create(end_mesh, end_mtl),
+        }
+    }
+}
 
-        let mut tr = Transform::default();
-        tr.translation = [0.3, 0.5, 0.0].into();
-        data.world
-            .create_entity()
-            .with(hit_judgement_mesh.clone())
-            .with(hit_judgement_mtl.clone())
-            .with(tr)
-            .with(GlobalTransform::default())
-            .with(Removal::new(RemovalLayer::Gameplay))
-            .build();
+impl<'a, 'b> State<GameData<'a, 'b>> for GameState {
+    fn on_start(&mut self, data: StateData<GameData<'a, 'b>>) {
+        self.dispatch.setup(&mut data.world.res);
+        self.hud_dispatch.setup(&mut data.world.res);
+        self.storyboard_dispatch.setup(&mut data.world.res);
+        self.playfield_dispatch.setup(&mut data.world.res);
+
+        GameState::start_play(data.world, &self.audio_handle);
+        data.world.add_resource(ReplayPlayback::default());
+
+        let replay = GameState::new_recording(data.world);
+        data.world.add_resource(replay);
+
+        self.skip_text = load_font(data.world).map(|font| {
+            create_text(
+                data.world,
+                &font,
+                "skip",
+                String::new(),
+                Anchor::BottomRight,
+                (-200.0, 60.0),
+                400.0,
+                30.0,
+                RemovalLayer::Gameplay,
+            )
+        });
+
+        let mut stopwatch = StopwatchWrapper {
+            stopwatch: Stopwatch::new(),
+        };
+        stopwatch.stopwatch.start();
+        data.world.add_resource(stopwatch);
     }
 
     fn on_stop(&mut self, mut data: StateData<GameData>) {
+        data.world.write_resource::<SongPlayer>().stop();
+        // Keeps the local offset changes made during the play.
+        data.world.read_resource::<UserSettings>().save();
         exec_removal(
             &data.world.read_resource(),
             &data.world.read_storage(),
@@ -276,37 +1240,320 @@ impl<'a, 'b> State<GameData<'a, 'b>> for GameState {
         );
     }
 
-    fn update(&mut self, mut data: StateData<GameData<'a, 'b>>) -> Trans<GameData<'a, 'b>> {
-        data.data.update(&mut data.world);
-        self.dispatch.dispatch(&mut data.world.res);
-        Trans::None
-    }
-    fn handle_event(
-        &mut self,
-        _: StateData<GameData<'a, 'b>>,
-        event: Event,
-    ) -> Trans<GameData<'a, 'b>> {
-        if is_key_down(&event, VirtualKeyCode::Escape) {
-            return Trans::Pop;
-        } else if is_close_requested(&event) {
-            return Trans::Quit;
+    fn update(&mut self, mut data: StateData<GameData<'a, 'b>>) -> Trans<GameData<'a, 'b>> {
+        data.data.update(&mut data.world);
+        self.dispatch.dispatch(&mut data.world.res);
+        self.hud_dispatch.dispatch(&mut data.world.res);
+        self.storyboard_dispatch.dispatch(&mut data.world.res);
+        self.playfield_dispatch.dispatch(&mut data.world.res);
+
+        self.update_retry(data.world);
+
+        let looped_section = self
+            .practice
+            .as_ref()
+            .and_then(|practice| practice.looped_section());
+        if let Some((start, end)) = looped_section {
+            if GameState::song_time(&data.world) > end {
+                self.restart_from(data.world, start);
+            }
+        }
+
+        if let Some(entity) = self.skip_text {
+            let text = match GameState::skip_target(&data.world) {
+                Some(_) => String::from("Press Space to skip"),
+                None => String::new(),
+            };
+            if let Some(ui_text) = data.world.write_storage::<UiText>().get_mut(entity) {
+                ui_text.text = text;
+            }
+        }
+
+        if data.world.read_resource::<HitObjectQueue>().queue.is_empty() {
+            let now = data.world.read_resource::<GameClock>().now();
+            let finish_time = *self.finish_time.get_or_insert(now);
+            if now - finish_time > RESULTS_DELAY {
+                let mut result = GameState::play_result(&data.world);
+                if self.practice.is_some() {
+                    let note = String::from("Practice mode: the score was not saved.");
+                    return Trans::Switch(Box::new(ResultState::unsaved(result, vec![note])));
+                }
+                result.replay = GameState::save_replay(&data.world, &result);
+                let suggested_offset = suggested_local_offset(
+                    &data.world.read_resource::<UserSettings>(),
+                    &result.beatmap_hash,
+                    &data.world.read_resource::<HitOffsets>(),
+                    result.mods.rate,
+                );
+                return Trans::Switch(Box::new(
+                    ResultState::new(result).with_offset_suggestion(suggested_offset),
+                ));
+            }
+        }
+        Trans::None
+    }
+    fn handle_event(
+        &mut self,
+        data: StateData<GameData<'a, 'b>>,
+        event: Event,
+    ) -> Trans<GameData<'a, 'b>> {
+        if is_key_down(&event, VirtualKeyCode::Escape) {
+            return Trans::Pop;
+        } else if is_close_requested(&event) {
+            return Trans::Quit;
+        }
+        if is_key_down(&event, VirtualKeyCode::Space) {
+            if let Some(target) = GameState::skip_target(&data.world) {
+                GameState::seek_song(data.world, target);
+            }
+        }
+        if self.practice.is_some() {
+            self.handle_practice_key(data.world, &event);
+        }
+        Trans::None
+    }
+}
+
+pub struct MenuState {
+    ui_events: Option<ReaderId<UiEvent>>,
+    all_maps: Vec<BeatMap>,
+    button_entities: Vec<Entity>,
+    leaderboard_entities: Vec<Entity>,
+    /// Hash of the difficulty under the mouse.
+    hovered_map: Option<String>,
+    /// Replay to watch as soon as the maps are loaded.
+    pending_replay: Option<Replay>,
+    /// Line showing the selected mods.
+    mods_text: Option<Entity>,
+    /// Blurred background of the hovered difficulty.
+    backdrop: Option<Entity>,
+    /// Blurred backgrounds already loaded, by image path. `None` if the image failed to
+    /// load.
+    backdrops: HashMap<String, Option<Material>>,
+    /// Whether the difficulties that failed to load were already reported.
+    skipped_reported: bool,
+}
+
+impl MenuState {
+    pub fn new() -> Self {
+        MenuState {
+            ui_events: None,
+            all_maps: vec![],
+            button_entities: vec![],
+            leaderboard_entities: vec![],
+            hovered_map: None,
+            pending_replay: None,
+            mods_text: None,
+            backdrop: None,
+            backdrops: HashMap::new(),
+            skipped_reported: false,
+        }
+    }
+
+    /// Starts on song select, then plays back `replay` on its beatmap.
+    pub fn with_replay(replay: Replay) -> Self {
+        MenuState {
+            pending_replay: Some(replay),
+            ..MenuState::new()
+        }
+    }
+
+    /// The difficulty under the mouse.
+    fn hovered(&self) -> Option<BeatMap> {
+        let hash = self.hovered_map.as_ref()?;
+        self.all_maps.iter().find(|m| m.hash == *hash).cloned()
+    }
+
+    /// Selects `map` and removes the song select UI.
+    fn select_map(&mut self, world: &mut World, map: BeatMap) {
+        world.add_resource(map);
+        self.clear(world);
+    }
+
+    /// Removes the song select entities before another state takes over the screen.
+    fn clear(&mut self, world: &mut World) {
+        world
+            .delete_entities(&self.button_entities)
+            .expect("Failed to cleanup MenuState buttons");
+        exec_removal(
+            &world.read_resource(),
+            &world.read_storage(),
+            RemovalLayer::SongSelect,
+        );
+    }
+
+    /// Toggles the mods bound to F1-F6 and changes the rate with [ and ].
+    /// Hard Rock and Easy can't be used together.
+    fn toggle_mod(&mut self, world: &mut World, key: VirtualKeyCode) {
+        {
+            let mut mods = world.write_resource::<Mods>();
+            match key {
+                VirtualKeyCode::F5 => {
+                    let rate = if mods.rate == DOUBLE_TIME_RATE {
+                        1.0
+                    } else {
+                        DOUBLE_TIME_RATE
+                    };
+                    mods.set_rate(rate);
+                }
+                VirtualKeyCode::F6 => {
+                    let rate = if mods.rate == HALF_TIME_RATE {
+                        1.0
+                    } else {
+                        HALF_TIME_RATE
+                    };
+                    mods.set_rate(rate);
+                }
+                VirtualKeyCode::LBracket => {
+                    let rate = mods.rate - RATE_STEP;
+                    mods.set_rate(rate);
+                }
+                VirtualKeyCode::RBracket => {
+                    let rate = mods.rate + RATE_STEP;
+                    mods.set_rate(rate);
+                }
+                VirtualKeyCode::F1 => mods.hidden = !mods.hidden,
+                VirtualKeyCode::F2 => mods.flashlight = !mods.flashlight,
+                VirtualKeyCode::F3 => {
+                    mods.hard_rock = !mods.hard_rock;
+                    mods.easy = false;
+                }
+                VirtualKeyCode::F4 => {
+                    mods.easy = !mods.easy;
+                    mods.hard_rock = false;
+                }
+                _ => return,
+            }
+        }
+        self.update_mods_text(world);
+    }
+
+    fn update_mods_text(&self, world: &World) {
+        let mods = *world.read_resource::<Mods>();
+        let text = if mods == Mods::default() {
+            String::from("Mods (F1-F6, [ ]): none")
+        } else {
+            format!(
+                "Mods (F1-F6, [ ]): {} (x{:.2})",
+                mods.names(),
+                mods.score_multiplier()
+            )
+        };
+        if let Some(entity) = self.mods_text {
+            if let Some(ui_text) = world.write_storage::<UiText>().get_mut(entity) {
+                ui_text.text = text;
+            }
+        }
+    }
+
+    /// Creates the camera and the hidden backdrop entity of song select.
+    fn create_backdrop(&mut self, world: &mut World) {
+        world
+            .create_entity()
+            .with(Camera::from(Projection::orthographic(0.0, 1.0, 1.0, 0.0)))
+            .with(GlobalTransform(
+                Matrix4::from_translation(Vector3::new(0.0, 0.0, 1.0)).into(),
+            ))
+            .with(Removal::new(RemovalLayer::SongSelect))
+            .build();
+        let mesh = gen_rectangle_mesh(
+            1.0,
+            1.0,
+            &world.read_resource::<Loader>(),
+            &world.read_resource(),
+        );
+        let mut tr = Transform::default();
+        tr.translation = [0.5, 0.5, -0.5].into();
+        self.backdrop = Some(
+            world
+                .create_entity()
+                .with(mesh)
+                .with(tr)
+                .with(GlobalTransform::default())
+                .with(Hidden)
+                .with(Removal::new(RemovalLayer::SongSelect))
+                .build(),
+        );
+    }
+
+    /// Shows the blurred background of `map` behind the song list.
+    fn show_backdrop(&mut self, world: &World, map: &BeatMap) {
+        let backdrop = match self.backdrop {
+            Some(backdrop) => backdrop,
+            None => return,
+        };
+        let material = map.background.as_ref().and_then(|path| {
+            self.backdrops
+                .entry(path.clone())
+                .or_insert_with(|| {
+                    blurred_material_from_image(
+                        path,
+                        BACKDROP_BRIGHTNESS,
+                        &world.read_resource(),
+                        &world.read_resource(),
+                        &world.read_resource(),
+                    )
+                })
+                .clone()
+        });
+        let mut hiddens = world.write_storage::<Hidden>();
+        match material {
+            Some(material) => {
+                world
+                    .write_storage::<Material>()
+                    .insert(backdrop, material)
+                    .expect("Failed to set the song select backdrop");
+                hiddens.remove(backdrop);
+            }
+            None => {
+                hiddens
+                    .insert(backdrop, Hidden)
+                    .expect("Failed to hide the song select backdrop");
+            }
         }
-        Trans::None
     }
-}
 
-pub struct MenuState {
-    ui_events: Option<ReaderId<UiEvent>>,
-    all_maps: Vec<BeatMap>,
-    button_entities: Vec<Entity>,
-}
+    /// Loads the replay of the best local score on the hovered difficulty.
+    fn best_replay(&self, world: &World) -> Option<Replay> {
+        let hash = self.hovered_map.as_ref()?;
+        let player = world.read_resource::<UserSettings>().player_name.clone();
+        let store = world.read_resource::<ScoreStore>();
+        let path = store
+            .top_scores(hash, &player, LEADERBOARD_SIZE)
+            .into_iter()
+            .filter_map(|s| s.replay.clone())
+            .next()?;
+        match Replay::load(&path) {
+            Ok(replay) => Some(replay),
+            Err(e) => {
+                error!("Failed to load replay {}: {}", path, e);
+                None
+            }
+        }
+    }
 
-impl MenuState {
-    pub fn new() -> Self {
-        MenuState {
-            ui_events: None,
-            all_maps: vec![],
-            button_entities: vec![],
+    /// Shows the local top scores of the given difficulty.
+    fn show_leaderboard(&self, world: &World, beatmap_hash: &str) {
+        let store = world.read_resource::<ScoreStore>();
+        let player = world.read_resource::<UserSettings>().player_name.clone();
+        let scores = store.top_scores(beatmap_hash, &player, LEADERBOARD_SIZE);
+        let mut texts = world.write_storage::<UiText>();
+        for (i, entity) in self.leaderboard_entities.iter().enumerate() {
+            if let Some(text) = texts.get_mut(*entity) {
+                text.text = match scores.get(i) {
+                    Some(s) => format!(
+                        "{}. {}  {:.2}%  {}x  {:.0}pp  {}",
+                        i + 1,
+                        s.score.score,
+                        s.score.accuracy() * 100.0,
+                        s.score.max_combo,
+                        s.performance,
+                        s.mods.names()
+                    ),
+                    None if i == 0 => String::from("No local scores"),
+                    None => String::new(),
+                };
+            }
         }
     }
 }
@@ -318,7 +1565,18 @@ impl<'a, 'b> State<GameData<'a, 'b>> for MenuState {
             .read_resource::<AssetLoader>()
             .resolve_path("maps")
             .expect("Failed to find maps folder");
-        let beatmaps = beatmap_list(&map_folder);
+        let files = beatmap_files(&map_folder);
+        let beatmaps = files
+            .iter()
+            .filter_map(|&(ref folder, ref diff)| read_beatmap(folder, diff))
+            .collect::<Vec<BeatMap>>();
+        if !self.skipped_reported && beatmaps.len() < files.len() {
+            data.world.write_resource::<Toasts>().push(format!(
+                "Skipped {} unsupported difficulties",
+                files.len() - beatmaps.len()
+            ));
+        }
+        self.skipped_reported = true;
         self.ui_events = Some(
             data.world
                 .write_resource::<EventChannel<UiEvent>>()
@@ -335,15 +1593,55 @@ impl<'a, 'b> State<GameData<'a, 'b>> for MenuState {
             &data.world.read_resource(),
         );
 
+        exec_removal(
+            &data.world.read_resource(),
+            &data.world.read_storage(),
+            RemovalLayer::SongSelect,
+        );
         self.button_entities.clear();
+        self.leaderboard_entities.clear();
+        self.create_backdrop(&mut data.world);
+        if let Some(map) = self.hovered() {
+            self.show_backdrop(&data.world, &map);
+        }
         if let Some(font) = font {
+            if data.world.read_resource::<Toasts>().entities.is_empty() {
+                create_toasts(&mut data.world, &font);
+            }
+            self.mods_text = Some(create_text(
+                &mut data.world,
+                &font,
+                "mods",
+                String::new(),
+                Anchor::TopLeft,
+                (250.0, -60.0),
+                500.0,
+                20.0,
+                RemovalLayer::SongSelect,
+            ));
+            self.update_mods_text(&data.world);
+            for i in 0..LEADERBOARD_SIZE {
+                let entity = create_text(
+                    &mut data.world,
+                    &font,
+                    &format!("leaderboard_{}", i),
+                    String::new(),
+                    Anchor::TopRight,
+                    (-250.0, -60.0 - 40.0 * i as f32),
+                    500.0,
+                    20.0,
+                    RemovalLayer::SongSelect,
+                );
+                self.leaderboard_entities.push(entity);
+            }
             for (i, b) in beatmaps.iter().enumerate() {
-                println!("Found beatmap: {}", b.songpath);
+                debug!("Found beatmap: {}", b.songpath);
                 /*world.create_entity()
                 .with(UiTransform::new("song_select",Anchor::TopMiddle, 0.0, -80.0 * (i as f32 + 1.0), 0.0, 1000.0, 80.0, i))
                 .with(UiText::new(font,""))
                 .build();*/
-                let entity = UiButtonBuilder::new(b.songpath.clone(), b.name.clone())
+                let label = format!("{} ({:.2}*)", b.name, b.star_rating);
+                let entity = UiButtonBuilder::new(b.hash.clone(), label)
                     .with_anchor(Anchor::TopMiddle)
                     .with_position(0.0, 40.0 + 100.0 * (i as f32 + 1.0))
                     .with_size(1000.0, 80.0)
@@ -372,52 +1670,304 @@ impl<'a, 'b> State<GameData<'a, 'b>> for MenuState {
 
     fn handle_event(
         &mut self,
-        _: StateData<GameData<'a, 'b>>,
+        data: StateData<GameData<'a, 'b>>,
         event: Event,
     ) -> Trans<GameData<'a, 'b>> {
         if is_key_down(&event, VirtualKeyCode::Space) || is_close_requested(&event) {
             return Trans::Quit;
         }
+        for key in [
+            VirtualKeyCode::F1,
+            VirtualKeyCode::F2,
+            VirtualKeyCode::F3,
+            VirtualKeyCode::F4,
+            VirtualKeyCode::F5,
+            VirtualKeyCode::F6,
+            VirtualKeyCode::LBracket,
+            VirtualKeyCode::RBracket,
+        ].iter()
+        {
+            if is_key_down(&event, *key) {
+                self.toggle_mod(data.world, *key);
+            }
+        }
+        // Practice the hovered difficulty.
+        if is_key_down(&event, VirtualKeyCode::P) {
+            if let Some(map) = self.hovered() {
+                self.select_map(data.world, map);
+                return Trans::Push(Box::new(BeatmapLoadState::practice()));
+            }
+        }
+        // Watch autoplay on the hovered difficulty.
+        if is_key_down(&event, VirtualKeyCode::A) {
+            if let Some(map) = self.hovered() {
+                let replay = autoplay_replay(&map, *data.world.read_resource::<Mods>());
+                self.select_map(data.world, map);
+                return Trans::Push(Box::new(BeatmapLoadState::new(Some(replay))));
+            }
+        }
+        // Calibrate the global offset.
+        if is_key_down(&event, VirtualKeyCode::O) {
+            self.clear(data.world);
+            return Trans::Push(Box::new(CalibrationState::default()));
+        }
+        // Watch the best local replay of the hovered difficulty.
+        if is_key_down(&event, VirtualKeyCode::R) {
+            if let Some(replay) = self.best_replay(&data.world) {
+                let map = self
+                    .all_maps
+                    .iter()
+                    .find(|m| m.hash == replay.beatmap_hash)
+                    .cloned();
+                if let Some(map) = map {
+                    self.select_map(data.world, map);
+                    return Trans::Push(Box::new(BeatmapLoadState::new(Some(replay))));
+                }
+            }
+        }
         Trans::None
     }
     fn update(&mut self, mut data: StateData<GameData<'a, 'b>>) -> Trans<GameData<'a, 'b>> {
         data.data.update(&mut data.world);
+        if let Some(replay) = self.pending_replay.take() {
+            let map = self
+                .all_maps
+                .iter()
+                .find(|m| m.hash == replay.beatmap_hash)
+                .cloned();
+            match map {
+                Some(map) => {
+                    self.select_map(data.world, map);
+                    return Trans::Push(Box::new(BeatmapLoadState::new(Some(replay))));
+                }
+                None => error!(
+                    "No beatmap matches the replay's hash {}. Is the map in the maps folder?",
+                    replay.beatmap_hash
+                ),
+            }
+        }
         let mut found_map = None;
+        let mut hovered_map = None;
         for ev in data.world
             .read_resource::<EventChannel<UiEvent>>()
             .read(&mut self.ui_events.as_mut().unwrap())
         {
             match ev.event_type {
                 UiEventType::Click => {
-                    let songpath = data.world
+                    let hash = data.world
                         .read_storage::<UiTransform>()
                         .get(ev.target)
                         .unwrap()
                         .id
                         .clone();
-                    if let Some(map) = self.all_maps.iter().find(|m| m.songpath == *songpath) {
+                    if let Some(map) = self.all_maps.iter().find(|m| m.hash == *hash) {
                         found_map = Some(map.clone());
                     } else {
-                        error!("Could not find selected map {} when clicking the select button. Was it removed?",songpath);
+                        error!("Could not find selected map {} when clicking the select button. Was it removed?",hash);
+                    }
+                }
+                UiEventType::HoverStart => {
+                    if let Some(tr) = data.world.read_storage::<UiTransform>().get(ev.target) {
+                        if self.all_maps.iter().any(|m| m.hash == tr.id) {
+                            hovered_map = Some(tr.id.clone());
+                        }
                     }
                 }
                 _ => {}
             }
         }
+        if let Some(hash) = hovered_map {
+            self.show_leaderboard(&data.world, &hash);
+            self.hovered_map = Some(hash);
+            if let Some(map) = self.hovered() {
+                self.show_backdrop(&data.world, &map);
+            }
+        }
         if let Some(map) = found_map {
-            data.world.add_resource(map);
-            data.world
-                .delete_entities(&self.button_entities)
-                .expect("Failed to cleanup MenuState buttons");
-            return Trans::Push(Box::new(BeatmapLoadState { audio_handle: None }));
+            self.select_map(data.world, map);
+            return Trans::Push(Box::new(BeatmapLoadState::new(None)));
+        }
+
+        Trans::None
+    }
+}
+
+/// Offset calibration wizard. The player hits the drum along a metronome, and the mean
+/// offset of the hits gives a global offset, saved in the user settings on confirmation.
+#[derive(Default)]
+pub struct CalibrationState {
+    calibration: OffsetCalibration,
+    player: Option<SongPlayer>,
+    clock: Option<SongClock>,
+    status: Option<Entity>,
+}
+
+impl CalibrationState {
+    /// Starts the metronome over and forgets the previous taps.
+    fn restart(&mut self, world: &World) {
+        if let Some(ref mut player) = self.player {
+            player.stop();
+        }
+        let (samples, sample_rate) = metronome_track();
+        let mut player = SongPlayer::from_samples(samples, 1, sample_rate, 0.5, false);
+        player.play_from(0.0, 1.0);
+        self.player = Some(player);
+        let now = world.read_resource::<GameClock>().now();
+        self.clock = Some(SongClock::new(now, 0.0));
+        self.calibration = OffsetCalibration::default();
+    }
+
+    /// Song time of the metronome, once started.
+    fn time(&self, world: &World) -> Option<f64> {
+        let now = world.read_resource::<GameClock>().now();
+        self.clock.as_ref().map(|clock| clock.time(now))
+    }
+
+    fn finished(&self, world: &World) -> bool {
+        self.time(world)
+            .map_or(false, |time| time > metronome_duration() + 0.5)
+    }
+
+    fn status(&self, world: &World) -> String {
+        let current = world.read_resource::<UserSettings>().offset * 1000.0;
+        if !self.finished(world) {
+            return format!("Taps: {}", self.calibration.taps.len());
+        }
+        match self.calibration.suggested_offset() {
+            Some(offset) => format!(
+                "Suggested offset: {:+.1} ms (currently {:+.1} ms). \
+                 Enter to save, R to try again, Escape to cancel.",
+                offset * 1000.0,
+                current
+            ),
+            None => String::from(
+                "Not enough taps on the beat. R to try again, Escape to cancel.",
+            ),
+        }
+    }
+}
+
+impl<'a, 'b> State<GameData<'a, 'b>> for CalibrationState {
+    fn on_start(&mut self, data: StateData<GameData<'a, 'b>>) {
+        let font = match load_font(data.world) {
+            Some(font) => font,
+            None => {
+                error!("Failed to load font for the offset calibration.");
+                return;
+            }
+        };
+        let lines = [
+            "Offset calibration",
+            "Hit the drum on every click, starting from the second bar.",
+        ];
+        for (i, line) in lines.iter().enumerate() {
+            create_text(
+                data.world,
+                &font,
+                &format!("calibration_{}", i),
+                line.to_string(),
+                Anchor::TopMiddle,
+                (0.0, -80.0 * (i as f32 + 1.0)),
+                1000.0,
+                30.0,
+                RemovalLayer::Calibration,
+            );
+        }
+        self.status = Some(create_text(
+            data.world,
+            &font,
+            "calibration_status",
+            String::new(),
+            Anchor::Middle,
+            (0.0, 0.0),
+            1400.0,
+            30.0,
+            RemovalLayer::Calibration,
+        ));
+        self.restart(data.world);
+    }
+
+    fn on_stop(&mut self, data: StateData<GameData>) {
+        if let Some(ref mut player) = self.player {
+            player.stop();
+        }
+        exec_removal(
+            &data.world.read_resource(),
+            &data.world.read_storage(),
+            RemovalLayer::Calibration,
+        );
+    }
+
+    fn update(&mut self, mut data: StateData<GameData<'a, 'b>>) -> Trans<GameData<'a, 'b>> {
+        data.data.update(&mut data.world);
+        // Taps come from the keyboard and the drum controllers alike.
+        if let Some(ref clock) = self.clock {
+            for &(time, _) in data.world.read_resource::<DrumInputs>().hits.iter() {
+                self.calibration.tap(clock.time(time));
+            }
+        }
+        let status = self.status(data.world);
+        if let Some(entity) = self.status {
+            if let Some(text) = data.world.write_storage::<UiText>().get_mut(entity) {
+                text.text = status;
+            }
         }
+        Trans::None
+    }
 
+    fn handle_event(
+        &mut self,
+        data: StateData<GameData<'a, 'b>>,
+        event: Event,
+    ) -> Trans<GameData<'a, 'b>> {
+        if is_key_down(&event, VirtualKeyCode::Escape) {
+            return Trans::Pop;
+        } else if is_close_requested(&event) {
+            return Trans::Quit;
+        }
+        if is_key_down(&event, VirtualKeyCode::R) {
+            self.restart(data.world);
+        }
+        if is_key_down(&event, VirtualKeyCode::Return) && self.finished(data.world) {
+            if let Some(offset) = self.calibration.suggested_offset() {
+                {
+                    let mut settings = data.world.write_resource::<UserSettings>();
+                    settings.offset = offset;
+                    settings.save();
+                }
+                data.world
+                    .write_resource::<Toasts>()
+                    .push(format!("Global offset saved: {:+.1} ms", offset * 1000.0));
+                return Trans::Pop;
+            }
+        }
         Trans::None
     }
 }
 
 pub struct BeatmapLoadState {
     audio_handle: Option<Handle<Source>>,
+    /// Replay to watch once loaded, instead of playing.
+    replay: Option<Replay>,
+    practice: bool,
+}
+
+impl BeatmapLoadState {
+    pub fn new(replay: Option<Replay>) -> Self {
+        BeatmapLoadState {
+            audio_handle: None,
+            replay,
+            practice: false,
+        }
+    }
+
+    /// Loads the beatmap for a practice play.
+    pub fn practice() -> Self {
+        BeatmapLoadState {
+            practice: true,
+            ..BeatmapLoadState::new(None)
+        }
+    }
 }
 
 impl<'a, 'b> State<GameData<'a, 'b>> for BeatmapLoadState {
@@ -445,12 +1995,399 @@ impl<'a, 'b> State<GameData<'a, 'b>> for BeatmapLoadState {
             .get(&self.audio_handle.as_ref().unwrap())
             .is_some()
         {
-            Trans::Switch(Box::new(GameState::new(
-                data.world,
-                self.audio_handle.as_ref().unwrap().clone(),
-            )))
+            let audio_handle = self.audio_handle.as_ref().unwrap().clone();
+            match self.replay.take() {
+                Some(replay) => Trans::Switch(Box::new(ReplayState::new(
+                    data.world,
+                    audio_handle,
+                    replay,
+                ))),
+                None if self.practice => Trans::Switch(Box::new(GameState::practice(
+                    data.world,
+                    audio_handle,
+                ))),
+                None => Trans::Switch(Box::new(GameState::new(data.world, audio_handle))),
+            }
         } else {
             Trans::None
         }
     }
 }
+
+pub struct ResultState {
+    result: PlayResult,
+    /// Whether the result goes into the score store.
+    save: bool,
+    /// Extra lines shown under the result.
+    notes: Vec<String>,
+    /// Local offset centring the hits of the play, applied with O.
+    suggested_offset: Option<f64>,
+    suggestion_text: Option<Entity>,
+}
+
+impl ResultState {
+    pub fn new(result: PlayResult) -> Self {
+        ResultState {
+            result,
+            save: true,
+            notes: vec![],
+            suggested_offset: None,
+            suggestion_text: None,
+        }
+    }
+
+    /// Results that are only shown, not saved. Used for replays and practice plays.
+    pub fn unsaved(result: PlayResult, notes: Vec<String>) -> Self {
+        ResultState {
+            notes,
+            save: false,
+            ..ResultState::new(result)
+        }
+    }
+
+    /// Offers to set the local offset of the beatmap to `offset`.
+    pub fn with_offset_suggestion(self, offset: Option<f64>) -> Self {
+        ResultState {
+            suggested_offset: offset,
+            ..self
+        }
+    }
+
+    fn apply_offset_suggestion(&mut self, world: &mut World) {
+        let offset = match self.suggested_offset.take() {
+            Some(offset) => offset,
+            None => return,
+        };
+        {
+            let mut settings = world.write_resource::<UserSettings>();
+            settings.set_local_offset(&self.result.beatmap_hash, offset);
+            settings.save();
+        }
+        world
+            .write_resource::<Toasts>()
+            .push(format!("Local offset saved: {:+.1} ms", offset * 1000.0));
+        if let Some(entity) = self.suggestion_text {
+            if let Some(text) = world.write_storage::<UiText>().get_mut(entity) {
+                text.text = format!("Local offset set to {:+.1} ms.", offset * 1000.0);
+            }
+        }
+    }
+}
+
+impl<'a, 'b> State<GameData<'a, 'b>> for ResultState {
+    fn on_start(&mut self, data: StateData<GameData<'a, 'b>>) {
+        // The play is saved first, so it isn't lost if the screen fails to show.
+        let new_best = self.save && {
+            let mut store = data.world.write_resource::<ScoreStore>();
+            let new_best = store
+                .personal_best(&self.result.beatmap_hash, &self.result.player)
+                .map(|best| self.result.score.score > best.score.score)
+                .unwrap_or(true);
+            store.add(self.result.clone());
+            store.save();
+            new_best
+        };
+
+        let font = match load_font(data.world) {
+            Some(font) => font,
+            None => {
+                error!("Failed to load font for the results screen. Skipping...");
+                return;
+            }
+        };
+
+        let score = &self.result.score;
+        let mut lines = vec![
+            format!("Score: {}", score.score),
+            format!("Accuracy: {:.2}%", score.accuracy() * 100.0),
+            format!(
+                "Perfect: {}  Good: {}  Miss: {}",
+                score.perfect, score.good, score.miss
+            ),
+            format!("Max combo: {}", score.max_combo),
+            format!("Performance: {:.2}", self.result.performance),
+        ];
+        if self.result.mods != Mods::default() {
+            lines.push(format!("Mods: {}", self.result.mods.names()));
+        }
+        if new_best {
+            lines.push(String::from("New personal best!"));
+        }
+        lines.extend(self.notes.iter().cloned());
+        let suggestion_line = self.suggested_offset.map(|offset| {
+            lines.push(format!(
+                "Suggested local offset: {:+.1} ms. Press O to apply it.",
+                offset * 1000.0
+            ));
+            lines.len() - 1
+        });
+        for (i, line) in lines.into_iter().enumerate() {
+            let entity = create_text(
+                data.world,
+                &font,
+                &format!("result_{}", i),
+                line,
+                Anchor::TopMiddle,
+                (0.0, -80.0 * (i as f32 + 1.0)),
+                1000.0,
+                30.0,
+                RemovalLayer::Results,
+            );
+            if suggestion_line == Some(i) {
+                self.suggestion_text = Some(entity);
+            }
+        }
+    }
+
+    fn on_stop(&mut self, data: StateData<GameData>) {
+        exec_removal(
+            &data.world.read_resource(),
+            &data.world.read_storage(),
+            RemovalLayer::Results,
+        );
+    }
+
+    fn update(&mut self, mut data: StateData<GameData<'a, 'b>>) -> Trans<GameData<'a, 'b>> {
+        data.data.update(&mut data.world);
+        Trans::None
+    }
+
+    fn handle_event(
+        &mut self,
+        data: StateData<GameData<'a, 'b>>,
+        event: Event,
+    ) -> Trans<GameData<'a, 'b>> {
+        if is_key_down(&event, VirtualKeyCode::Escape)
+            || is_key_down(&event, VirtualKeyCode::Return)
+        {
+            return Trans::Pop;
+        } else if is_close_requested(&event) {
+            return Trans::Quit;
+        }
+        if is_key_down(&event, VirtualKeyCode::O) {
+            self.apply_offset_suggestion(data.world);
+        }
+        Trans::None
+    }
+}
+
+/// Plays back a recorded play. The stored inputs go through the same judgement as live
+/// play, and the recomputed score is checked against the recorded one at the end.
+pub struct ReplayState {
+    dispatch: ParSeq<Arc<rayon::ThreadPool>, GameSystem>,
+    hud_dispatch: ParSeq<Arc<rayon::ThreadPool>, HudSystem>,
+    storyboard_dispatch: ParSeq<Arc<rayon::ThreadPool>, StoryboardSystem>,
+    playfield_dispatch: ParSeq<Arc<rayon::ThreadPool>, PlayfieldSystem>,
+    audio_handle: Handle<Source>,
+    replay: Option<Replay>,
+    /// Mods selected before the replay, restored when leaving.
+    previous_mods: Mods,
+    finish_time: Option<f64>,
+    /// Playback speed, on top of the rate of the replay's mods.
+    speed: f64,
+}
+
+/// Seconds skipped by a seek.
+const REPLAY_SEEK_STEP: f64 = 5.0;
+/// Bounds of the playback speed of replays, relative to the rate they were played at.
+const MIN_REPLAY_SPEED: f64 = 0.5;
+const MAX_REPLAY_SPEED: f64 = 2.0;
+
+impl ReplayState {
+    pub fn new(world: &mut World, audio_handle: Handle<Source>, replay: Replay) -> ReplayState {
+        ReplayState {
+            dispatch: ParSeq::new(
+                GameSystem { reader_id: None },
+                world.read_resource::<Arc<ThreadPool>>().clone(),
+            ),
+            hud_dispatch: ParSeq::new(
+                HudSystem,
+                world.read_resource::<Arc<ThreadPool>>().clone(),
+            ),
+            storyboard_dispatch: ParSeq::new(
+                StoryboardSystem,
+                world.read_resource::<Arc<ThreadPool>>().clone(),
+            ),
+            playfield_dispatch: ParSeq::new(
+                PlayfieldSystem,
+                world.read_resource::<Arc<ThreadPool>>().clone(),
+            ),
+            audio_handle,
+            replay: Some(replay),
+            previous_mods: Mods::default(),
+            finish_time: None,
+            speed: 1.0,
+        }
+    }
+
+    /// Moves the replay to `target` song time. The play is restarted and every input
+    /// before `target` is judged at once.
+    fn seek(&mut self, world: &mut World, target: f64) {
+        let target = target.max(0.0);
+        GameState::reset_play(world);
+        {
+            let beatmap = world.read_resource::<BeatMap>();
+            let mods = *world.read_resource::<Mods>();
+            let mut playback = world.write_resource::<ReplayPlayback>();
+            let mut hitqueue = world.write_resource::<HitObjectQueue>();
+            let mut hitoffsets = world.write_resource::<HitOffsets>();
+            let mut score = world.write_resource::<Score>();
+            let mut gauge = world.write_resource::<Gauge>();
+            let mut last_judgement = world.write_resource::<LastJudgement>();
+            let mut tracker = PlayTracker {
+                windows: beatmap.hit_windows(&mods),
+                mods,
+                hitqueue: &mut *hitqueue,
+                hitoffsets: &mut *hitoffsets,
+                score: &mut *score,
+                gauge: &mut *gauge,
+                last_judgement: &mut *last_judgement,
+            };
+
+            playback.next_input = 0;
+            for (time, key) in playback.take_inputs(target) {
+                tracker.drop_missed(time);
+                tracker.hit(time, key);
+            }
+            let cur_time = playback.replay.judge_time(target);
+            tracker.drop_missed(cur_time);
+        }
+        GameState::spawn_notes(world);
+        GameState::seek_song(world, target);
+        self.finish_time = None;
+    }
+
+    fn toggle_pause(&mut self, world: &mut World) {
+        let now = world.read_resource::<GameClock>().now();
+        let mut clock = world.write_resource::<SongClock>();
+        let paused = !clock.paused();
+        clock.set_paused(now, paused);
+        let song_player = world.read_resource::<SongPlayer>();
+        if paused {
+            song_player.pause();
+        } else {
+            song_player.resume();
+        }
+    }
+
+    /// Changes the playback speed by `change`. The song plays at the rate of the replay's
+    /// mods times the speed, so judgements stay the same.
+    fn change_speed(&mut self, world: &mut World, change: f64) {
+        self.speed = (self.speed + change)
+            .max(MIN_REPLAY_SPEED)
+            .min(MAX_REPLAY_SPEED);
+        let rate = world.read_resource::<Mods>().rate * self.speed;
+        let now = world.read_resource::<GameClock>().now();
+        let mut clock = world.write_resource::<SongClock>();
+        clock.set_rate(now, rate);
+        world
+            .write_resource::<Toasts>()
+            .push(format!("Replay speed: {}x", self.speed));
+        let mut song_player = world.write_resource::<SongPlayer>();
+        song_player.play_from(clock.time(now), rate);
+        if clock.paused() {
+            song_player.pause();
+        }
+    }
+}
+
+impl<'a, 'b> State<GameData<'a, 'b>> for ReplayState {
+    fn on_start(&mut self, data: StateData<GameData<'a, 'b>>) {
+        self.dispatch.setup(&mut data.world.res);
+        self.hud_dispatch.setup(&mut data.world.res);
+        self.storyboard_dispatch.setup(&mut data.world.res);
+        self.playfield_dispatch.setup(&mut data.world.res);
+
+        let replay = self.replay.take().expect("ReplayState started twice");
+        self.previous_mods = *data.world.read_resource::<Mods>();
+        data.world.add_resource(replay.mods);
+
+        GameState::start_play(data.world, &self.audio_handle);
+        data.world.add_resource(ReplayPlayback::new(replay));
+    }
+
+    fn on_stop(&mut self, data: StateData<GameData>) {
+        data.world.write_resource::<SongPlayer>().stop();
+        data.world.add_resource(ReplayPlayback::default());
+        data.world.add_resource(self.previous_mods);
+        exec_removal(
+            &data.world.read_resource(),
+            &data.world.read_storage(),
+            RemovalLayer::Gameplay,
+        );
+    }
+
+    fn update(&mut self, mut data: StateData<GameData<'a, 'b>>) -> Trans<GameData<'a, 'b>> {
+        data.data.update(&mut data.world);
+        self.dispatch.dispatch(&mut data.world.res);
+        self.hud_dispatch.dispatch(&mut data.world.res);
+        self.storyboard_dispatch.dispatch(&mut data.world.res);
+        self.playfield_dispatch.dispatch(&mut data.world.res);
+
+        let finished = data.world.read_resource::<HitObjectQueue>().queue.is_empty()
+            && data.world.read_resource::<ReplayPlayback>().finished();
+        if finished {
+            let now = data.world.read_resource::<GameClock>().now();
+            let finish_time = *self.finish_time.get_or_insert(now);
+            if now - finish_time > RESULTS_DELAY {
+                let result = GameState::play_result(&data.world);
+                let (recorded, imported) = {
+                    let playback = data.world.read_resource::<ReplayPlayback>();
+                    (playback.replay.score.clone(), playback.replay.imported)
+                };
+                let note = if imported && result.score.judgements_match(&recorded) {
+                    String::from("Replay verified: the judgements match the osu! replay.")
+                } else if !imported && result.score.matches(&recorded) {
+                    String::from("Replay verified: the score matches the recorded one.")
+                } else {
+                    warn!(
+                        "Replay score mismatch: recorded {} ({}/{}/{}), got {} ({}/{}/{})",
+                        recorded.score,
+                        recorded.perfect,
+                        recorded.good,
+                        recorded.miss,
+                        result.score.score,
+                        result.score.perfect,
+                        result.score.good,
+                        result.score.miss
+                    );
+                    if imported {
+                        format!(
+                            "Replay mismatch: the osu! replay had {}/{}/{}.",
+                            recorded.perfect, recorded.good, recorded.miss
+                        )
+                    } else {
+                        format!("Replay mismatch: recorded score was {}.", recorded.score)
+                    }
+                };
+                return Trans::Switch(Box::new(ResultState::unsaved(result, vec![note])));
+            }
+        }
+        Trans::None
+    }
+
+    fn handle_event(
+        &mut self,
+        data: StateData<GameData<'a, 'b>>,
+        event: Event,
+    ) -> Trans<GameData<'a, 'b>> {
+        if is_key_down(&event, VirtualKeyCode::Escape) {
+            return Trans::Pop;
+        } else if is_close_requested(&event) {
+            return Trans::Quit;
+        } else if is_key_down(&event, VirtualKeyCode::Space) {
+            self.toggle_pause(data.world);
+        } else if is_key_down(&event, VirtualKeyCode::Left) {
+            let target = GameState::song_time(&data.world) - REPLAY_SEEK_STEP;
+            self.seek(data.world, target);
+        } else if is_key_down(&event, VirtualKeyCode::Right) {
+            let target = GameState::song_time(&data.world) + REPLAY_SEEK_STEP;
+            self.seek(data.world, target);
+        } else if is_key_down(&event, VirtualKeyCode::Up) {
+            self.change_speed(data.world, 0.25);
+        } else if is_key_down(&event, VirtualKeyCode::Down) {
+            self.change_speed(data.world, -0.25);
+        }
+        Trans::None
+    }
+}
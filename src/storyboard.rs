@@ -0,0 +1,850 @@
+//! osu! storyboards, read from the .osb of the beatmap set and the [Events] of the
+//! difficulty.
+//!
+//! A storyboard is a list of sprites and animations, each animated by commands:
+//! `_K,easing,start,end,values...` where `K` is F (fade), M (move), MX, MY, S (scale),
+//! V (vector scale), R (rotate), C (colour) or P (parameter: H, V or A). Several value
+//! sets chain commands of the same length one after the other. Commands can be grouped
+//! in loops (`_L,start,count`) and triggers (`_T,name,start,end`), whose commands are
+//! timed from the start of each iteration or activation.
+//!
+//! Positions are in storyboard pixels: the 4:3 area is 640x480, and widescreen
+//! storyboards extend it by 107 pixels on each side. Times are in seconds.
+
+use amethyst::ecs::prelude::*;
+use amethyst::renderer::Material;
+
+use std::f64::consts::PI;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use utils::*;
+
+/// Size of the widescreen storyboard area, in storyboard pixels.
+pub const STORYBOARD_WIDTH: f64 = 854.0;
+pub const STORYBOARD_HEIGHT: f64 = 480.0;
+/// Width added on the left of the 4:3 area in widescreen.
+pub const STORYBOARD_WIDESCREEN_OFFSET: f64 = 107.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layer {
+    Background,
+    /// Only shown while failing.
+    Fail,
+    /// Only shown while passing.
+    Pass,
+    Foreground,
+}
+
+impl Layer {
+    fn parse(s: &str) -> Option<Layer> {
+        match s {
+            "Background" | "0" => Some(Layer::Background),
+            "Fail" | "1" => Some(Layer::Fail),
+            "Pass" | "2" => Some(Layer::Pass),
+            "Foreground" | "3" => Some(Layer::Foreground),
+            _ => None,
+        }
+    }
+
+    /// Whether the layer is shown, depending on whether the player is passing.
+    pub fn shown(&self, passing: bool) -> bool {
+        match *self {
+            Layer::Fail => !passing,
+            Layer::Pass => passing,
+            _ => true,
+        }
+    }
+}
+
+/// Point of the image placed at the sprite position, that it also scales and rotates
+/// around.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Origin {
+    TopLeft,
+    TopCentre,
+    TopRight,
+    CentreLeft,
+    Centre,
+    CentreRight,
+    BottomLeft,
+    BottomCentre,
+    BottomRight,
+}
+
+impl Origin {
+    fn parse(s: &str) -> Origin {
+        match s {
+            "TopLeft" | "0" => Origin::TopLeft,
+            "TopCentre" | "2" => Origin::TopCentre,
+            "TopRight" | "4" => Origin::TopRight,
+            "CentreLeft" | "5" => Origin::CentreLeft,
+            "CentreRight" | "6" => Origin::CentreRight,
+            "BottomLeft" | "7" => Origin::BottomLeft,
+            "BottomCentre" | "8" => Origin::BottomCentre,
+            "BottomRight" | "9" => Origin::BottomRight,
+            _ => Origin::Centre,
+        }
+    }
+
+    /// Position of the origin in the image, from 0 to 1 left to right and top to bottom.
+    pub fn offset(&self) -> [f64; 2] {
+        match *self {
+            Origin::TopLeft => [0.0, 0.0],
+            Origin::TopCentre => [0.5, 0.0],
+            Origin::TopRight => [1.0, 0.0],
+            Origin::CentreLeft => [0.0, 0.5],
+            Origin::Centre => [0.5, 0.5],
+            Origin::CentreRight => [1.0, 0.5],
+            Origin::BottomLeft => [0.0, 1.0],
+            Origin::BottomCentre => [0.5, 1.0],
+            Origin::BottomRight => [1.0, 1.0],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoopType {
+    Forever,
+    /// Stays on the last frame once played.
+    Once,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Animation {
+    pub frame_count: usize,
+    /// Seconds each frame is shown for.
+    pub frame_delay: f64,
+    pub loop_type: LoopType,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CommandKind {
+    Fade,
+    Move,
+    MoveX,
+    MoveY,
+    Scale,
+    VectorScale,
+    Rotate,
+    Colour,
+    /// Flips or additive blending, in effect between the start and end of the command.
+    Parameter(Parameter),
+}
+
+impl CommandKind {
+    fn parse(s: &str) -> Option<CommandKind> {
+        match s {
+            "F" => Some(CommandKind::Fade),
+            "M" => Some(CommandKind::Move),
+            "MX" => Some(CommandKind::MoveX),
+            "MY" => Some(CommandKind::MoveY),
+            "S" => Some(CommandKind::Scale),
+            "V" => Some(CommandKind::VectorScale),
+            "R" => Some(CommandKind::Rotate),
+            "C" => Some(CommandKind::Colour),
+            _ => None,
+        }
+    }
+
+    /// Number of values set by a command.
+    fn value_count(&self) -> usize {
+        match *self {
+            CommandKind::Move | CommandKind::VectorScale => 2,
+            CommandKind::Colour => 3,
+            CommandKind::Parameter(_) => 0,
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Parameter {
+    FlipHorizontal,
+    FlipVertical,
+    Additive,
+}
+
+#[derive(Clone, Debug)]
+pub struct Command {
+    pub kind: CommandKind,
+    pub easing: u8,
+    pub start: f64,
+    pub end: f64,
+    pub from: Vec<f64>,
+    pub to: Vec<f64>,
+}
+
+impl Command {
+    /// Value `i` of the command at `time`, clamped to its start and end.
+    fn value_at(&self, i: usize, time: f64) -> f64 {
+        if time >= self.end {
+            return self.to[i];
+        }
+        if time <= self.start {
+            return self.from[i];
+        }
+        let t = ease(self.easing, (time - self.start) / (self.end - self.start));
+        self.from[i] + (self.to[i] - self.from[i]) * t
+    }
+
+    fn shifted(&self, by: f64) -> Command {
+        Command {
+            start: self.start + by,
+            end: self.end + by,
+            ..self.clone()
+        }
+    }
+}
+
+/// Commands run each time the trigger activates between `start` and `end`, timed from
+/// the activation.
+#[derive(Clone, Debug)]
+pub struct Trigger {
+    /// Event activating the trigger: "Passing", "Failing" or "HitSound...".
+    pub name: String,
+    pub start: f64,
+    pub end: f64,
+    pub commands: Vec<Command>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Sprite {
+    pub layer: Layer,
+    pub origin: Origin,
+    /// Path of the image. Animation frames add the frame number before the extension.
+    pub path: String,
+    /// Position before any move command.
+    pub position: [f64; 2],
+    pub animation: Option<Animation>,
+    /// Commands sorted by start time.
+    pub commands: Vec<Command>,
+    pub triggers: Vec<Trigger>,
+}
+
+/// What a sprite looks like at some point in time.
+#[derive(Clone, Copy, Debug)]
+pub struct SpriteState {
+    pub position: [f64; 2],
+    pub scale: [f64; 2],
+    /// Clockwise, in radians.
+    pub rotation: f64,
+    /// RGB, from 0 to 1.
+    pub colour: [f64; 3],
+    pub opacity: f64,
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+    pub additive: bool,
+    /// Animation frame to show.
+    pub frame: usize,
+}
+
+impl Sprite {
+    /// Path of each image used by the sprite.
+    pub fn frame_paths(&self) -> Vec<String> {
+        match self.animation {
+            Some(animation) => {
+                let (stem, extension) = match self.path.rfind('.') {
+                    Some(i) => (&self.path[..i], &self.path[i..]),
+                    None => (&self.path[..], ""),
+                };
+                (0..animation.frame_count)
+                    .map(|i| format!("{}{}{}", stem, i, extension))
+                    .collect()
+            }
+            None => vec![self.path.clone()],
+        }
+    }
+
+    /// State of the sprite at `time`, or `None` if it isn't visible then. `activations`
+    /// holds when each trigger last activated.
+    pub fn state_at(&self, time: f64, activations: &[Option<f64>]) -> Option<SpriteState> {
+        if activations.iter().all(|a| a.is_none()) {
+            return self.state_from(&self.commands, time);
+        }
+        let mut commands = self.commands.clone();
+        for (trigger, activation) in self.triggers.iter().zip(activations) {
+            if let Some(activation) = *activation {
+                commands.extend(trigger.commands.iter().map(|c| c.shifted(activation)));
+            }
+        }
+        commands.sort_by(|a, b| a.start.partial_cmp(&b.start).unwrap());
+        self.state_from(&commands, time)
+    }
+
+    fn state_from(&self, commands: &[Command], time: f64) -> Option<SpriteState> {
+        let start = commands.iter().map(|c| c.start).fold(None, min_option)?;
+        let end = commands.iter().map(|c| c.end).fold(None, max_option)?;
+        if time < start || time > end {
+            return None;
+        }
+
+        let value = |default: f64, select: &Fn(&Command) -> Option<usize>| {
+            property(commands, time, select).unwrap_or(default)
+        };
+        let x = value(self.position[0], &|c| match c.kind {
+            CommandKind::Move | CommandKind::MoveX => Some(0),
+            _ => None,
+        });
+        let y = value(self.position[1], &|c| match c.kind {
+            CommandKind::Move => Some(1),
+            CommandKind::MoveY => Some(0),
+            _ => None,
+        });
+        let scale_x = value(1.0, &|c| match c.kind {
+            CommandKind::Scale | CommandKind::VectorScale => Some(0),
+            _ => None,
+        });
+        let scale_y = value(1.0, &|c| match c.kind {
+            CommandKind::Scale => Some(0),
+            CommandKind::VectorScale => Some(1),
+            _ => None,
+        });
+        let mut colour = [1.0; 3];
+        for (i, c) in colour.iter_mut().enumerate() {
+            *c = value(255.0, &|c| match c.kind {
+                CommandKind::Colour => Some(i),
+                _ => None,
+            }) / 255.0;
+        }
+        let parameter = |p: Parameter| {
+            commands.iter().any(|c| {
+                c.kind == CommandKind::Parameter(p)
+                    && (c.start == c.end || (c.start <= time && time <= c.end))
+            })
+        };
+
+        Some(SpriteState {
+            position: [x, y],
+            scale: [scale_x, scale_y],
+            rotation: value(0.0, &|c| match c.kind {
+                CommandKind::Rotate => Some(0),
+                _ => None,
+            }),
+            colour,
+            opacity: value(1.0, &|c| match c.kind {
+                CommandKind::Fade => Some(0),
+                _ => None,
+            }),
+            flip_horizontal: parameter(Parameter::FlipHorizontal),
+            flip_vertical: parameter(Parameter::FlipVertical),
+            additive: parameter(Parameter::Additive),
+            frame: self.frame_at(time - start),
+        })
+    }
+
+    /// Animation frame shown `elapsed` seconds after the sprite appears.
+    fn frame_at(&self, elapsed: f64) -> usize {
+        match self.animation {
+            Some(animation) if animation.frame_count > 0 && animation.frame_delay > 0.0 => {
+                let frame = (elapsed / animation.frame_delay).max(0.0) as usize;
+                match animation.loop_type {
+                    LoopType::Forever => frame % animation.frame_count,
+                    LoopType::Once => frame.min(animation.frame_count - 1),
+                }
+            }
+            _ => 0,
+        }
+    }
+}
+
+fn min_option(acc: Option<f64>, v: f64) -> Option<f64> {
+    Some(acc.map_or(v, |a| a.min(v)))
+}
+
+fn max_option(acc: Option<f64>, v: f64) -> Option<f64> {
+    Some(acc.map_or(v, |a| a.max(v)))
+}
+
+/// Value of a property at `time`, from the commands `select` gives a value index for.
+/// The last command started wins. Before the first one, its start value is used.
+fn property(
+    commands: &[Command],
+    time: f64,
+    select: &Fn(&Command) -> Option<usize>,
+) -> Option<f64> {
+    let mut value = None;
+    for c in commands {
+        if let Some(i) = select(c) {
+            if time < c.start {
+                if value.is_none() {
+                    value = Some(c.from[i]);
+                }
+                break;
+            }
+            value = Some(c.value_at(i, time));
+        }
+    }
+    value
+}
+
+/// Applies easing `easing` to the progress `t` of a command, between 0 and 1.
+pub fn ease(easing: u8, t: f64) -> f64 {
+    // Easings come in groups of In, Out and InOut of the same curve.
+    let out = |f: &Fn(f64) -> f64| 1.0 - f(1.0 - t);
+    let in_out = |f: &Fn(f64) -> f64| {
+        if t < 0.5 {
+            f(t * 2.0) / 2.0
+        } else {
+            1.0 - f((1.0 - t) * 2.0) / 2.0
+        }
+    };
+    let quad = |t: f64| t * t;
+    let cubic = |t: f64| t * t * t;
+    let quart = |t: f64| t * t * t * t;
+    let quint = |t: f64| t * t * t * t * t;
+    let sine = |t: f64| 1.0 - (t * PI / 2.0).cos();
+    let expo = |t: f64| if t <= 0.0 { 0.0 } else { 2f64.powf(10.0 * (t - 1.0)) };
+    let circ = |t: f64| 1.0 - (1.0 - t * t).max(0.0).sqrt();
+    let elastic = |t: f64| {
+        if t <= 0.0 || t >= 1.0 {
+            t
+        } else {
+            -(2f64.powf(10.0 * (t - 1.0))) * ((t - 1.075) * 2.0 * PI / 0.3).sin()
+        }
+    };
+    let back = |t: f64| t * t * ((1.70158 + 1.0) * t - 1.70158);
+    let bounce_out = |t: f64| {
+        if t < 1.0 / 2.75 {
+            7.5625 * t * t
+        } else if t < 2.0 / 2.75 {
+            let t = t - 1.5 / 2.75;
+            7.5625 * t * t + 0.75
+        } else if t < 2.5 / 2.75 {
+            let t = t - 2.25 / 2.75;
+            7.5625 * t * t + 0.9375
+        } else {
+            let t = t - 2.625 / 2.75;
+            7.5625 * t * t + 0.984375
+        }
+    };
+    let bounce = |t: f64| 1.0 - bounce_out(1.0 - t);
+    let elastic_out = |period: f64| {
+        if t <= 0.0 || t >= 1.0 {
+            t
+        } else {
+            2f64.powf(-10.0 * t) * ((period * t - 0.075) * 2.0 * PI / 0.3).sin() + 1.0
+        }
+    };
+
+    match easing {
+        1 | 4 => out(&quad),
+        2 | 3 => quad(t),
+        5 => in_out(&quad),
+        6 => cubic(t),
+        7 => out(&cubic),
+        8 => in_out(&cubic),
+        9 => quart(t),
+        10 => out(&quart),
+        11 => in_out(&quart),
+        12 => quint(t),
+        13 => out(&quint),
+        14 => in_out(&quint),
+        15 => sine(t),
+        16 => out(&sine),
+        17 => in_out(&sine),
+        18 => expo(t),
+        19 => out(&expo),
+        20 => in_out(&expo),
+        21 => circ(t),
+        22 => out(&circ),
+        23 => in_out(&circ),
+        24 => elastic(t),
+        25 => elastic_out(1.0),
+        26 => elastic_out(0.5),
+        27 => elastic_out(0.25),
+        28 => in_out(&elastic),
+        29 => back(t),
+        30 => out(&back),
+        31 => in_out(&back),
+        32 => bounce(t),
+        33 => bounce_out(t),
+        34 => in_out(&bounce),
+        _ => t,
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Storyboard {
+    pub sprites: Vec<Sprite>,
+}
+
+/// Commands grouped under a loop or trigger of the sprite being read.
+enum Group {
+    Loop {
+        start: f64,
+        count: u32,
+        commands: Vec<Command>,
+    },
+    Trigger(Trigger),
+}
+
+impl Storyboard {
+    /// Reads the storyboard of the difficulty at `beatmap_path`: the .osb files of its
+    /// folder, then its own [Events].
+    pub fn load(beatmap_path: &str) -> Storyboard {
+        let mut storyboard = Storyboard::default();
+        let folder = match Path::new(beatmap_path).parent() {
+            Some(folder) => folder.to_path_buf(),
+            None => return storyboard,
+        };
+        let mut files = match fs::read_dir(&folder) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| p.extension().map_or(false, |e| e == "osb"))
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                error!("Failed to list storyboards in {:?}: {}", folder, e);
+                vec![]
+            }
+        };
+        files.push(Path::new(beatmap_path).to_path_buf());
+
+        let folder = folder.to_string_lossy().into_owned();
+        for file in files {
+            let mut content = String::new();
+            match File::open(&file).and_then(|mut f| f.read_to_string(&mut content)) {
+                Ok(_) => storyboard.parse(&content, &folder),
+                Err(e) => error!("Failed to read storyboard {:?}: {}", file, e),
+            }
+        }
+        storyboard
+    }
+
+    /// Adds the sprites of the [Events] section of `content`. Image paths are relative
+    /// to `folder`.
+    pub fn parse(&mut self, content: &str, folder: &str) {
+        let mut section = "";
+        let mut variables: Vec<(String, String)> = vec![];
+        let mut sprite: Option<Sprite> = None;
+        let mut group: Option<Group> = None;
+
+        for line in content.lines() {
+            let line = line.trim_right();
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+            if line.starts_with('[') {
+                section = line;
+                continue;
+            }
+            if section == "[Variables]" {
+                let mut split = line.splitn(2, '=');
+                if let (Some(name), Some(value)) = (split.next(), split.next()) {
+                    variables.push((name.to_string(), value.to_string()));
+                    // Longest first, so `$ab` isn't replaced by the value of `$a`.
+                    variables.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+                }
+                continue;
+            }
+            if section != "[Events]" {
+                continue;
+            }
+
+            let mut line = line.to_string();
+            if line.contains('$') {
+                for &(ref name, ref value) in &variables {
+                    line = line.replace(name.as_str(), value);
+                }
+            }
+            let depth = line.chars().take_while(|c| *c == ' ' || *c == '_').count();
+            let split = line[depth..].split(',').collect::<Vec<_>>();
+
+            if depth < 2 {
+                if let Some(group) = group.take() {
+                    if let Some(ref mut sprite) = sprite {
+                        close_group(sprite, group);
+                    }
+                }
+            }
+            if depth == 0 {
+                if let Some(sprite) = sprite.take() {
+                    self.add(sprite);
+                }
+                sprite = parse_sprite(&split, folder);
+                continue;
+            }
+            let sprite = match sprite {
+                Some(ref mut sprite) => sprite,
+                None => continue,
+            };
+
+            match (depth, split[0]) {
+                (1, "L") if split.len() >= 3 => {
+                    group = Some(Group::Loop {
+                        start: parse_time(split[1]),
+                        count: split[2].trim().parse().unwrap_or(1),
+                        commands: vec![],
+                    });
+                }
+                (1, "T") if split.len() >= 2 => {
+                    let time = |i: usize| split.get(i).map(|t| parse_time(t));
+                    group = Some(Group::Trigger(Trigger {
+                        name: split[1].to_string(),
+                        start: time(2).unwrap_or(0.0),
+                        end: time(3).unwrap_or(::std::f64::MAX),
+                        commands: vec![],
+                    }));
+                }
+                (1, _) => sprite.commands.extend(parse_commands(&split)),
+                (_, _) => match group {
+                    Some(Group::Loop {
+                        ref mut commands, ..
+                    })
+                    | Some(Group::Trigger(Trigger {
+                        ref mut commands, ..
+                    })) => commands.extend(parse_commands(&split)),
+                    None => {}
+                },
+            }
+        }
+
+        if let Some(mut sprite) = sprite {
+            if let Some(group) = group {
+                close_group(&mut sprite, group);
+            }
+            self.add(sprite);
+        }
+    }
+
+    fn add(&mut self, mut sprite: Sprite) {
+        sprite
+            .commands
+            .sort_by(|a, b| a.start.partial_cmp(&b.start).unwrap());
+        for trigger in sprite.triggers.iter_mut() {
+            trigger
+                .commands
+                .sort_by(|a, b| a.start.partial_cmp(&b.start).unwrap());
+        }
+        if !sprite.commands.is_empty() || !sprite.triggers.is_empty() {
+            self.sprites.push(sprite);
+        }
+    }
+}
+
+/// Adds the commands of a finished loop or trigger to `sprite`.
+/// Loops are unrolled: each iteration lasts until the end of its last command.
+fn close_group(sprite: &mut Sprite, group: Group) {
+    match group {
+        Group::Loop {
+            start,
+            count,
+            commands,
+        } => {
+            let duration = commands.iter().map(|c| c.end).fold(0.0, f64::max);
+            for i in 0..count.max(1) {
+                let offset = start + duration * i as f64;
+                sprite
+                    .commands
+                    .extend(commands.iter().map(|c| c.shifted(offset)));
+            }
+        }
+        Group::Trigger(trigger) => sprite.triggers.push(trigger),
+    }
+}
+
+/// Reads a `Sprite,layer,origin,"file",x,y` or
+/// `Animation,layer,origin,"file",x,y,frameCount,frameDelay,loopType` line.
+fn parse_sprite(split: &[&str], folder: &str) -> Option<Sprite> {
+    let animation = match split[0] {
+        "Sprite" | "4" => false,
+        "Animation" | "6" => true,
+        _ => return None,
+    };
+    if split.len() < 6 || (animation && split.len() < 8) {
+        return None;
+    }
+    let layer = Layer::parse(split[1])?;
+    let file = split[3].trim_matches('"').replace('\\', "/");
+    let number = |s: &str| s.trim().parse::<f64>().unwrap_or(0.0);
+    Some(Sprite {
+        layer,
+        origin: Origin::parse(split[2]),
+        path: format!("{}/{}", folder, file),
+        position: [number(split[4]), number(split[5])],
+        animation: if animation {
+            Some(Animation {
+                frame_count: number(split[6]).max(0.0) as usize,
+                frame_delay: number(split[7]) / 1000.0,
+                loop_type: match split.get(8).map(|s| s.trim()) {
+                    Some("LoopOnce") | Some("1") => LoopType::Once,
+                    _ => LoopType::Forever,
+                },
+            })
+        } else {
+            None
+        },
+        commands: vec![],
+        triggers: vec![],
+    })
+}
+
+/// Reads a `K,easing,start,end,values...` line. More value sets than needed chain
+/// commands of the same length one after the other.
+fn parse_commands(split: &[&str]) -> Vec<Command> {
+    if split.len() < 5 {
+        return vec![];
+    }
+    let easing = split[1].trim().parse::<u8>().unwrap_or(0);
+    let start = parse_time(split[2]);
+    let end = if split[3].trim().is_empty() {
+        start
+    } else {
+        parse_time(split[3])
+    };
+
+    if split[0] == "P" {
+        let parameter = match split[4].trim() {
+            "H" => Parameter::FlipHorizontal,
+            "V" => Parameter::FlipVertical,
+            "A" => Parameter::Additive,
+            _ => return vec![],
+        };
+        return vec![Command {
+            kind: CommandKind::Parameter(parameter),
+            easing,
+            start,
+            end,
+            from: vec![],
+            to: vec![],
+        }];
+    }
+
+    let kind = match CommandKind::parse(split[0]) {
+        Some(kind) => kind,
+        None => return vec![],
+    };
+    let n = kind.value_count();
+    let values = split[4..]
+        .iter()
+        .map(|v| v.trim().parse::<f64>().unwrap_or(0.0))
+        .collect::<Vec<_>>();
+    if values.len() < n {
+        return vec![];
+    }
+    if values.len() < n * 2 {
+        return vec![Command {
+            kind,
+            easing,
+            start,
+            end,
+            from: values[..n].to_vec(),
+            to: values[..n].to_vec(),
+        }];
+    }
+    let duration = end - start;
+    (0..values.len() / n - 1)
+        .map(|i| Command {
+            kind,
+            easing,
+            start: start + duration * i as f64,
+            end: end + duration * i as f64,
+            from: values[i * n..(i + 1) * n].to_vec(),
+            to: values[(i + 1) * n..(i + 2) * n].to_vec(),
+        })
+        .collect()
+}
+
+fn parse_time(s: &str) -> f64 {
+    osu_to_real_time(s.trim().parse::<f64>().unwrap_or(0.0) as i32)
+}
+
+/// Entity showing a sprite, with the material of each animation frame.
+pub struct SpriteEntity {
+    pub entity: Entity,
+    pub frames: Vec<Material>,
+    /// Frame currently shown.
+    pub frame: usize,
+}
+
+/// The storyboard of the current play and the state of its triggers.
+#[derive(Default)]
+pub struct StoryboardPlayback {
+    pub storyboard: Storyboard,
+    /// Entity of each sprite. `None` if its image couldn't be loaded.
+    pub entities: Vec<Option<SpriteEntity>>,
+    /// When each trigger of each sprite last activated.
+    activations: Vec<Vec<Option<f64>>>,
+    passing: Option<bool>,
+    /// Song time of the last hit that activated hit sound triggers.
+    last_hit: f64,
+}
+
+impl StoryboardPlayback {
+    pub fn new(storyboard: Storyboard, entities: Vec<Option<SpriteEntity>>) -> Self {
+        let activations = storyboard
+            .sprites
+            .iter()
+            .map(|s| vec![None; s.triggers.len()])
+            .collect();
+        StoryboardPlayback {
+            storyboard,
+            entities,
+            activations,
+            passing: None,
+            last_hit: ::std::f64::MIN,
+        }
+    }
+
+    /// Forgets the trigger activations and the passing state, for a play that starts over.
+    pub fn reset(&mut self) {
+        for activations in self.activations.iter_mut() {
+            for activation in activations.iter_mut() {
+                *activation = None;
+            }
+        }
+        self.passing = None;
+        self.last_hit = ::std::f64::MIN;
+    }
+
+    /// Activates triggers on changes of the passing state and on hits. Activations after
+    /// `time` are forgotten, so seeking back replays them.
+    pub fn update_triggers(&mut self, time: f64, passing: bool, last_hit: Option<f64>) {
+        for activations in self.activations.iter_mut() {
+            for activation in activations.iter_mut() {
+                if activation.map_or(false, |a| a > time) {
+                    *activation = None;
+                }
+            }
+        }
+        if self.last_hit > time {
+            self.last_hit = ::std::f64::MIN;
+        }
+
+        if self.passing != Some(passing) {
+            if self.passing.is_some() {
+                self.activate(if passing { "Passing" } else { "Failing" }, time);
+            }
+            self.passing = Some(passing);
+        }
+        if let Some(hit) = last_hit {
+            if hit > self.last_hit && hit <= time {
+                self.activate("HitSound", hit);
+                self.last_hit = hit;
+            }
+        }
+    }
+
+    fn activate(&mut self, event: &str, time: f64) {
+        for (sprite, activations) in self.storyboard.sprites.iter().zip(&mut self.activations) {
+            for (trigger, activation) in sprite.triggers.iter().zip(activations.iter_mut()) {
+                if trigger.name.starts_with(event) && trigger.start <= time && time <= trigger.end
+                {
+                    *activation = Some(time);
+                }
+            }
+        }
+    }
+
+    /// State of sprite `i` at `time`, or `None` if it isn't shown.
+    pub fn state_at(&self, i: usize, time: f64) -> Option<SpriteState> {
+        let sprite = &self.storyboard.sprites[i];
+        if !sprite.layer.shown(self.passing.unwrap_or(true)) {
+            return None;
+        }
+        sprite.state_at(time, &self.activations[i])
+    }
+}
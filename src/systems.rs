@@ -1,23 +1,37 @@
 extern crate amethyst;
 
+use std::cmp::Ordering;
+use std::time::Instant;
+
 use amethyst::assets::AssetStorage;
 use amethyst::audio::output::Output;
 use amethyst::audio::Source;
-use amethyst::core::timing::Time;
+use amethyst::core::cgmath::{Quaternion, Rad, Rotation3};
 use amethyst::core::transform::Transform;
 use amethyst::ecs::prelude::*;
 use amethyst::input::InputEvent;
-use amethyst::input::InputHandler;
+use amethyst::renderer::{Hidden, Material, ScreenDimensions};
 use amethyst::shrev::{EventChannel, ReaderId};
-use amethyst::winit::VirtualKeyCode;
+use amethyst::ui::UiText;
+use amethyst::winit::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
 
 use components::*;
+use controllers::*;
+use keyboard::*;
+use hitsounds::*;
+use replay::*;
 use resources::*;
+use skin::*;
+use storyboard::*;
 use utils::*;
 
+/// Screen distance a note travels per second, before the mods' scroll speed.
+const NOTE_SPEED: f64 = 0.5;
+/// Seconds the local offset changes by on each press of = or -.
+const OFFSET_STEP: f64 = 0.005;
+
 pub struct GameSystem {
     pub reader_id: Option<ReaderId<InputEvent<String>>>,
-    pub start_time: f64,
 }
 
 impl<'a> System<'a> for GameSystem {
@@ -26,15 +40,29 @@ impl<'a> System<'a> for GameSystem {
         WriteStorage<'a, HitObject>,
         WriteStorage<'a, Transform>,
         Read<'a, AssetStorage<Source>>,
-        Read<'a, Time>,
-        Read<'a, InputHandler<String, String>>,
+        Read<'a, GameClock>,
+        Read<'a, SongClock>,
         ReadExpect<'a, Sounds>,
+        Read<'a, HitSoundBank>,
         Option<Read<'a, Output>>,
         Read<'a, BeatMap>,
-        Write<'a, EventChannel<InputEvent<String>>>,
+        Read<'a, Mods>,
+        Read<'a, Skin>,
+        ReadExpect<'a, ScreenDimensions>,
+        ReadStorage<'a, DrumrollSprites>,
+        WriteStorage<'a, Hidden>,
+        // Grouped to stay within the 26 items a `SystemData` tuple can hold.
+        (Write<'a, EventChannel<InputEvent<String>>>, Read<'a, DrumInputs>),
         Write<'a, HitObjectQueue>,
         Write<'a, HitOffsets>,
+        Write<'a, Score>,
+        Write<'a, Gauge>,
+        Write<'a, LastJudgement>,
+        Write<'a, Replay>,
+        Write<'a, ReplayPlayback>,
         Write<'a, UserSettings>,
+        Write<'a, DrumFlash>,
+        Write<'a, Toasts>,
     );
     fn run(
         &mut self,
@@ -44,131 +72,645 @@ impl<'a> System<'a> for GameSystem {
             mut transforms,
             audio,
             time,
-            _input,
+            clock,
             sounds,
+            hitsound_bank,
             audio_output,
             beatmap,
-            mut events,
+            mods,
+            skin,
+            screen,
+            rolls,
+            mut hiddens,
+            (mut events, drum_inputs),
             mut hitqueue,
             mut hitoffsets,
+            mut score,
+            mut gauge,
+            mut last_judgement,
+            mut replay,
+            mut playback,
             mut user_settings,
+            mut drum_flash,
+            mut toasts,
         ): Self::SystemData,
     ) {
         if self.reader_id.is_none() {
             self.reader_id = Some(events.register_reader());
         }
 
-        if self.start_time <= 0.0 {
-            self.start_time = time.absolute_time_seconds();
-        }
-
-        let cur_time = time.absolute_time_seconds() - self.start_time;
-
-        let cur_time = cur_time + user_settings.offset;
+        // Song time without the user offset. This is what replays are stamped with.
+        let song_time = clock.time(time.now());
 
-        let (mut r1, mut r2, mut b1, mut b2, mut offset_up, mut offset_down) =
-            (false, false, false, false, false, false);
+        let (mut offset_up, mut offset_down) = (false, false);
         for ev in events.read(self.reader_id.as_mut().unwrap()) {
-            match ev {
-                &InputEvent::KeyPressed { key_code, .. } => match key_code {
-                    VirtualKeyCode::Z => r1 = true,
-                    VirtualKeyCode::X => r2 = true,
-                    VirtualKeyCode::N => b1 = true,
-                    VirtualKeyCode::M => b2 = true,
+            if let &InputEvent::KeyPressed { key_code, .. } = ev {
+                match key_code {
                     VirtualKeyCode::Equals => offset_up = true,
                     VirtualKeyCode::Subtract => offset_down = true,
                     _ => {}
-                },
-                &InputEvent::KeyReleased { .. } => {}
-                _ => {}
+                }
             }
         }
-
-        if offset_up {
-            user_settings.offset = user_settings.offset + 0.005;
-            println!("Offset: {} ms", user_settings.offset * 1000.0);
-        } else if offset_down {
-            user_settings.offset = user_settings.offset - 0.005;
-            println!("Offset: {} ms", user_settings.offset * 1000.0);
-        }
-
-        let mut dropped_offsets = Vec::new();
-        while let Some(head) = (&mut hitqueue.queue).pop_front() {
-            if head.time + beatmap.maxhitoffset < cur_time {
-                hitoffsets.offsets.push(None);
-                dropped_offsets.push(head.time);
+        // Drum hits to judge this frame, each with the time to judge it at.
+        let hits = if playback.active {
+            playback.take_inputs(song_time)
+        } else {
+            // =/- adjust the local offset of the beatmap being played.
+            let step = if offset_up {
+                OFFSET_STEP
+            } else if offset_down {
+                -OFFSET_STEP
             } else {
-                // put back into the list
-                hitqueue.queue.push_front(head);
-                break;
+                0.0
+            };
+            if step != 0.0 {
+                let local_offset = user_settings.local_offset(&beatmap.hash) + step;
+                user_settings.set_local_offset(&beatmap.hash, local_offset);
+                toasts.push(format!("Local offset: {:+.0} ms", local_offset * 1000.0));
+                replay.offset_changes.push(OffsetChange {
+                    time: song_time,
+                    offset: user_settings.offset_for(&beatmap.hash),
+                });
             }
+
+            // Each hit is stamped with the song time it happened at, not the frame's.
+            let mut hits = Vec::new();
+            for &(hit_time, key) in &drum_inputs.hits {
+                let hit_song_time = clock.time(hit_time);
+                replay.inputs.push(DrumInput { time: hit_song_time, key });
+                hits.push((replay.judge_time(hit_song_time), key));
+            }
+            hits
+        };
+
+        for &(_, key) in &hits {
+            drum_flash.hit(key, time.now());
         }
 
-        if r1 || r2 || b1 || b2 {
-            let (red, dual) = get_key_press_type(r1, r2, b1, b2);
+        let cur_time = judge_time(song_time, &replay, &playback);
 
-            if let Some(output) = audio_output {
-                if red {
-                    output.play_once(
-                        audio
-                            .get(&sounds.normal)
-                            .expect("Failed to find normal hitsound"),
-                        1.0,
-                    );
-                } else {
-                    output.play_once(
-                        audio
-                            .get(&sounds.clap)
-                            .expect("Failed to find clap hitsound"),
-                        1.0,
-                    );
-                }
-                if dual {
-                    output.play_once(
-                        audio
-                            .get(&sounds.finish)
-                            .expect("Failed to find finish hitsound"),
-                        1.0,
-                    );
-                }
+        let mut tracker = PlayTracker {
+            windows: beatmap.hit_windows(&mods),
+            mods: *mods,
+            hitqueue: &mut *hitqueue,
+            hitoffsets: &mut *hitoffsets,
+            score: &mut *score,
+            gauge: &mut *gauge,
+            last_judgement: &mut *last_judgement,
+        };
+        let mut dropped_offsets = Vec::new();
+        for (hit_time, key) in hits {
+            dropped_offsets.extend(tracker.drop_missed(hit_time));
+
+            if let Some(ref output) = audio_output {
+                // The hit plays the samples of the object it's judged against.
+                let samples = tracker
+                    .hitqueue
+                    .queue
+                    .front()
+                    .map(|o| o.samples.clone())
+                    .unwrap_or_default();
+                // The second side of a big note plays the samples of both.
+                let mut keys = vec![key];
+                keys.extend(tracker.big_hit_partner(hit_time, key));
+                play_hitsounds(output, &audio, &sounds, &hitsound_bank, &samples, &keys);
             } else {
                 error!("Failed to find audio `Output` from system.");
             }
 
-            //Get clickable object
-            if let Some(head) = (&mut hitqueue.queue).pop_front() {
-                if let (Some(offset), clicked) = check_hit(&beatmap, &head, cur_time, red, dual) {
-                    if clicked {
-                        hitoffsets.offsets.push(Some(offset));
-                    } else {
-                        hitoffsets.offsets.push(None);
-                    }
-                    dropped_offsets.push(head.time);
-                } else {
-                    //Put back into list if pressed but no hitobject was found
-                    hitqueue.queue.push_front(head);
-                }
-            }
+            dropped_offsets.extend(tracker.hit(hit_time, key));
         }
+        dropped_offsets.extend(tracker.drop_missed(cur_time));
 
         //println!("cur_time: {}", cur_time);
+        // Notes are sized in screen heights, so their width is scaled to keep them round.
+        let width_scale = screen.height() / screen.width();
+        let speed = NOTE_SPEED * mods.scroll_speed();
+        // Drumroll bodies to place once the heads are moved: left edge, length and end cap.
+        let mut roll_updates = Vec::new();
         'outer: for (entity, obj, tr) in (&*entities, &mut hitobjects, &mut transforms).join() {
             //Drop objects that weren't clicked fast enough
             for dropped_offset in dropped_offsets.iter() {
                 if *dropped_offset == obj.time {
                     //Drop visual object
                     //println!("Dropped entity");
-                    match entities.delete(entity) {
-                        Ok(_) => {}
-                        Err(err) => {
-                            error!("Failed to delete entity {:?} because {:?}", entity, err)
+                    let mut dropped = vec![entity];
+                    if let Some(roll) = rolls.get(entity) {
+                        dropped.push(roll.body);
+                        dropped.push(roll.end);
+                    }
+                    for entity in dropped {
+                        match entities.delete(entity) {
+                            Ok(_) => {}
+                            Err(err) => {
+                                error!("Failed to delete entity {:?} because {:?}", entity, err)
+                            }
                         }
                     }
-                    //continue 'outer;
+                    continue 'outer;
                 }
             }
             //Update object position
-            tr.translation[0] = (((obj.time - cur_time) * 0.50) + 0.3) as f32; //TEMPORARY. TO TEST HIT JUDGEMENT
+            let distance = ((obj.time - cur_time) * speed) as f32;
+            let x = skin.ini.layout.hit_position[0] + distance;
+            tr.translation[0] = x;
+            tr.scale[0] = width_scale;
+            let shown = mods.note_visible(distance);
+            set_shown(&mut hiddens, entity, shown);
+            if let (Some(roll), HitObjectKind::Drumroll { end }) = (rolls.get(entity), obj.kind) {
+                let length = ((end - obj.time) * speed) as f32;
+                roll_updates.push((roll.body, roll.end, x, length, shown));
+            }
+        }
+        for (body, end, x, length, shown) in roll_updates {
+            if let Some(tr) = transforms.get_mut(body) {
+                tr.translation[0] = x;
+                tr.scale[0] = length;
+            }
+            if let Some(tr) = transforms.get_mut(end) {
+                tr.translation[0] = x + length;
+                tr.scale[0] = width_scale;
+            }
+            set_shown(&mut hiddens, body, shown);
+            set_shown(&mut hiddens, end, shown);
+        }
+    }
+}
+
+/// Song time notes are judged at, with the offset of the live play or of the replay
+/// being watched.
+fn judge_time(song_time: f64, replay: &Replay, playback: &ReplayPlayback) -> f64 {
+    if playback.active {
+        playback.replay.judge_time(song_time)
+    } else {
+        replay.judge_time(song_time)
+    }
+}
+
+/// How much the kiai glow of the judgement circle grows on beats.
+const KIAI_PULSE: f32 = 0.15;
+
+/// Scrolls the barlines, lights the drum on hits, shows the kiai effects, and keeps the
+/// round playfield sprites round whatever the window size.
+pub struct PlayfieldSystem;
+
+impl<'a> System<'a> for PlayfieldSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, GameClock>,
+        Read<'a, SongClock>,
+        Read<'a, Replay>,
+        Read<'a, ReplayPlayback>,
+        Read<'a, BeatMap>,
+        Read<'a, Mods>,
+        Read<'a, Skin>,
+        Read<'a, DrumFlash>,
+        ReadExpect<'a, ScreenDimensions>,
+        ReadStorage<'a, Barline>,
+        ReadStorage<'a, DrumPart>,
+        ReadStorage<'a, KeepAspect>,
+        ReadStorage<'a, KiaiEffect>,
+        WriteStorage<'a, Transform>,
+        WriteStorage<'a, Hidden>,
+    );
+    fn run(
+        &mut self,
+        (
+            entities,
+            time,
+            clock,
+            replay,
+            playback,
+            beatmap,
+            mods,
+            skin,
+            drum_flash,
+            screen,
+            barlines,
+            drum_parts,
+            keep_aspects,
+            kiai_effects,
+            mut transforms,
+            mut hiddens,
+        ): Self::SystemData,
+    ) {
+        let width_scale = screen.height() / screen.width();
+        for (tr, keep_aspect) in (&mut transforms, &keep_aspects).join() {
+            tr.scale[0] = if keep_aspect.mirrored {
+                -width_scale
+            } else {
+                width_scale
+            };
+        }
+
+        let now = time.now();
+        for (entity, part) in (&*entities, &drum_parts).join() {
+            set_shown(&mut hiddens, entity, drum_flash.lit(part.key, now));
+        }
+
+        let cur_time = judge_time(clock.time(now), &replay, &playback);
+        let kiai = beatmap.kiai_at(cur_time);
+        let pulse = kiai.map_or(1.0, |k| {
+            1.0 + KIAI_PULSE * (1.0 - k.beat_progress(cur_time) as f32)
+        });
+        for (entity, effect, tr) in (&*entities, &kiai_effects, &mut transforms).join() {
+            if effect.pulse {
+                tr.scale = [width_scale * pulse, pulse, 1.0].into();
+            }
+            set_shown(&mut hiddens, entity, kiai.is_some());
+        }
+
+        let layout = &skin.ini.layout;
+        for (entity, barline, tr) in (&*entities, &barlines, &mut transforms).join() {
+            let distance = ((barline.time - cur_time) * NOTE_SPEED * mods.scroll_speed()) as f32;
+            let x = layout.hit_position[0] + distance;
+            tr.translation[0] = x;
+            set_shown(&mut hiddens, entity, x >= layout.lane_left && x <= 1.0);
+        }
+    }
+}
+
+fn play_hitsounds(
+    output: &Output,
+    audio: &AssetStorage<Source>,
+    sounds: &Sounds,
+    bank: &HitSoundBank,
+    samples: &HitSamples,
+    keys: &[DrumKey],
+) {
+    let (red, dual) = get_key_press_type(
+        keys.contains(&DrumKey::LeftDon),
+        keys.contains(&DrumKey::RightDon),
+        keys.contains(&DrumKey::LeftKat),
+        keys.contains(&DrumKey::RightKat),
+    );
+    let handles = match bank.custom(samples) {
+        Some(custom) => vec![custom],
+        None => hit_sounds(samples, red, dual)
+            .into_iter()
+            .map(|(sound, set)| bank.get(samples, sound, set).unwrap_or(sounds.get(sound)))
+            .collect(),
+    };
+    for handle in handles {
+        match audio.get(handle) {
+            Some(source) => output.play_once(source, samples.volume),
+            None => error!("Failed to find hitsound {:?}", handle),
+        }
+    }
+}
+
+/// Seconds a judgement stays on screen.
+const JUDGEMENT_DISPLAY_TIME: f64 = 0.3;
+
+/// Left edge and width of the gauge bar.
+pub const GAUGE_LEFT: f32 = 0.3;
+pub const GAUGE_WIDTH: f32 = 0.6;
+/// Width of the break progress bar, when the break starts.
+pub const BREAK_BAR_WIDTH: f32 = 0.4;
+/// Width of the hit error meter, covering the good window on both sides.
+pub const HIT_ERROR_WIDTH: f32 = 0.3;
+/// Seconds a tick of the hit error meter takes to shrink away.
+const HIT_ERROR_TICK_TIME: f64 = 3.0;
+/// Hits the average marker of the hit error meter follows.
+const HIT_ERROR_AVERAGE_HITS: usize = 10;
+
+/// Keeps the score, combo, gauge, latest judgement, break overlay and hit error meter on
+/// screen up to date.
+pub struct HudSystem;
+
+impl<'a> System<'a> for HudSystem {
+    type SystemData = (
+        Option<Write<'a, Hud>>,
+        Option<Read<'a, HitResultTextures>>,
+        Read<'a, GameClock>,
+        Read<'a, SongClock>,
+        Read<'a, BeatMap>,
+        Read<'a, Mods>,
+        Read<'a, Score>,
+        Read<'a, Gauge>,
+        Read<'a, LastJudgement>,
+        Read<'a, HitOffsets>,
+        WriteStorage<'a, UiText>,
+        WriteStorage<'a, Transform>,
+        WriteStorage<'a, Material>,
+        WriteStorage<'a, Hidden>,
+    );
+    fn run(
+        &mut self,
+        (
+            hud,
+            hit_results,
+            time,
+            clock,
+            beatmap,
+            mods,
+            score,
+            gauge,
+            last_judgement,
+            hit_offsets,
+            mut texts,
+            mut transforms,
+            mut materials,
+            mut hiddens,
+        ): Self::SystemData,
+    ) {
+        let mut hud = match hud {
+            Some(hud) => hud,
+            None => return,
+        };
+
+        if let Some(text) = texts.get_mut(hud.score) {
+            text.text = format!("{:08}", score.score);
+        }
+        if let Some(text) = texts.get_mut(hud.combo) {
+            text.text = format!("{}x", score.combo);
+        }
+
+        if let Some(tr) = transforms.get_mut(hud.gauge) {
+            let value = gauge.value as f32;
+            tr.scale[0] = value;
+            tr.translation[0] = GAUGE_LEFT + GAUGE_WIDTH * value / 2.0;
+        }
+
+        let song_time = clock.time(time.now());
+        let shown = match (last_judgement.result, hit_results) {
+            (Some(result), Some(textures)) => {
+                if (song_time - last_judgement.time).abs() < JUDGEMENT_DISPLAY_TIME {
+                    let material = match result {
+                        HitResult::Perfect => textures.perfect.clone(),
+                        HitResult::Good => textures.good.clone(),
+                        HitResult::Miss => textures.miss.clone(),
+                    };
+                    if let Err(e) = materials.insert(hud.judgement, material) {
+                        error!("Failed to update judgement material: {:?}", e);
+                    }
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => false,
+        };
+        set_shown(&mut hiddens, hud.judgement, shown);
+
+        let current_break = beatmap.break_at(song_time);
+        if let Some(text) = texts.get_mut(hud.break_text) {
+            text.text = match current_break {
+                Some(_) => String::from("Break"),
+                None => String::new(),
+            };
+        }
+        if let Some(b) = current_break {
+            if let Some(tr) = transforms.get_mut(hud.break_bar) {
+                tr.scale[0] = (1.0 - b.progress(song_time)) as f32;
+            }
+        }
+        set_shown(&mut hiddens, hud.break_bar, current_break.is_some());
+        for entity in &hud.letterbox {
+            set_shown(
+                &mut hiddens,
+                *entity,
+                current_break.is_some() && beatmap.letterbox_in_breaks,
+            );
+        }
+
+        if let Some(ref mut meter) = hud.hit_error {
+            let now = time.now();
+            let scale = meter.scale;
+            let x = |offset: f64| {
+                let half = HIT_ERROR_WIDTH / 2.0;
+                0.5 + (offset as f32 * scale).max(-half).min(half)
+            };
+            // The offsets start over when the play is restarted.
+            if hit_offsets.offsets.len() < meter.seen {
+                meter.seen = 0;
+                for tick_time in meter.tick_times.iter_mut() {
+                    *tick_time = ::std::f64::NEG_INFINITY;
+                }
+            }
+            for offset in hit_offsets.offsets[meter.seen..].iter().filter_map(|o| *o) {
+                if let Some(tr) = transforms.get_mut(meter.ticks[meter.next_tick]) {
+                    tr.translation[0] = x(offset);
+                }
+                meter.tick_times[meter.next_tick] = now;
+                meter.next_tick = (meter.next_tick + 1) % meter.ticks.len();
+            }
+            meter.seen = hit_offsets.offsets.len();
+
+            for (tick, tick_time) in meter.ticks.iter().zip(&meter.tick_times) {
+                let age = now - tick_time;
+                if let Some(tr) = transforms.get_mut(*tick) {
+                    tr.scale[1] = (1.0 - age / HIT_ERROR_TICK_TIME).max(0.0) as f32;
+                }
+                set_shown(&mut hiddens, *tick, age < HIT_ERROR_TICK_TIME);
+            }
+
+            let average = hit_offsets.recent_mean(HIT_ERROR_AVERAGE_HITS);
+            if let (Some(average), Some(tr)) = (average, transforms.get_mut(meter.average)) {
+                tr.translation[0] = x(average);
+            }
+            set_shown(&mut hiddens, meter.average, average.is_some());
+            if let Some(text) = texts.get_mut(meter.unstable_rate) {
+                text.text = match hit_offsets.unstable_rate(mods.rate) {
+                    Some(unstable_rate) => format!("{:.2} UR", unstable_rate),
+                    None => String::new(),
+                };
+            }
+        }
+    }
+}
+
+/// Moves the storyboard sprites to where they are at the current song time.
+///
+/// The flat renderer has no per-entity tint, so colours and partial fades can't be drawn:
+/// sprites are shown whenever they aren't fully faded out.
+pub struct StoryboardSystem;
+
+impl<'a> System<'a> for StoryboardSystem {
+    type SystemData = (
+        Option<Write<'a, StoryboardPlayback>>,
+        Read<'a, GameClock>,
+        Read<'a, SongClock>,
+        Read<'a, Gauge>,
+        Read<'a, LastJudgement>,
+        WriteStorage<'a, Transform>,
+        WriteStorage<'a, Material>,
+        WriteStorage<'a, Hidden>,
+    );
+    fn run(
+        &mut self,
+        (
+            playback,
+            time,
+            clock,
+            gauge,
+            last_judgement,
+            mut transforms,
+            mut materials,
+            mut hiddens,
+        ): Self::SystemData,
+    ) {
+        let mut playback = match playback {
+            Some(playback) => playback,
+            None => return,
+        };
+        let song_time = clock.time(time.now());
+        let last_hit = match last_judgement.result {
+            Some(HitResult::Miss) | None => None,
+            Some(_) => Some(last_judgement.time),
+        };
+        playback.update_triggers(song_time, gauge.cleared(), last_hit);
+
+        for i in 0..playback.entities.len() {
+            let state = playback.state_at(i, song_time);
+            let sprite = match playback.entities[i] {
+                Some(ref mut sprite) => sprite,
+                None => continue,
+            };
+            let state = match state {
+                Some(ref state) if state.opacity > 0.0 => state,
+                _ => {
+                    set_shown(&mut hiddens, sprite.entity, false);
+                    continue;
+                }
+            };
+
+            if let Some(tr) = transforms.get_mut(sprite.entity) {
+                tr.translation[0] = state.position[0] as f32;
+                tr.translation[1] = state.position[1] as f32;
+                let flip = |flipped: bool| if flipped { -1.0 } else { 1.0 };
+                tr.scale[0] = (state.scale[0] * flip(state.flip_horizontal)) as f32;
+                tr.scale[1] = (state.scale[1] * flip(state.flip_vertical)) as f32;
+                tr.rotation = Quaternion::from_angle_z(Rad(state.rotation as f32));
+            }
+            let frame = state.frame.min(sprite.frames.len() - 1);
+            if frame != sprite.frame {
+                if let Err(e) = materials.insert(sprite.entity, sprite.frames[frame].clone()) {
+                    error!("Failed to update storyboard frame: {:?}", e);
+                }
+                sprite.frame = frame;
+            }
+            set_shown(&mut hiddens, sprite.entity, true);
+        }
+    }
+}
+
+fn set_shown(hiddens: &mut WriteStorage<Hidden>, entity: Entity, shown: bool) {
+    if shown {
+        hiddens.remove(entity);
+    } else if !hiddens.contains(entity) {
+        if let Err(e) = hiddens.insert(entity, Hidden) {
+            error!("Failed to hide entity {:?}: {:?}", entity, e);
+        }
+    }
+}
+
+/// Moves the `GameClock` to the current frame, before any other system reads it.
+pub struct GameClockSystem;
+
+impl<'a> System<'a> for GameClockSystem {
+    type SystemData = Write<'a, GameClock>;
+    fn run(&mut self, mut clock: Self::SystemData) {
+        clock.tick();
+    }
+}
+
+/// Shows the `Toasts` in their text entities, in every state, fading them out before they
+/// expire.
+pub struct ToastSystem;
+
+impl<'a> System<'a> for ToastSystem {
+    type SystemData = (Read<'a, GameClock>, Write<'a, Toasts>, WriteStorage<'a, UiText>);
+    fn run(&mut self, (time, mut toasts, mut texts): Self::SystemData) {
+        let entities = toasts.entities.clone();
+        let shown = toasts.update(time.now());
+        for (i, entity) in entities.into_iter().enumerate() {
+            if let Some(text) = texts.get_mut(entity) {
+                match shown.get(i) {
+                    Some(&(message, alpha)) => {
+                        text.text = message.to_string();
+                        text.color[3] = alpha;
+                    }
+                    None => text.text = String::new(),
+                }
+            }
+        }
+    }
+}
+
+/// Stamps the key presses with the time the window received them, into `KeyStamps`. It is
+/// a thread local system added after the renderer, so it runs right after the window
+/// events are polled.
+#[derive(Default)]
+pub struct KeyStampSystem {
+    reader_id: Option<ReaderId<Event>>,
+}
+
+impl<'a> System<'a> for KeyStampSystem {
+    type SystemData = (
+        Read<'a, GameClock>,
+        Write<'a, EventChannel<Event>>,
+        Write<'a, KeyStamps>,
+    );
+    fn run(&mut self, (clock, mut events, mut stamps): Self::SystemData) {
+        if self.reader_id.is_none() {
+            self.reader_id = Some(events.register_reader());
+        }
+        let now = clock.time_of(Instant::now());
+        for event in events.read(self.reader_id.as_mut().unwrap()) {
+            let event = match *event {
+                Event::WindowEvent { ref event, .. } => event,
+                _ => continue,
+            };
+            match *event {
+                WindowEvent::KeyboardInput {
+                    input: KeyboardInput { scancode, state, .. },
+                    ..
+                } => stamps.key_event(scancode, state == ElementState::Pressed, now),
+                WindowEvent::Focused(false) => stamps.release_all(),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Gathers the drum hits of the frame from the keyboard and the drum controllers into
+/// `DrumInputs`, each with the time it happened at. Key presses get the time of their
+/// stamp in `KeyStamps`, or the time of the frame if there's none.
+#[derive(Default)]
+pub struct DrumInputSystem {
+    reader_id: Option<ReaderId<InputEvent<String>>>,
+}
+
+impl<'a> System<'a> for DrumInputSystem {
+    type SystemData = (
+        Read<'a, GameClock>,
+        Read<'a, DrumControllers>,
+        Write<'a, KeyStamps>,
+        Write<'a, EventChannel<InputEvent<String>>>,
+        Write<'a, DrumInputs>,
+    );
+    fn run(
+        &mut self,
+        (time, controllers, mut stamps, mut events, mut inputs): Self::SystemData,
+    ) {
+        if self.reader_id.is_none() {
+            self.reader_id = Some(events.register_reader());
+        }
+        let now = time.now();
+        stamps.drop_old(now);
+        inputs.hits.clear();
+        for ev in events.read(self.reader_id.as_mut().unwrap()) {
+            if let &InputEvent::KeyPressed { key_code, scancode } = ev {
+                if let Some(key) = DrumKey::from_key_code(key_code) {
+                    inputs.hits.push((stamps.take(scancode, now), key));
+                }
+            }
         }
+        inputs.hits.extend(controllers.poll(&time));
+        inputs
+            .hits
+            .sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
     }
 }
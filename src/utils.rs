@@ -4,26 +4,306 @@ extern crate itertools;
 //use self::itertools::Itertools;
 
 use amethyst::assets::{AssetStorage, Handle, Loader,SimpleFormat};
+use amethyst::ecs::prelude::*;
+use amethyst::ui::{Anchor, FontHandle, TtfFormat, UiText, UiTransform};
 use amethyst::renderer::{
-    Event, KeyboardInput, Material, MaterialDefaults, Mesh, PngFormat, PosTex, Texture,
-    TextureMetadata, WindowEvent,
+    Event, JpgFormat, KeyboardInput, Material, MaterialDefaults, Mesh, MeshHandle, PngFormat,
+    PosTex, Texture, TextureData, TextureMetadata, WindowEvent,
 };
 use amethyst::winit::VirtualKeyCode;
 use amethyst::Result;
 use amethyst_extra::*;
 
+use imagefmt;
+use imagefmt::ColFmt;
+use md5;
+
 use std::fs;
 use std::fs::File;
 use std::io::Read;
 use std::ops::{Add, Sub};
 
 use components::*;
+use difficulty::*;
+use replay::*;
 use resources::*;
 
 #[derive(PartialEq)]
 pub enum RemovalLayer {
     SongSelect,
     Gameplay,
+    Results,
+    Calibration,
+}
+
+pub fn load_font(world: &World) -> Option<FontHandle> {
+    world.read_resource::<AssetLoader>().load(
+        "fonts/Arial.ttf",
+        TtfFormat,
+        (),
+        &mut world.write_resource(),
+        &mut world.write_resource(),
+        &world.read_resource(),
+    )
+}
+
+/// Loads a PNG or JPEG image as a material.
+pub fn material_from_image(
+    path: &str,
+    loader: &Loader,
+    storage: &AssetStorage<Texture>,
+    material_defaults: &MaterialDefaults,
+) -> Material {
+    let texture = if path.to_lowercase().ends_with(".png") {
+        loader.load(path, PngFormat, TextureMetadata::srgb(), (), storage)
+    } else {
+        loader.load(path, JpgFormat, TextureMetadata::srgb(), (), storage)
+    };
+    Material {
+        albedo: texture,
+        ..material_defaults.0.clone()
+    }
+}
+
+/// Creates a `width` by `height` rectangle placed so that `origin` is at (0, 0). The origin
+/// goes from 0 to 1, left to right and top to bottom.
+pub fn gen_sprite_mesh(
+    width: f32,
+    height: f32,
+    origin: [f32; 2],
+    loader: &Loader,
+    storage: &AssetStorage<Mesh>,
+) -> MeshHandle {
+    let left = -origin[0] * width;
+    let top = -origin[1] * height;
+    let (right, bottom) = (left + width, top + height);
+    let vertices = vec![
+        PosTex { position: [left, top, 0.0], tex_coord: [0.0, 0.0] },
+        PosTex { position: [right, top, 0.0], tex_coord: [1.0, 0.0] },
+        PosTex { position: [right, bottom, 0.0], tex_coord: [1.0, 1.0] },
+        PosTex { position: [right, bottom, 0.0], tex_coord: [1.0, 1.0] },
+        PosTex { position: [left, bottom, 0.0], tex_coord: [0.0, 1.0] },
+        PosTex { position: [left, top, 0.0], tex_coord: [0.0, 0.0] },
+    ];
+    loader.load_from_data(vertices.into(), (), storage)
+}
+
+/// Loads an image as a material, with its colours multiplied by `colour` (RGBA). If given,
+/// `overlay` is drawn untinted on top, like osu! does with hit circle overlays.
+pub fn tinted_material_from_image(
+    path: &str,
+    overlay: Option<&str>,
+    colour: [f32; 4],
+    loader: &Loader,
+    storage: &AssetStorage<Texture>,
+    material_defaults: &MaterialDefaults,
+) -> Option<Material> {
+    let image = match imagefmt::read(path, ColFmt::RGBA) {
+        Ok(image) => image,
+        Err(e) => {
+            error!("Failed to read image {}: {:?}", path, e);
+            return None;
+        }
+    };
+    let mut pixels = image
+        .buf
+        .chunks(4)
+        .flat_map(|p| {
+            vec![
+                p[0] as f32 * colour[0],
+                p[1] as f32 * colour[1],
+                p[2] as f32 * colour[2],
+                p[3] as f32 * colour[3],
+            ]
+        })
+        .collect::<Vec<f32>>();
+
+    if let Some(overlay_path) = overlay {
+        match imagefmt::read(overlay_path, ColFmt::RGBA) {
+            Ok(ref top) if top.w == image.w && top.h == image.h => {
+                // Alpha blending of the overlay over the tinted image.
+                for (p, o) in pixels.chunks_mut(4).zip(top.buf.chunks(4)) {
+                    let alpha = o[3] as f32 / 255.0;
+                    for c in 0..3 {
+                        p[c] = o[c] as f32 * alpha + p[c] * (1.0 - alpha);
+                    }
+                    p[3] = o[3] as f32 + p[3] * (1.0 - alpha);
+                }
+            }
+            Ok(_) => error!("Overlay {} doesn't have the size of {}", overlay_path, path),
+            Err(e) => error!("Failed to read image {}: {:?}", overlay_path, e),
+        }
+    }
+
+    let data = pixels.iter().map(|p| p.max(0.0).min(255.0) as u8).collect::<Vec<u8>>();
+    let texture = loader.load_from_data(
+        TextureData::U8(
+            data,
+            TextureMetadata::srgb().with_size(image.w as u16, image.h as u16),
+        ),
+        (),
+        storage,
+    );
+    Some(Material {
+        albedo: texture,
+        ..material_defaults.0.clone()
+    })
+}
+
+/// Downscaling applied before blurring, which also makes the blur wider.
+const BLUR_DOWNSCALE: usize = 4;
+/// Radius of each box blur pass, in downscaled pixels.
+const BLUR_RADIUS: usize = 6;
+
+/// Loads an image as a blurred material, multiplying its colours by `brightness`.
+pub fn blurred_material_from_image(
+    path: &str,
+    brightness: f32,
+    loader: &Loader,
+    storage: &AssetStorage<Texture>,
+    material_defaults: &MaterialDefaults,
+) -> Option<Material> {
+    let image = match imagefmt::read(path, ColFmt::RGBA) {
+        Ok(image) => image,
+        Err(e) => {
+            error!("Failed to read image {}: {:?}", path, e);
+            return None;
+        }
+    };
+    let (w, h) = (image.w / BLUR_DOWNSCALE, image.h / BLUR_DOWNSCALE);
+    if w == 0 || h == 0 {
+        return None;
+    }
+
+    // Downscale by averaging blocks of pixels.
+    let mut pixels = vec![0.0f32; w * h * 4];
+    for y in 0..h * BLUR_DOWNSCALE {
+        for x in 0..w * BLUR_DOWNSCALE {
+            for c in 0..4 {
+                pixels[((y / BLUR_DOWNSCALE) * w + x / BLUR_DOWNSCALE) * 4 + c] +=
+                    image.buf[(y * image.w + x) * 4 + c] as f32;
+            }
+        }
+    }
+    let block = (BLUR_DOWNSCALE * BLUR_DOWNSCALE) as f32;
+    for p in pixels.iter_mut() {
+        *p /= block;
+    }
+
+    // Three box blurs in each direction are close to a gaussian blur.
+    for _ in 0..3 {
+        pixels = box_blur(&pixels, w, h, 1, w);
+        pixels = box_blur(&pixels, h, w, w, 1);
+    }
+
+    let data = pixels
+        .chunks(4)
+        .flat_map(|p| {
+            vec![
+                (p[0] * brightness) as u8,
+                (p[1] * brightness) as u8,
+                (p[2] * brightness) as u8,
+                255,
+            ]
+        })
+        .collect::<Vec<u8>>();
+    let texture = loader.load_from_data(
+        TextureData::U8(data, TextureMetadata::srgb().with_size(w as u16, h as u16)),
+        (),
+        storage,
+    );
+    Some(Material {
+        albedo: texture,
+        ..material_defaults.0.clone()
+    })
+}
+
+/// Blurs RGBA `pixels` along one direction. Lines are `len` pixels long, `step` apart
+/// within a line and `line_step` apart between lines.
+fn box_blur(pixels: &[f32], len: usize, lines: usize, step: usize, line_step: usize) -> Vec<f32> {
+    let mut out = vec![0.0; pixels.len()];
+    let window = (BLUR_RADIUS * 2 + 1) as f32;
+    for line in 0..lines {
+        let at = |i: usize| (line * line_step + i * step) * 4;
+        for c in 0..4 {
+            // Running sum over the window, with the edges repeated.
+            let mut sum = 0.0;
+            for i in 0..BLUR_RADIUS * 2 + 1 {
+                let i = (i as isize - BLUR_RADIUS as isize).max(0).min(len as isize - 1);
+                sum += pixels[at(i as usize) + c];
+            }
+            for i in 0..len {
+                out[at(i) + c] = sum / window;
+                let leaving = (i as isize - BLUR_RADIUS as isize).max(0) as usize;
+                let entering = (i + BLUR_RADIUS + 1).min(len - 1);
+                sum += pixels[at(entering) + c] - pixels[at(leaving) + c];
+            }
+        }
+    }
+    out
+}
+
+/// Creates a line of white text.
+pub fn create_text(
+    world: &mut World,
+    font: &FontHandle,
+    id: &str,
+    text: String,
+    anchor: Anchor,
+    (x, y): (f32, f32),
+    width: f32,
+    font_size: f32,
+    layer: RemovalLayer,
+) -> Entity {
+    world
+        .create_entity()
+        .with(UiTransform::new(
+            id.to_string(),
+            anchor,
+            x,
+            y,
+            1.0,
+            width,
+            font_size * 1.5,
+            0,
+        ))
+        .with(UiText::new(
+            font.clone(),
+            text,
+            [1.0, 1.0, 1.0, 1.0],
+            font_size,
+        ))
+        .with(Removal::new(layer))
+        .build()
+}
+
+/// Creates the text lines `Toasts` are shown in, at the bottom right of the screen. They
+/// have no removal layer so they stay through every state.
+pub fn create_toasts(world: &mut World, font: &FontHandle) {
+    let entities = (0..TOAST_LINES)
+        .map(|i| {
+            world
+                .create_entity()
+                .with(UiTransform::new(
+                    format!("toast_{}", i),
+                    Anchor::BottomRight,
+                    -270.0,
+                    40.0 + 35.0 * i as f32,
+                    2.0,
+                    500.0,
+                    30.0,
+                    0,
+                ))
+                .with(UiText::new(
+                    font.clone(),
+                    String::new(),
+                    [1.0, 1.0, 0.6, 1.0],
+                    20.0,
+                ))
+                .build()
+        })
+        .collect::<Vec<Entity>>();
+    world.write_resource::<Toasts>().entities = entities;
 }
 
 pub fn get_key_press_type(z: bool, x: bool, two: bool, three: bool) -> (bool, bool) {
@@ -37,49 +317,239 @@ pub fn get_key_press_type(z: bool, x: bool, two: bool, three: bool) -> (bool, bo
 ///Found an object to hit, used wrong button (Some(offset),false)
 ///Found an object to hit, used right button  (Some(offset),true)
 pub fn check_hit(
-    beatmap: &BeatMap,
+    windows: &HitWindows,
     hit: &HitObject,
     time: f64,
     redpressed: bool,
     dual: bool,
 ) -> (Option<f64>, bool) {
     //for hit in &beatmap.objects {
-    if value_near(time, hit.time, beatmap.maxhitoffset) {
+    if value_near(time, hit.time, windows.miss) {
         if (hit.red && redpressed) || (!hit.red && !redpressed) {
             if (hit.big && dual) || (!hit.big && !dual) {
-                println!("GOOD HIT @ {}, hit.time {}", time, hit.time);
+                trace!("Good hit @ {}, hit.time {}", time, hit.time);
                 return (Some(time - hit.time), true);
             } else {
-                println!("Wrong dual @ {}, hit.time {}", time, hit.time);
+                trace!("Wrong dual @ {}, hit.time {}", time, hit.time);
                 return (Some(time - hit.time), false);
             }
         }
-        println!("Wrong key >_<");
+        trace!("Wrong key @ {}, hit.time {}", time, hit.time);
         return (Some(time - hit.time), false);
     }
     //}
     return (None, false);
 }
 
-pub fn beatmap_list(maps_folder: &String) -> Vec<BeatMap> {
+/// Points for each hit on a drumroll. Doubled on big drumrolls.
+const DRUMROLL_HIT: u64 = 300;
+/// Points for each hit on a denden, and for clearing it.
+const DENDEN_HIT: u64 = 300;
+const DENDEN_CLEAR: u64 = 5000;
+/// Seconds within which both sides of a big note have to be hit to count as one hit.
+pub const BIG_NOTE_HIT_WINDOW: f64 = 0.03;
+
+/// Everything a judgement updates.
+/// Live play and replays both go through it, so they always judge the same way.
+pub struct PlayTracker<'a> {
+    /// Judgement windows, with the mods applied.
+    pub windows: HitWindows,
+    pub mods: Mods,
+    pub hitqueue: &'a mut HitObjectQueue,
+    pub hitoffsets: &'a mut HitOffsets,
+    pub score: &'a mut Score,
+    pub gauge: &'a mut Gauge,
+    pub last_judgement: &'a mut LastJudgement,
+}
+
+impl<'a> PlayTracker<'a> {
+    fn record(&mut self, hit: &HitObject, result: HitResult, offset: f64, time: f64) {
+        if result == HitResult::Miss {
+            self.hitoffsets.offsets.push(None);
+        } else {
+            self.hitoffsets.offsets.push(Some(offset));
+        }
+        self.score.add(result, hit.big, hit.kiai, self.mods.score_multiplier());
+        self.gauge.add(result);
+        self.last_judgement.result = Some(result);
+        self.last_judgement.time = time;
+    }
+
+    /// Adds drumroll or denden points for a hit on `hit`.
+    fn add_bonus(&mut self, hit: &HitObject, points: u64) {
+        let mut multiplier = self.mods.score_multiplier();
+        if hit.kiai {
+            multiplier *= KIAI_MULTIPLIER;
+        }
+        self.score.add_bonus((points as f64 * multiplier) as u64);
+    }
+
+    /// Misses the notes that can't be hit anymore at `cur_time`.
+    /// Returns the times of the missed notes.
+    pub fn drop_missed(&mut self, cur_time: f64) -> Vec<f64> {
+        // Nothing is missed while a big note waits for its other side.
+        if let Some((first_time, _)) = self.hitqueue.half_big_hit {
+            if cur_time - first_time <= BIG_NOTE_HIT_WINDOW {
+                return vec![];
+            }
+        }
+        let mut dropped = self.judge_half_big_hit();
+        while let Some(head) = self.hitqueue.queue.pop_front() {
+            // Drumrolls and dendens just go away once over, or once the next note is due.
+            let next_due = self
+                .hitqueue
+                .queue
+                .front()
+                .map(|next| next.time <= cur_time)
+                .unwrap_or(false);
+            if !head.is_note() && (head.end_time() < cur_time || next_due) {
+                dropped.push(head.time);
+            } else if head.is_note() && head.time + self.windows.miss < cur_time {
+                self.record(&head, HitResult::Miss, 0.0, cur_time);
+                dropped.push(head.time);
+            } else {
+                // put back into the list
+                self.hitqueue.queue.push_front(head);
+                break;
+            }
+        }
+        dropped
+    }
+
+    /// Judges the drum `key` hit at `time` against the next note. Successive hits are
+    /// judged against successive notes, except the two sides of a big note: the first
+    /// waits up to `BIG_NOTE_HIT_WINDOW` for the other, and both are judged together.
+    /// Returns the times of the judged notes and cleared dendens.
+    pub fn hit(&mut self, time: f64, key: DrumKey) -> Vec<f64> {
+        if let Some(first_key) = self.big_hit_partner(time, key) {
+            let first_time = self.hitqueue.half_big_hit.take().map_or(time, |h| h.0);
+            return self.press(first_time, &[first_key, key]).into_iter().collect();
+        }
+        let mut judged = self.judge_half_big_hit();
+        let waits_for_other_side = self.hitqueue.queue.front().map_or(false, |head| {
+            head.is_note()
+                && head.big
+                && head.red == key.red()
+                && value_near(time, head.time, self.windows.miss)
+        });
+        if waits_for_other_side {
+            self.hitqueue.half_big_hit = Some((time, key));
+        } else {
+            judged.extend(self.press(time, &[key]));
+        }
+        judged
+    }
+
+    /// First side of the big note hit waiting for the other one, if `key` hit at `time`
+    /// is that other side.
+    pub fn big_hit_partner(&self, time: f64, key: DrumKey) -> Option<DrumKey> {
+        let (first_time, first_key) = self.hitqueue.half_big_hit?;
+        let other_side = first_key != key && first_key.red() == key.red();
+        if other_side && time - first_time <= BIG_NOTE_HIT_WINDOW {
+            Some(first_key)
+        } else {
+            None
+        }
+    }
+
+    /// Judges the first side of a big note hit on its own, if it still waits for the
+    /// other one.
+    fn judge_half_big_hit(&mut self) -> Vec<f64> {
+        match self.hitqueue.half_big_hit.take() {
+            Some((time, key)) => self.press(time, &[key]).into_iter().collect(),
+            None => vec![],
+        }
+    }
+
+    /// Judges the drum keys pressed at `cur_time` against the next note.
+    /// Returns the time of the judged note or cleared denden, if there was one in range.
+    /// Drumroll and denden hits only add bonus points.
+    fn press(&mut self, cur_time: f64, keys: &[DrumKey]) -> Option<f64> {
+        if keys.is_empty() {
+            return None;
+        }
+        let (red, dual) = get_key_press_type(
+            keys.contains(&DrumKey::LeftDon),
+            keys.contains(&DrumKey::RightDon),
+            keys.contains(&DrumKey::LeftKat),
+            keys.contains(&DrumKey::RightKat),
+        );
+
+        //Get clickable object
+        let mut head = self.hitqueue.queue.pop_front()?;
+        match head.kind {
+            HitObjectKind::Note => {}
+            HitObjectKind::Drumroll { .. } => {
+                if cur_time >= head.time {
+                    let points = if head.big { DRUMROLL_HIT * 2 } else { DRUMROLL_HIT };
+                    self.add_bonus(&head, points);
+                }
+                self.hitqueue.queue.push_front(head);
+                return None;
+            }
+            HitObjectKind::Denden {
+                end,
+                required,
+                hits,
+            } => {
+                if cur_time < head.time {
+                    self.hitqueue.queue.push_front(head);
+                    return None;
+                }
+                self.add_bonus(&head, DENDEN_HIT);
+                if hits + 1 >= required {
+                    self.add_bonus(&head, DENDEN_CLEAR);
+                    return Some(head.time);
+                }
+                head.kind = HitObjectKind::Denden {
+                    end,
+                    required,
+                    hits: hits + 1,
+                };
+                self.hitqueue.queue.push_front(head);
+                return None;
+            }
+        }
+        let hit = check_hit(&self.windows, &head, cur_time, red, dual);
+        if let (Some(offset), clicked) = hit {
+            let result = if clicked {
+                self.windows.judge(offset)
+            } else {
+                HitResult::Miss
+            };
+            self.record(&head, result, offset, cur_time);
+            Some(head.time)
+        } else {
+            //Put back into list if pressed but no hitobject was found
+            self.hitqueue.queue.push_front(head);
+            None
+        }
+    }
+}
+
+/// Folder and path of every difficulty in the beatmap folders of `maps_folder`.
+pub fn beatmap_files(maps_folder: &String) -> Vec<(String, String)> {
     list_directory(maps_folder)
         .into_iter()
         .map(|m| {
-            println!("BeatMap folder path {}", m);
+            debug!("Beatmap folder {}", m);
             list_directory(&m)
                 .into_iter()
                 .filter(|diff| diff.ends_with(".osu"))
-                .map(|diff| {
-                    println!("BeatMap diff path {}", m);
-                    read_beatmap(&m, &diff)
-                })
-                .flatten()
-                .collect::<Vec<BeatMap>>()
+                .map(|diff| (m.clone(), diff))
+                .collect::<Vec<(String, String)>>()
         })
         .flatten()
         .collect()
 }
 
+pub fn beatmap_list(maps_folder: &String) -> Vec<BeatMap> {
+    beatmap_files(maps_folder)
+        .into_iter()
+        .filter_map(|(folder, diff)| read_beatmap(&folder, &diff))
+        .collect()
+}
+
 pub fn list_directory(dir: &String) -> Vec<String> {
     fs::read_dir(dir)
         .expect(&*format!("Failed to read directory {}", dir))
@@ -173,61 +643,388 @@ pub fn read_beatmap(folder_path: &String, difficulty_path: &String) -> Option<Be
     let mut hitobjects: Vec<HitObject> = vec![];
     let mut mode = "";
     let mut songpath = "";
+    let mut od = 5.0;
+    let mut slider_multiplier = 1.4;
+    let mut timing_points: Vec<TimingPoint> = vec![];
+    let mut breaks = vec![];
+    let mut letterbox_in_breaks = false;
+    let mut background = None;
+    let mut default_set = SampleSet::Normal;
     for line in content.lines() {
         if line == "[HitObjects]" {
             mode = "HitObjects";
         } else if line == "[General]" {
             mode = "General";
+        } else if line == "[Difficulty]" {
+            mode = "Difficulty";
+        } else if line == "[TimingPoints]" {
+            mode = "TimingPoints";
+        } else if line == "[Events]" {
+            mode = "Events";
+        } else if line.starts_with("[") {
+            mode = "";
         }
         if mode == "General" {
             if line.starts_with("AudioFilename:") {
                 songpath = &line[15..];
                 if !songpath.ends_with(".ogg") {
+                    warn!("Skipping {}: only .ogg audio is supported", difficulty_path);
                     return None;
                 }
             }
             if line.starts_with("Mode:") {
                 // 1=taiko,3=mania
                 if &line[6..] != "1" {
+                    debug!("Skipping {}: not an osu!taiko map", difficulty_path);
                     return None;
                 }
             }
+            if line.starts_with("LetterboxInBreaks:") {
+                letterbox_in_breaks = line[18..].trim() == "1";
+            }
+            if line.starts_with("SampleSet:") {
+                default_set = SampleSet::from_name(line[10..].trim()).unwrap_or(default_set);
+            }
+        }
+        if mode == "Events" {
+            // Breaks are `2,start,end` (or `Break,start,end`), in milliseconds.
+            let split: Vec<&str> = line.split(",").collect();
+            // The background is `0,0,"file",x,y`.
+            if split.len() >= 3 && split[0] == "0" {
+                let file = split[2].trim().trim_matches('"');
+                background = Some(format!("{}/{}", folder, file));
+            }
+            if split.len() >= 3 && (split[0] == "2" || split[0] == "Break") {
+                if let (Ok(start), Ok(end)) = (
+                    split[1].trim().parse::<i32>(),
+                    split[2].trim().parse::<i32>(),
+                ) {
+                    breaks.push(Break {
+                        start: osu_to_real_time(start),
+                        end: osu_to_real_time(end),
+                    });
+                }
+            }
+        }
+        if mode == "Difficulty" {
+            if line.starts_with("OverallDifficulty:") {
+                od = line[18..].trim().parse::<f64>().unwrap_or(od);
+            }
+            if line.starts_with("SliderMultiplier:") {
+                slider_multiplier = line[17..].trim().parse::<f64>().unwrap_or(slider_multiplier);
+            }
+        }
+        if mode == "TimingPoints" {
+            let split: Vec<&str> = line.split(",").collect();
+            if split.len() < 2 {
+                continue;
+            }
+            // `time,beatLength,meter,sampleSet,sampleIndex,volume,uninherited,effects`,
+            // where everything after the beat length is optional.
+            let field = |i: usize| split.get(i).and_then(|f| f.trim().parse::<u32>().ok());
+            if let (Ok(time), Ok(beat_length)) = (
+                split[0].trim().parse::<f64>(),
+                split[1].trim().parse::<f64>(),
+            ) {
+                timing_points.push(TimingPoint {
+                    time: time / 1000.0,
+                    beat_length,
+                    meter: field(2).filter(|&meter| meter > 0).unwrap_or(4),
+                    kiai: field(7).map_or(false, |effects| effects & 1 != 0),
+                    omit_first_barline: field(7).map_or(false, |effects| effects & 8 != 0),
+                    sample_set: field(3)
+                        .and_then(|set| SampleSet::from_index(set as u8))
+                        .unwrap_or(default_set),
+                    sample_index: field(4).unwrap_or(1),
+                    volume: field(5).unwrap_or(100),
+                });
+            }
         }
         if mode == "HitObjects" {
             let split: Vec<&str> = line.split(",").collect();
 
-            if split.len() != 6 {
+            if split.len() < 5 {
                 continue;
             }
-            let objecttype = split[4 as usize]
+            let time = osu_to_real_time(
+                split[2 as usize]
+                    .parse::<i32>()
+                    .expect("Failed to parse hitobject time as i32."),
+            );
+            let objecttype = split[3 as usize]
+                .parse::<u8>()
+                .expect("Failed to parse hitobject type as u8");
+            let hitsound = split[4 as usize]
                 .parse::<u8>()
                 .expect("Failed to parse as u8");
-            let (red, big) = match objecttype {
-                0 => (true, false),  //small red
-                4 => (true, true),   //big red
-                8 => (false, false), //small blue
-                12 => (false, true), //big blue
-                _ => (false, false), // We don't know what this is, but it happens. Probably sliders.
+            // Whistles and claps make kats, finishes make big notes.
+            let red = hitsound & (HITSOUND_WHISTLE | HITSOUND_CLAP) == 0;
+            let big = hitsound & HITSOUND_FINISH != 0;
+            let kind = if objecttype & 2 != 0 && split.len() >= 8 {
+                // Sliders are drumrolls lasting as long as the slider would.
+                let slides = split[6].parse::<f64>().unwrap_or(1.0);
+                let length = split[7].parse::<f64>().unwrap_or(0.0);
+                let (beat_length, velocity) = timing_at(&timing_points, time);
+                let duration =
+                    length * slides / (slider_multiplier * 100.0 * velocity) * beat_length / 1000.0;
+                HitObjectKind::Drumroll {
+                    end: time + duration,
+                }
+            } else if objecttype & 8 != 0 && split.len() >= 6 {
+                // Spinners are dendens.
+                let end = osu_to_real_time(split[5].parse::<i32>().unwrap_or(0)).max(time);
+                HitObjectKind::Denden {
+                    end,
+                    required: denden_required_hits(od, end - time),
+                    hits: 0,
+                }
+            } else {
+                HitObjectKind::Note
             };
+            let samples = read_hit_samples(&split, hitsound, &timing_points, default_set, time);
+            let kiai = timing_points
+                .iter()
+                .take_while(|p| p.time <= time)
+                .last()
+                .map_or(false, |p| p.kiai);
             hitobjects.push(HitObject {
                 red: red,
-                time: osu_to_real_time(
-                    split[2 as usize]
-                        .parse::<i32>()
-                        .expect("Failed to parse hitobject time as i32."),
-                ),
+                time: time,
                 big: big,
+                kind: kind,
+                samples: samples,
+                kiai: kiai,
             });
         }
     }
+    let star_rating = calculate_star_rating_of(&hitobjects);
+    let last_time = hitobjects
+        .iter()
+        .map(|o| o.end_time())
+        .fold(0.0, f64::max);
+    let barlines = barline_times(&timing_points, last_time);
+    let kiai = kiai_sections(&timing_points, last_time);
+    let hash = format!("{:x}", md5::compute(content.as_bytes()));
     Some(BeatMap {
         name: String::from(songpath),
+        path: difficulty_path.clone(),
         songpath: format!("{}/{}", folder, songpath),
         objects: hitobjects,
-        maxhitoffset: 0.05,
+        star_rating,
+        od,
+        hash,
+        breaks,
+        letterbox_in_breaks,
+        background,
+        barlines,
+        kiai,
     })
 }
 
+/// Reads every playable difficulty found in `folder`.
+/// `folder` can either be a single beatmap set or a folder of sets.
+pub fn load_beatmaps(folder: &String) -> Vec<BeatMap> {
+    let has_diffs = list_directory(folder)
+        .iter()
+        .any(|diff| diff.ends_with(".osu"));
+    if has_diffs {
+        list_directory(folder)
+            .into_iter()
+            .filter(|diff| diff.ends_with(".osu"))
+            .filter_map(|diff| read_beatmap(folder, &diff))
+            .collect::<Vec<BeatMap>>()
+    } else {
+        beatmap_list(folder)
+    }
+}
+
+/// Prints the star rating of every playable difficulty found in `folder`.
+pub fn print_star_ratings(folder: &String) {
+    for b in load_beatmaps(folder) {
+        println!("{:>6.2}*  {}", b.star_rating, b.songpath);
+    }
+}
+
+/// Plays `replay` on `beatmap` without rendering anything, and returns the final score.
+/// Inputs are judged exactly like `GameSystem` judges them.
+pub fn simulate_replay(beatmap: &BeatMap, replay: Replay) -> Score {
+    let mut hitqueue = HitObjectQueue::new();
+    hitqueue.queue.extend(beatmap.objects.iter().cloned());
+    let mut hitoffsets = HitOffsets::default();
+    let mut score = Score::default();
+    let mut gauge = Gauge::new(beatmap.note_count(), &replay.mods);
+    let mut last_judgement = LastJudgement::default();
+    {
+        let mut tracker = PlayTracker {
+            windows: beatmap.hit_windows(&replay.mods),
+            mods: replay.mods,
+            hitqueue: &mut hitqueue,
+            hitoffsets: &mut hitoffsets,
+            score: &mut score,
+            gauge: &mut gauge,
+            last_judgement: &mut last_judgement,
+        };
+        let mut playback = ReplayPlayback::new(replay);
+        for (time, key) in playback.take_inputs(::std::f64::INFINITY) {
+            tracker.drop_missed(time);
+            tracker.hit(time, key);
+        }
+        tracker.drop_missed(::std::f64::INFINITY);
+    }
+    score
+}
+
+/// A line of [TimingPoints]. A negative beat length is an inherited point, which changes
+/// the scroll speed to `-100 / beat_length` instead of the tempo.
+struct TimingPoint {
+    time: f64,
+    beat_length: f64,
+    /// Beats per bar.
+    meter: u32,
+    /// Whether kiai time (go-go time in taiko) is on from this point.
+    kiai: bool,
+    omit_first_barline: bool,
+    /// Samples of the hit objects that don't set their own.
+    sample_set: SampleSet,
+    sample_index: u32,
+    volume: u32,
+}
+
+/// Hit sound bits of a hit object.
+const HITSOUND_WHISTLE: u8 = 2;
+const HITSOUND_FINISH: u8 = 4;
+const HITSOUND_CLAP: u8 = 8;
+
+/// Reads the samples of a hit object from its
+/// `normalSet:additionSet:index:volume:filename` extras, the last field of the line.
+/// Values left at 0 come from the timing point in effect, or from `default_set` before the
+/// first one.
+fn read_hit_samples(
+    split: &[&str],
+    hitsound: u8,
+    points: &[TimingPoint],
+    default_set: SampleSet,
+    time: f64,
+) -> HitSamples {
+    let point = points.iter().take_while(|p| p.time <= time).last();
+    let (point_set, point_index, point_volume) = match point {
+        Some(p) => (p.sample_set, p.sample_index, p.volume),
+        None => (default_set, 1, 100),
+    };
+
+    let extras = match split.last() {
+        Some(last) if split.len() > 5 && last.contains(':') && !last.contains('|') => {
+            last.split(':').collect::<Vec<_>>()
+        }
+        _ => vec![],
+    };
+    let number = |i: usize| extras.get(i).and_then(|v| v.trim().parse::<u32>().ok());
+    let normal_set = number(0)
+        .and_then(|set| SampleSet::from_index(set as u8))
+        .unwrap_or(point_set);
+    let volume = match number(3) {
+        Some(volume) if volume > 0 => volume,
+        _ => point_volume,
+    };
+    HitSamples {
+        normal_set,
+        addition_set: number(1)
+            .and_then(|set| SampleSet::from_index(set as u8))
+            .unwrap_or(normal_set),
+        index: match number(2) {
+            Some(index) if index > 0 => index,
+            _ => point_index,
+        },
+        volume: volume.min(100) as f32 / 100.0,
+        filename: extras
+            .get(4)
+            .map(|f| f.trim())
+            .filter(|f| !f.is_empty())
+            .map(String::from),
+        whistle: hitsound & HITSOUND_WHISTLE != 0,
+    }
+}
+
+/// Beat length (ms) and scroll speed multiplier in effect at `time`.
+fn timing_at(points: &[TimingPoint], time: f64) -> (f64, f64) {
+    let mut beat_length = 500.0;
+    let mut velocity = 1.0;
+    for point in points.iter().take_while(|p| p.time <= time) {
+        if point.beat_length > 0.0 {
+            beat_length = point.beat_length;
+            velocity = 1.0;
+        } else if point.beat_length < 0.0 {
+            velocity = -100.0 / point.beat_length;
+        }
+    }
+    (beat_length, velocity)
+}
+
+/// Times of the barlines until `end`: one every `meter` beats from each uninherited timing
+/// point until the next one.
+fn barline_times(points: &[TimingPoint], end: f64) -> Vec<f64> {
+    let uninherited = points
+        .iter()
+        .filter(|p| p.beat_length > 0.0)
+        .collect::<Vec<_>>();
+    let mut barlines = vec![];
+    for (i, point) in uninherited.iter().enumerate() {
+        let next = uninherited.get(i + 1).map_or(end, |p| p.time.min(end));
+        let bar_length = point.beat_length * point.meter as f64 / 1000.0;
+        // Some maps use absurdly short beats for effects.
+        if bar_length < 0.05 {
+            continue;
+        }
+        let mut time = point.time;
+        if point.omit_first_barline {
+            time += bar_length;
+        }
+        // Rounding can put a bar a hair before the next point, where it would double it.
+        while time < next - 0.001 {
+            barlines.push(time);
+            time += bar_length;
+        }
+    }
+    barlines
+}
+
+/// Kiai sections, from the timing points turning it on to the ones turning it off, or to
+/// `end`.
+fn kiai_sections(points: &[TimingPoint], end: f64) -> Vec<KiaiSection> {
+    let mut sections = vec![];
+    let mut beat_length = 0.5;
+    let mut current: Option<KiaiSection> = None;
+    for point in points {
+        if point.beat_length > 0.0 {
+            beat_length = point.beat_length / 1000.0;
+        }
+        match (point.kiai, current.take()) {
+            (true, Some(section)) => current = Some(section),
+            (true, None) => {
+                current = Some(KiaiSection {
+                    start: point.time,
+                    end,
+                    beat_length,
+                })
+            }
+            (false, Some(section)) => sections.push(KiaiSection {
+                end: point.time,
+                ..section
+            }),
+            (false, None) => {}
+        }
+    }
+    sections.extend(current);
+    sections.retain(|section| section.end > section.start);
+    sections
+}
+
+/// Hits needed to clear a denden lasting `duration` seconds.
+fn denden_required_hits(od: f64, duration: f64) -> u32 {
+    let hits_per_second = difficulty_range(od, 3.0, 5.0, 7.5) * 1.65;
+    ((duration * hits_per_second) as u32).max(1)
+}
+
 pub fn osu_to_real_time(time: i32) -> f64 {
     time as f64 / 1000.0
 }